@@ -0,0 +1,9 @@
+// The URL of a thumbnail. With the version from the file list, the browser can cache it forever.
+export function thumbUrl(baseUrl, path, version){
+    if(version){
+        return `${baseUrl}/thumbs/${path}?v=${version}`;
+    }
+    else {
+        return `${baseUrl}/thumbs/${path}`;
+    }
+}
@@ -0,0 +1,455 @@
+//! Role-based access control: user groups and explicit per-album grants.
+//!
+//! Like [`crate::cache::CacheMap`], the whole access control list is kept in memory and every
+//! change is written through to the DB immediately.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use actix_web::{error, web, HttpRequest, Result};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    files::{authorized_path, validate_path, CheckAuth},
+    map_err,
+    session::{check_admin, get_valid_session},
+    user::user_exists,
+    MyData,
+};
+
+/// A role of a user on an album. Roles are ordered so that a higher role implies all the
+/// permissions of the lower ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Role {
+    /// Can see the album contents even if it is locked
+    Viewer,
+    /// Can upload files to the album
+    Contributor,
+    /// Can move, delete and describe files in the album
+    Editor,
+    /// Can do anything the owner of the album can, including locking and granting
+    Owner,
+}
+
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Viewer => "viewer",
+            Self::Contributor => "contributor",
+            Self::Editor => "editor",
+            Self::Owner => "owner",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        Some(match s {
+            "viewer" => Self::Viewer,
+            "contributor" => Self::Contributor,
+            "editor" => Self::Editor,
+            "owner" => Self::Owner,
+            _ => return None,
+        })
+    }
+}
+
+/// Who a grant is given to. Exactly one of them is set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Principal {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<usize>,
+}
+
+impl Principal {
+    fn validate(&self) -> Result<()> {
+        if self.user_id.is_some() == self.group_id.is_some() {
+            return Err(error::ErrorBadRequest(
+                "Specify exactly one of user_id or group_id",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Grant {
+    #[serde(flatten)]
+    pub principal: Principal,
+    pub role: Role,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Group {
+    pub name: String,
+    pub members: HashSet<usize>,
+}
+
+/// In-memory copy of the groups and grants tables.
+#[derive(Debug, Default)]
+pub(crate) struct AccessControl {
+    pub groups: HashMap<usize, Group>,
+    pub grants: HashMap<PathBuf, Vec<Grant>>,
+}
+
+impl AccessControl {
+    pub(crate) fn groups_of(&self, user_id: usize) -> HashSet<usize> {
+        self.groups
+            .iter()
+            .filter(|(_, group)| group.members.contains(&user_id))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Returns the highest role granted on exactly this path to the user or any of the groups.
+    /// Inheritance is the caller's responsibility.
+    pub(crate) fn granted_role(
+        &self,
+        path: &Path,
+        user_id: usize,
+        groups: &HashSet<usize>,
+    ) -> Option<Role> {
+        self.grants
+            .get(path)?
+            .iter()
+            .filter(|grant| {
+                grant.principal.user_id == Some(user_id)
+                    || grant
+                        .principal
+                        .group_id
+                        .map(|group| groups.contains(&group))
+                        .unwrap_or(false)
+            })
+            .map(|grant| grant.role)
+            .max()
+    }
+
+    /// Forget everything about a user, e.g. when the user is deleted.
+    pub(crate) fn remove_user(&mut self, user_id: usize) {
+        for group in self.groups.values_mut() {
+            group.members.remove(&user_id);
+        }
+        for grants in self.grants.values_mut() {
+            grants.retain(|grant| grant.principal.user_id != Some(user_id));
+        }
+    }
+}
+
+pub(crate) fn load_access_control(conn: &Connection) -> rusqlite::Result<AccessControl> {
+    let mut access = AccessControl::default();
+
+    let mut stmt = conn.prepare("SELECT id, name FROM user_group")?;
+    for row in stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))? {
+        let (id, name) = row?;
+        access.groups.insert(
+            id,
+            Group {
+                name,
+                members: HashSet::new(),
+            },
+        );
+    }
+
+    let mut stmt = conn.prepare("SELECT group_id, user_id FROM group_member")?;
+    for row in stmt.query_map([], |row| Ok((row.get::<_, usize>(0)?, row.get(1)?)))? {
+        let (group_id, user_id) = row?;
+        if let Some(group) = access.groups.get_mut(&group_id) {
+            group.members.insert(user_id);
+        }
+    }
+
+    let mut stmt = conn.prepare("SELECT path, user_id, group_id, role FROM album_grant")?;
+    for row in stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get::<_, String>(3)?,
+        ))
+    })? {
+        let (path, user_id, group_id, role) = row?;
+        let Some(role) = Role::from_str(&role) else {
+            println!("Ignoring a grant with an unknown role {role:?} on {path:?}");
+            continue;
+        };
+        access
+            .grants
+            .entry(PathBuf::from(path))
+            .or_default()
+            .push(Grant {
+                principal: Principal { user_id, group_id },
+                role,
+            });
+    }
+
+    println!(
+        "Loaded {} groups and grants on {} albums",
+        access.groups.len(),
+        access.grants.len()
+    );
+
+    Ok(access)
+}
+
+#[derive(Serialize)]
+struct ListElementGroup {
+    id: usize,
+    name: String,
+    members: Vec<usize>,
+}
+
+#[actix_web::get("/groups")]
+pub(crate) async fn list_groups(
+    data: web::Data<MyData>,
+    req: HttpRequest,
+) -> Result<web::Json<Vec<ListElementGroup>>> {
+    check_admin(&data, &req, "manage groups")?;
+    let access = data.access.read().unwrap();
+    let mut groups: Vec<_> = access
+        .groups
+        .iter()
+        .map(|(id, group)| {
+            let mut members: Vec<_> = group.members.iter().copied().collect();
+            members.sort();
+            ListElementGroup {
+                id: *id,
+                name: group.name.clone(),
+                members,
+            }
+        })
+        .collect();
+    groups.sort_by_key(|group| group.id);
+    Ok(web::Json(groups))
+}
+
+#[derive(Deserialize)]
+struct CreateGroupParams {
+    name: String,
+}
+
+/// Creates a group and returns the newly created group id
+#[actix_web::post("/groups")]
+pub(crate) async fn create_group(
+    data: web::Data<MyData>,
+    params: web::Json<CreateGroupParams>,
+    req: HttpRequest,
+) -> Result<String> {
+    check_admin(&data, &req, "manage groups")?;
+    let mut access = data.access.write().unwrap();
+    let conn = data.conn()?;
+    conn.execute(
+        "INSERT INTO user_group (name) VALUES (?1)",
+        params![params.name],
+    )
+    .map_err(map_err)?;
+    let id = conn.last_insert_rowid() as usize;
+    access.groups.insert(
+        id,
+        Group {
+            name: params.name.clone(),
+            members: HashSet::new(),
+        },
+    );
+    Ok(id.to_string())
+}
+
+#[actix_web::delete("/groups/{id}")]
+pub(crate) async fn delete_group(
+    data: web::Data<MyData>,
+    id: web::Path<usize>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    check_admin(&data, &req, "manage groups")?;
+    let id = id.into_inner();
+    let mut access = data.access.write().unwrap();
+    let mut conn = data.conn()?;
+    let tx = conn.transaction().map_err(map_err)?;
+    tx.execute("DELETE FROM user_group WHERE id = ?1", [id])
+        .map_err(map_err)?;
+    tx.execute("DELETE FROM group_member WHERE group_id = ?1", [id])
+        .map_err(map_err)?;
+    tx.execute("DELETE FROM album_grant WHERE group_id = ?1", [id])
+        .map_err(map_err)?;
+    tx.commit().map_err(map_err)?;
+    access.groups.remove(&id);
+    for grants in access.grants.values_mut() {
+        grants.retain(|grant| grant.principal.group_id != Some(id));
+    }
+    Ok("Ok")
+}
+
+#[derive(Deserialize)]
+struct AddMemberParams {
+    user_id: usize,
+}
+
+#[actix_web::post("/groups/{id}/members")]
+pub(crate) async fn add_group_member(
+    data: web::Data<MyData>,
+    id: web::Path<usize>,
+    params: web::Json<AddMemberParams>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    check_admin(&data, &req, "manage groups")?;
+    let mut access = data.access.write().unwrap();
+    let group = access
+        .groups
+        .get_mut(&*id)
+        .ok_or_else(|| error::ErrorNotFound("Group not found"))?;
+    let conn = data.conn()?;
+    if !user_exists(&conn, params.user_id).map_err(map_err)? {
+        return Err(error::ErrorNotFound("User not found"));
+    }
+    conn.execute(
+        "INSERT OR IGNORE INTO group_member (group_id, user_id) VALUES (?1, ?2)",
+        params![*id, params.user_id],
+    )
+    .map_err(map_err)?;
+    group.members.insert(params.user_id);
+    Ok("Ok")
+}
+
+#[actix_web::delete("/groups/{id}/members/{user_id}")]
+pub(crate) async fn remove_group_member(
+    data: web::Data<MyData>,
+    ids: web::Path<(usize, usize)>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    check_admin(&data, &req, "manage groups")?;
+    let (id, user_id) = ids.into_inner();
+    let mut access = data.access.write().unwrap();
+    let group = access
+        .groups
+        .get_mut(&id)
+        .ok_or_else(|| error::ErrorNotFound("Group not found"))?;
+    let conn = data.conn()?;
+    conn.execute(
+        "DELETE FROM group_member WHERE group_id = ?1 AND user_id = ?2",
+        params![id, user_id],
+    )
+    .map_err(map_err)?;
+    group.members.remove(&user_id);
+    Ok("Ok")
+}
+
+/// Only the owner of the album (including inherited ownership) or the admin can see or change
+/// the grants. Paths with `..` are refused, and so are hidden paths by `authorized_path`.
+fn check_album_owner(data: &MyData, req: &HttpRequest, path: &Path) -> Result<()> {
+    validate_path(path)?;
+    let sessions = data.sessions.read().unwrap();
+    let session = get_valid_session(req, &sessions)?;
+    let cache = data.cache.read().unwrap();
+    let access = data.access.read().unwrap();
+    authorized_path(path, Some(session), &cache, &access, CheckAuth::Ownership)
+}
+
+#[actix_web::get("/albums/{path:.*}/grants")]
+pub(crate) async fn list_grants(
+    data: web::Data<MyData>,
+    path: web::Path<PathBuf>,
+    req: HttpRequest,
+) -> Result<web::Json<Vec<Grant>>> {
+    check_album_owner(&data, &req, &path)?;
+    let access = data.access.read().unwrap();
+    Ok(web::Json(
+        access.grants.get(&*path).cloned().unwrap_or_default(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct SetGrantParams {
+    #[serde(flatten)]
+    principal: Principal,
+    role: Role,
+}
+
+#[actix_web::post("/albums/{path:.*}/grants")]
+pub(crate) async fn set_grant(
+    data: web::Data<MyData>,
+    path: web::Path<PathBuf>,
+    params: web::Json<SetGrantParams>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    check_album_owner(&data, &req, &path)?;
+    // The grants of an album that has gone can still be listed and deleted, but not added.
+    if !data.path.join(&*path).is_dir() {
+        return Err(error::ErrorNotFound("Album not found"));
+    }
+    params.principal.validate()?;
+    let mut access = data.access.write().unwrap();
+    if let Some(group_id) = params.principal.group_id {
+        if !access.groups.contains_key(&group_id) {
+            return Err(error::ErrorNotFound("Group not found"));
+        }
+    }
+    let mut conn = data.conn()?;
+    if let Some(user_id) = params.principal.user_id {
+        if !user_exists(&conn, user_id).map_err(map_err)? {
+            return Err(error::ErrorNotFound("User not found"));
+        }
+    }
+    let tx = conn.transaction().map_err(map_err)?;
+    tx.execute(
+        "DELETE FROM album_grant WHERE path = ?1 AND user_id IS ?2 AND group_id IS ?3",
+        params![
+            path.to_str(),
+            params.principal.user_id,
+            params.principal.group_id
+        ],
+    )
+    .map_err(map_err)?;
+    tx.execute(
+        "INSERT INTO album_grant (path, user_id, group_id, role) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            path.to_str(),
+            params.principal.user_id,
+            params.principal.group_id,
+            params.role.as_str()
+        ],
+    )
+    .map_err(map_err)?;
+    tx.commit().map_err(map_err)?;
+
+    let grants = access.grants.entry(path.clone()).or_default();
+    grants.retain(|grant| grant.principal != params.principal);
+    grants.push(Grant {
+        principal: params.principal,
+        role: params.role,
+    });
+
+    println!(
+        "Granted {:?} on {path:?} to {:?}",
+        params.role, params.principal
+    );
+
+    Ok("Ok")
+}
+
+#[actix_web::delete("/albums/{path:.*}/grants")]
+pub(crate) async fn delete_grant(
+    data: web::Data<MyData>,
+    path: web::Path<PathBuf>,
+    params: web::Json<Principal>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    check_album_owner(&data, &req, &path)?;
+    params.validate()?;
+    let mut access = data.access.write().unwrap();
+    let conn = data.conn()?;
+    conn.execute(
+        "DELETE FROM album_grant WHERE path = ?1 AND user_id IS ?2 AND group_id IS ?3",
+        params![path.to_str(), params.user_id, params.group_id],
+    )
+    .map_err(map_err)?;
+    if let Some(grants) = access.grants.get_mut(&*path) {
+        grants.retain(|grant| grant.principal != *params);
+        if grants.is_empty() {
+            access.grants.remove(&*path);
+        }
+    }
+    Ok("Ok")
+}
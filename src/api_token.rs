@@ -0,0 +1,442 @@
+//! Personal access tokens for scripts and sync clients.
+//!
+//! A token is sent as `Authorization: Bearer <token>` instead of the session cookie. The
+//! [`ApiToken`] middleware checks it against the DB on every request and keeps a [`Session`] for
+//! it in the sessions, so that the handlers and `authorized_path` see the same user as with a
+//! browser session. The scopes of the token limit what the requests can do. Only the hashes of the
+//! tokens are stored.
+
+use std::collections::HashSet;
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    error,
+    http::{
+        header::{HeaderMap, AUTHORIZATION},
+        Method,
+    },
+    web, Error, HttpRequest, Result,
+};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    csrf::generate_token,
+    map_err, now,
+    rate_limit::ip_key,
+    session::{get_valid_session, Session, Sessions},
+    MyData,
+};
+
+const TOKEN_PREFIX: &str = "mpt_";
+
+/// The paths to manage the account, which an API token cannot use, so that a leaked token cannot
+/// take over the account or mint other tokens.
+const ACCOUNT_PATHS: &[&str] = &[
+    "/users/login",
+    "/users/reset_password",
+    "/user_logout",
+    "/set_password",
+    "/totp",
+    "/tokens",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Scope {
+    /// GET requests
+    Read,
+    /// Uploading files
+    Upload,
+    /// Other state-changing requests, such as moving, deleting and describing files
+    Write,
+    /// The admin privileges, if the user is an admin
+    Admin,
+}
+
+impl Scope {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Upload => "upload",
+            Self::Write => "write",
+            Self::Admin => "admin",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        [Self::Read, Self::Upload, Self::Write, Self::Admin]
+            .into_iter()
+            .find(|scope| scope.name() == name)
+    }
+
+    /// The scope required for a request
+    fn required(method: &Method, path: &str) -> Self {
+        if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            Self::Read
+        } else if *method == Method::POST && path.starts_with("/upload/") {
+            Self::Upload
+        } else {
+            Self::Write
+        }
+    }
+}
+
+fn format_scopes(scopes: &HashSet<Scope>) -> String {
+    let mut names: Vec<_> = scopes.iter().map(|scope| scope.name()).collect();
+    names.sort();
+    names.join(",")
+}
+
+fn parse_scopes(scopes: &str) -> HashSet<Scope> {
+    scopes.split(',').filter_map(Scope::parse).collect()
+}
+
+/// Returns the token in the `Authorization: Bearer` header if any.
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+const TOKEN_SESSION_PREFIX: &str = "token:";
+
+/// The key of the session of the token in the sessions
+pub(crate) fn token_session_key(token: &str) -> String {
+    format!("{TOKEN_SESSION_PREFIX}{}", sha256::digest(token))
+}
+
+/// Whether the session key belongs to an API token rather than a browser session
+pub(crate) fn is_token_session_key(key: &str) -> bool {
+    key.starts_with(TOKEN_SESSION_PREFIX)
+}
+
+/// Remove the sessions of the API tokens of the user and return how many were removed.
+pub(crate) fn remove_token_sessions(sessions: &mut Sessions, user_id: usize) -> usize {
+    let count = sessions.len();
+    sessions.retain(|key, session| !is_token_session_key(key) || session.user_id != Some(user_id));
+    count - sessions.len()
+}
+
+/// Remove the sessions of the tokens that expired, were revoked or whose user was disabled or
+/// deleted since their last use, and return how many were removed.
+pub(crate) fn prune_token_sessions(data: &MyData) -> anyhow::Result<usize> {
+    let conn = data.pool.get()?;
+    let mut stmt = conn.prepare(
+        "SELECT t.token_hash FROM api_token t JOIN user u ON u.id = t.user_id
+        WHERE NOT u.disabled AND (t.expires IS NULL OR ?1 < t.expires)",
+    )?;
+    let valid = stmt
+        .query_map([now()], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<HashSet<_>>>()?;
+    let mut sessions = data.sessions.write().unwrap();
+    let count = sessions.len();
+    sessions.retain(|key, _| {
+        key.strip_prefix(TOKEN_SESSION_PREFIX)
+            .is_none_or(|hash| valid.contains(hash))
+    });
+    Ok(count - sessions.len())
+}
+
+/// Check the token of the request and update the session of the token.
+fn authenticate(data: &MyData, req: &ServiceRequest, token: &str) -> Result<()> {
+    let key = token_session_key(token);
+    let conn = data.conn()?;
+    let row: Option<(usize, String, Option<f64>, bool, bool)> = {
+        let mut limiter = data.rate_limiter.lock().unwrap();
+        // Count the failures only by the client, since an unknown token does not tell the user.
+        let keys: Vec<_> = req
+            .peer_addr()
+            .map(|addr| ip_key(addr.ip()))
+            .into_iter()
+            .collect();
+        limiter.check_attempt(&keys)?;
+        let row = conn
+            .query_row(
+                "SELECT t.user_id, t.scopes, t.expires, u.is_admin, u.disabled
+                FROM api_token t JOIN user u ON u.id = t.user_id WHERE t.token_hash = ?1",
+                [sha256::digest(token)],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                    ))
+                },
+            )
+            .optional()
+            .map_err(map_err)?;
+        if row.is_none() {
+            limiter.record_failure(&conn, &keys).map_err(map_err)?;
+        }
+        row
+    };
+    let valid = row.filter(|(_, _, expires, _, disabled)| {
+        !disabled && !matches!(expires, Some(expires) if *expires <= now())
+    });
+    let Some((user_id, scopes, _, is_admin, _)) = valid else {
+        data.sessions.write().unwrap().remove(&key);
+        return Err(error::ErrorUnauthorized("Invalid or expired API token"));
+    };
+
+    let path = req.path();
+    if ACCOUNT_PATHS.iter().any(|prefix| path.starts_with(prefix)) {
+        return Err(error::ErrorForbidden(
+            "API tokens cannot be used to manage the account",
+        ));
+    }
+    let scopes = parse_scopes(&scopes);
+    let required = Scope::required(req.method(), path);
+    if !scopes.contains(&required) {
+        return Err(error::ErrorForbidden(format!(
+            "The API token does not have the {} scope",
+            required.name()
+        )));
+    }
+
+    let mut sessions = data.sessions.write().unwrap();
+    let session = sessions.entry(key).or_insert_with(Session::new);
+    session.user_id = Some(user_id);
+    session.is_admin = is_admin && scopes.contains(&Scope::Admin);
+    session.scopes = Some(scopes);
+    Ok(())
+}
+
+/// A middleware that authenticates the requests with an API token.
+pub(crate) struct ApiToken;
+
+impl<S, B> Transform<S, ServiceRequest> for ApiToken
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ApiTokenMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ApiTokenMiddleware { service }))
+    }
+}
+
+pub(crate) struct ApiTokenMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ApiTokenMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Some(token) = bearer_token(req.headers()) {
+            let res = match req.app_data::<web::Data<MyData>>() {
+                Some(data) => authenticate(data, &req, token),
+                None => Err(error::ErrorInternalServerError("No app data")),
+            };
+            if let Err(e) = res {
+                let res = req.error_response(e);
+                return Box::pin(ready(Ok(res.map_into_right_body())));
+            }
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+    }
+}
+
+fn logged_in_user(data: &MyData, req: &HttpRequest) -> Result<(usize, bool)> {
+    let sessions = data.sessions.read().unwrap();
+    let session = get_valid_session(req, &sessions)?;
+    let user_id = session
+        .user_id
+        .ok_or_else(|| error::ErrorBadRequest("Please login first"))?;
+    Ok((user_id, session.is_admin))
+}
+
+#[derive(Serialize)]
+struct ListElementToken {
+    id: usize,
+    name: String,
+    scopes: HashSet<Scope>,
+    /// In seconds since the UNIX epoch
+    created: f64,
+    /// In seconds since the UNIX epoch. Never expires if null.
+    expires: Option<f64>,
+}
+
+/// Lists the tokens of the logged in user.
+#[actix_web::get("/tokens")]
+pub(crate) async fn list_tokens(
+    data: web::Data<MyData>,
+    req: HttpRequest,
+) -> Result<web::Json<Vec<ListElementToken>>> {
+    let (user_id, _) = logged_in_user(&data, &req)?;
+    let conn = data.conn()?;
+    let mut stmt = conn
+        .prepare("SELECT id, name, scopes, created, expires FROM api_token WHERE user_id = ?1")
+        .map_err(map_err)?;
+    let tokens = stmt
+        .query_map([user_id], |row| {
+            Ok(ListElementToken {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                scopes: parse_scopes(&row.get::<_, String>(2)?),
+                created: row.get(3)?,
+                expires: row.get(4)?,
+            })
+        })
+        .map_err(map_err)?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .map_err(map_err)?;
+    Ok(web::Json(tokens))
+}
+
+#[derive(Deserialize)]
+struct CreateTokenParams {
+    /// To tell the tokens apart, e.g. the name of the script or the device
+    name: String,
+    scopes: HashSet<Scope>,
+    /// Never expires if not given
+    expires_in_days: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct CreateTokenResult {
+    id: usize,
+    /// Shown only once
+    token: String,
+    expires: Option<f64>,
+}
+
+/// Creates a token of the logged in user.
+#[actix_web::post("/tokens")]
+pub(crate) async fn create_token(
+    data: web::Data<MyData>,
+    params: web::Json<CreateTokenParams>,
+    req: HttpRequest,
+) -> Result<web::Json<CreateTokenResult>> {
+    let (user_id, is_admin) = logged_in_user(&data, &req)?;
+    if params.scopes.is_empty() {
+        return Err(error::ErrorBadRequest("Give at least one scope"));
+    }
+    if params.scopes.contains(&Scope::Admin) && !is_admin {
+        return Err(error::ErrorForbidden(
+            "Only the admin can create a token with the admin scope",
+        ));
+    }
+    let token = format!("{TOKEN_PREFIX}{}", generate_token());
+    let created = now();
+    let expires = params
+        .expires_in_days
+        .map(|days| created + (days * 24 * 60 * 60) as f64);
+    let conn = data.conn()?;
+    conn.execute(
+        "INSERT INTO api_token (user_id, name, token_hash, scopes, created, expires)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            user_id,
+            params.name,
+            sha256::digest(&token),
+            format_scopes(&params.scopes),
+            created,
+            expires
+        ],
+    )
+    .map_err(map_err)?;
+    let id = conn.last_insert_rowid() as usize;
+    println!(
+        "User {user_id} created API token {id} {:?} with scopes {}",
+        params.name,
+        format_scopes(&params.scopes)
+    );
+    Ok(web::Json(CreateTokenResult { id, token, expires }))
+}
+
+/// Revokes a token. The admin can revoke the tokens of any user.
+#[actix_web::delete("/tokens/{id}")]
+pub(crate) async fn revoke_token(
+    data: web::Data<MyData>,
+    id: web::Path<usize>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    let (user_id, is_admin) = logged_in_user(&data, &req)?;
+    let conn = data.conn()?;
+    let row: Option<(usize, String)> = conn
+        .query_row(
+            "SELECT user_id, token_hash FROM api_token WHERE id = ?1",
+            [*id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map_err(map_err)?;
+    let token_hash = match row {
+        Some((owner, token_hash)) if owner == user_id || is_admin => token_hash,
+        // Do not tell whether the token of another user exists.
+        _ => return Err(error::ErrorNotFound("Token not found")),
+    };
+    conn.execute("DELETE FROM api_token WHERE id = ?1", [*id])
+        .map_err(map_err)?;
+    data.sessions
+        .write()
+        .unwrap()
+        .remove(&format!("{TOKEN_SESSION_PREFIX}{token_hash}"));
+    println!("User {user_id} revoked API token {id}");
+    Ok("Ok")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{session_key, SESSION_COOKIE};
+    use actix_web::{cookie::Cookie, test::TestRequest};
+
+    fn session_of(user_id: usize) -> Session {
+        let mut session = Session::new();
+        session.user_id = Some(user_id);
+        session
+    }
+
+    #[test]
+    fn remove_sessions_of_user_tokens() {
+        let mut sessions = Sessions::new();
+        sessions.insert(token_session_key("mpt_a"), session_of(1));
+        sessions.insert(token_session_key("mpt_b"), session_of(2));
+        sessions.insert("browser".to_string(), session_of(1));
+        assert_eq!(remove_token_sessions(&mut sessions, 1), 1);
+        assert!(!sessions.contains_key(&token_session_key("mpt_a")));
+        assert!(sessions.contains_key(&token_session_key("mpt_b")));
+        assert!(sessions.contains_key("browser"));
+        assert_eq!(remove_token_sessions(&mut sessions, 1), 0);
+    }
+
+    #[test]
+    fn cookie_cannot_pick_token_session() {
+        let key = token_session_key("mpt_a");
+        assert!(is_token_session_key(&key));
+        let req = TestRequest::default()
+            .cookie(Cookie::new(SESSION_COOKIE, key.clone()))
+            .to_http_request();
+        assert_eq!(session_key(&req), None);
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer mpt_a"))
+            .to_http_request();
+        assert_eq!(session_key(&req), Some(key));
+    }
+}
@@ -0,0 +1,231 @@
+//! Consistent backups of the DB while the server is running, and restoring them.
+//!
+//! Copying `sqliter.db` by hand can catch a half-written file or miss the changes still in the WAL
+//! file, and thumbnails made since the last periodic cleanup are only in memory. A backup flushes
+//! them with [`write_db`] and copies the DB with the SQLite online backup API, which gives a
+//! snapshot at a single point in time without stopping the server.
+
+use std::{
+    os::raw::c_int,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{web, HttpRequest, Result};
+use anyhow::{bail, Context};
+use rusqlite::{backup::Backup, Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db_utils::{write_db, DB_FILE_NAME},
+    map_err,
+    migration::{format_version, latest_version, schema_version},
+    session::check_admin,
+    MyData,
+};
+
+#[derive(Debug, Serialize)]
+pub(crate) struct BackupSummary {
+    path: PathBuf,
+    /// In bytes
+    size: u64,
+    /// In seconds
+    elapsed: f64,
+}
+
+/// Flush the thumbnails in memory and copy the DB to `dest`. Without `thumbnails`, the thumbnails
+/// in the DB are left out of the copy to make it smaller; they are made again on demand after
+/// restoring it.
+pub(crate) fn backup_db(
+    data: &MyData,
+    dest: &Path,
+    thumbnails: bool,
+) -> anyhow::Result<BackupSummary> {
+    let start = Instant::now();
+    write_db(data)?;
+
+    create_new_file(dest)?;
+    let res = (|| -> anyhow::Result<()> {
+        let mut dest_conn = Connection::open(dest)?;
+        copy_db(&*data.pool.get()?, &mut dest_conn)?;
+        if !thumbnails {
+            dest_conn.execute_batch("UPDATE file SET data = NULL; VACUUM;")?;
+        }
+        Ok(())
+    })();
+    if let Err(e) = res {
+        // Do not leave a partial backup that looks like a good one.
+        let _ = std::fs::remove_file(dest);
+        return Err(e);
+    }
+
+    let summary = BackupSummary {
+        path: dest.to_owned(),
+        size: std::fs::metadata(dest)?.len(),
+        elapsed: start.elapsed().as_secs_f64(),
+    };
+    println!(
+        "Backed up the database to {:?}: {} bytes in {} s",
+        summary.path, summary.size, summary.elapsed
+    );
+    Ok(summary)
+}
+
+/// Copy all the pages in one step, so that the snapshot is never restarted by a concurrent write.
+/// Readers do not block writers in WAL mode, so the server keeps working meanwhile.
+fn copy_db(src: &Connection, dest: &mut Connection) -> rusqlite::Result<()> {
+    Backup::new(src, dest)?.run_to_completion(c_int::MAX, Duration::ZERO, None)
+}
+
+/// Create an empty file at `path` for a copy of the DB, failing if it exists, so that a backup
+/// never overwrites another one even if two are made at once.
+fn create_new_file(path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("Failed to create {path:?}"))?;
+    Ok(())
+}
+
+/// A path in `dir` named `{prefix}{timestamp}.{extension}` that does not exist yet. A number is
+/// appended to the timestamp if another backup was made in the same second.
+fn unique_path(dir: &Path, prefix: &str, extension: &str) -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut path = dir.join(format!("{prefix}{timestamp}.{extension}"));
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("{prefix}{timestamp}-{n}.{extension}"));
+        n += 1;
+    }
+    path
+}
+
+/// A path for a new backup in `dir` with the current time in the name
+pub(crate) fn new_backup_path(dir: &Path) -> PathBuf {
+    unique_path(dir, "sqliter-", "db")
+}
+
+/// Replace the DB at `db_path` with the backup at `src`. The backup is checked before touching the
+/// DB, and the current DB is kept in `backup_dir` as `sqliter.db.before-restore-<timestamp>.bak`.
+/// The server must not be running.
+pub(crate) fn restore_db(src: &Path, db_path: &Path, backup_dir: &Path) -> anyhow::Result<()> {
+    let src_conn = Connection::open_with_flags(src, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open the backup {src:?}"))?;
+    let integrity: String = src_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    if integrity != "ok" {
+        bail!("The backup {src:?} is corrupted: {integrity}");
+    }
+    let Some(version) = schema_version(&src_conn)? else {
+        bail!("{src:?} is not a massphoto database");
+    };
+    let latest = latest_version();
+    if latest < version {
+        bail!(
+            "The backup {src:?} has the schema {}, which is newer than this program ({})",
+            format_version(version),
+            format_version(latest)
+        );
+    }
+
+    let mut db_conn = Connection::open(db_path)?;
+    // Not next to the DB in the album root, where the copy of the password hashes and the
+    // secrets could be served.
+    let current_backup = unique_path(
+        backup_dir,
+        &format!("{DB_FILE_NAME}.before-restore-"),
+        "bak",
+    );
+    create_new_file(&current_backup)?;
+    copy_db(&db_conn, &mut Connection::open(&current_backup)?)?;
+    println!("Backed up the current database to {current_backup:?}");
+
+    copy_db(&src_conn, &mut db_conn)?;
+    println!("Restored the database from {src:?}");
+    if version < latest {
+        println!(
+            "The backup has the schema {}; it will be migrated to {} on the next start",
+            format_version(version),
+            format_version(latest)
+        );
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct BackupQuery {
+    /// Include the thumbnails in the DB. Defaults to true.
+    thumbnails: Option<bool>,
+}
+
+#[actix_web::post("/admin/backup")]
+pub(crate) async fn create_backup(
+    data: web::Data<MyData>,
+    query: web::Query<BackupQuery>,
+    req: HttpRequest,
+) -> Result<web::Json<BackupSummary>> {
+    check_admin(&data, &req, "create backups")?;
+    let dest = new_backup_path(&data.backup_dir);
+    let thumbnails = query.thumbnails.unwrap_or(true);
+    let summary = web::block(move || backup_db(&data, &dest, thumbnails))
+        .await?
+        .map_err(map_err)?;
+    Ok(web::Json(summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_paths_are_unique() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = new_backup_path(dir.path());
+        create_new_file(&first).unwrap();
+        assert!(create_new_file(&first).is_err());
+        let second = new_backup_path(dir.path());
+        assert_ne!(first, second);
+        create_new_file(&second).unwrap();
+        assert_ne!(new_backup_path(dir.path()), second);
+    }
+
+    #[test]
+    fn restore_keeps_current_db_in_backup_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_dir = dir.path().join(crate::config::BACKUP_DIR_NAME);
+        let db_path = dir.path().join(DB_FILE_NAME);
+        let src = dir.path().join("backup.db");
+        for (path, name) in [(&db_path, "current"), (&src, "restored")] {
+            let mut conn = Connection::open(path).unwrap();
+            crate::migration::migrate(&mut conn, &backup_dir).unwrap();
+            conn.execute("INSERT INTO file (path) VALUES (?1)", [name])
+                .unwrap();
+        }
+
+        restore_db(&src, &db_path, &backup_dir).unwrap();
+        let file_path = |path: &Path| -> String {
+            Connection::open(path)
+                .unwrap()
+                .query_row("SELECT path FROM file", [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(file_path(&db_path), "restored");
+        let kept: Vec<_> = std::fs::read_dir(&backup_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(file_path(&kept[0]), "current");
+        assert!(!std::fs::read_dir(dir.path()).unwrap().any(|entry| entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".bak")));
+    }
+}
@@ -1,8 +1,13 @@
 //! Data models for cached data on memory from DB.
 
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
-use actix_web::{error, web, HttpRequest, HttpResponse};
+use actix_web::{error, web, web::Bytes, HttpRequest, HttpResponse};
+use lru::LruCache;
+use serde::Serialize;
 
 use crate::{map_err, session::get_valid_session, MyData};
 
@@ -15,6 +20,8 @@ pub(crate) struct FilePayload {
 pub(crate) struct AlbumPayload {
     pub password_hash: String,
     pub owner: usize,
+    /// Public override; the album does not inherit the locks of its ancestors.
+    pub public: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -40,6 +47,7 @@ impl CacheEntry {
             payload: CachePayload::Album(AlbumPayload {
                 password_hash: String::new(),
                 owner,
+                public: false,
             }),
         }
     }
@@ -69,7 +77,106 @@ impl CacheEntry {
 /// Cached data from DB and also filesystem. It is kept in-memory and written back to disk on exit.
 pub(crate) type CacheMap = HashMap<PathBuf, CacheEntry>;
 
-#[actix_web::get("/clear_cache")]
+/// Recently used thumbnail bytes, separate from the metadata in [`CacheMap`]. The least recently
+/// used ones are evicted when the total size exceeds the budget. The entries are not validated
+/// against the files; check the modified date in [`CacheMap`] before using them.
+pub(crate) struct ThumbnailCache {
+    entries: LruCache<PathBuf, Bytes>,
+    /// Total size of the entries in bytes
+    size: usize,
+    /// In bytes. 0 disables the cache.
+    budget: usize,
+    stats: ThumbnailCacheStats,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct ThumbnailCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl ThumbnailCache {
+    pub(crate) fn new(budget: usize) -> Self {
+        Self {
+            entries: LruCache::unbounded(),
+            size: 0,
+            budget,
+            stats: ThumbnailCacheStats::default(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, path: &PathBuf) -> Option<Bytes> {
+        let res = self.entries.get(path).cloned();
+        if res.is_some() {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+        }
+        res
+    }
+
+    pub(crate) fn insert(&mut self, path: PathBuf, data: Bytes) {
+        if data.len() > self.budget {
+            self.remove(&path);
+            return;
+        }
+        self.size += data.len();
+        if let Some(old) = self.entries.put(path, data) {
+            self.size -= old.len();
+        }
+        while self.budget < self.size {
+            let Some((_, evicted)) = self.entries.pop_lru() else {
+                break;
+            };
+            self.size -= evicted.len();
+            self.stats.evictions += 1;
+        }
+    }
+
+    pub(crate) fn remove(&mut self, path: &Path) {
+        if let Some(old) = self.entries.pop(path) {
+            self.size -= old.len();
+        }
+    }
+
+    /// Remove the thumbnails of the path and everything under it, for a moved or deleted directory.
+    pub(crate) fn remove_subtree(&mut self, path: &Path) {
+        let paths: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| key.starts_with(path))
+            .cloned()
+            .collect();
+        for path in paths {
+            self.remove(&path);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(crate) fn budget(&self) -> usize {
+        self.budget
+    }
+
+    pub(crate) fn stats(&self) -> &ThumbnailCacheStats {
+        &self.stats
+    }
+}
+
+#[actix_web::post("/clear_cache")]
 pub(crate) async fn clear_cache(
     data: web::Data<MyData>,
     req: HttpRequest,
@@ -82,7 +189,7 @@ pub(crate) async fn clear_cache(
         ));
     }
     let start = std::time::Instant::now();
-    let mut cache = data.cache.lock().map_err(map_err)?;
+    let mut cache = data.cache.write().unwrap();
     for (_key, entry) in cache.iter_mut() {
         match entry.payload {
             CachePayload::File(ref mut f) => f.data.clear(),
@@ -94,8 +201,7 @@ pub(crate) async fn clear_cache(
         cache.len(),
         start.elapsed().as_secs_f64() / 1e3
     );
-    let conn = data.conn.lock().map_err(map_err)?;
-    conn.execute("UPDATE file SET data = x''", [])
-        .map_err(map_err)?;
+    data.thumbnails.lock().unwrap().clear();
+    data.thumb_store.clear(&*data.conn()?).map_err(map_err)?;
     Ok(HttpResponse::Ok().body("Ok"))
 }
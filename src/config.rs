@@ -0,0 +1,481 @@
+//! The effective configuration of the server.
+//!
+//! It is merged from these sources, in the order of increasing precedence:
+//!
+//! 1. The defaults
+//! 2. The config file, `massphoto.toml` in the album root or the one given by `--config`
+//! 3. The environment variables, `MASSPHOTO_*`
+//! 4. The command line arguments
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{password::PasswordPolicy, thumb_store::ThumbnailStoreKind, Args};
+
+pub(crate) const CONFIG_FILE_NAME: &str = "massphoto.toml";
+/// The default directory of the disk thumbnail store. It is hidden from the album list, as well as
+/// any other file or directory whose name starts with a dot.
+pub(crate) const THUMBNAIL_DIR_NAME: &str = ".thumbnails";
+/// The default directory of the video renditions, hidden as well
+pub(crate) const RENDITION_DIR_NAME: &str = ".renditions";
+/// The default directory of the DB backups made by `POST /admin/backup`
+pub(crate) const BACKUP_DIR_NAME: &str = ".backups";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// The root directory of the photo albums. A relative path in the config file is relative to
+    /// the directory of the config file.
+    pub path: PathBuf,
+    pub port: u16,
+    pub host: String,
+    pub cors_origin: String,
+    /// In seconds
+    pub cleanup_period: u64,
+    /// In bytes
+    pub upload_limit: u64,
+    /// The certificate chain in PEM. Serves HTTPS instead of HTTP if given with `tls_key`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_cert: Option<PathBuf>,
+    /// The private key in PEM
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_key: Option<PathBuf>,
+    /// The port to listen to plain HTTP and redirect to HTTPS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_redirect_port: Option<u16>,
+    /// Where to store thumbnails
+    pub thumbnail_store: ThumbnailStoreKind,
+    /// The directory to store thumbnails in, if `thumbnail_store` is `disk`. Defaults to
+    /// `.thumbnails` in the album root.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_dir: Option<PathBuf>,
+    /// Memory budget for recently used thumbnails, in megabytes
+    pub thumbnail_cache_mb: u64,
+    /// Maximum number of thumbnails made concurrently on demand. 0 means the number of CPUs.
+    pub thumbnail_workers: usize,
+    /// Pre-generate missing or outdated thumbnails in the background on startup
+    pub pregenerate: bool,
+    /// Number of threads to pre-generate thumbnails. 0 means the number of CPUs.
+    pub pregenerate_workers: usize,
+    /// Also write descriptions into the XMP metadata of the image files, and read the embedded
+    /// ones of new images
+    pub embed_descriptions: bool,
+    /// The ffmpeg executable to make lower bitrate renditions of videos. An empty string disables
+    /// transcoding, and the original videos are streamed.
+    pub ffmpeg: String,
+    /// The directory to store video renditions in. Defaults to `.renditions` in the album root.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rendition_dir: Option<PathBuf>,
+    /// The directory to store DB backups made by `POST /admin/backup`, before migrations and
+    /// before restoring in. Defaults to `.backups` in the album root.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup_dir: Option<PathBuf>,
+    /// The password to set to the admin on the first run. A random one is generated and printed
+    /// if not given. Never printed by `--print-config`.
+    #[serde(skip_serializing)]
+    pub admin_password: Option<String>,
+    /// Minimum number of characters of user passwords
+    pub password_min_length: usize,
+    /// Minimum number of the classes of characters in user passwords: lower case letters, upper
+    /// case letters, digits and the others
+    pub password_min_classes: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("."),
+            port: 8808,
+            host: "127.0.0.1".to_string(),
+            cors_origin: "http://localhost:8808".to_string(),
+            cleanup_period: 120,
+            upload_limit: 5_000_000,
+            tls_cert: None,
+            tls_key: None,
+            http_redirect_port: None,
+            thumbnail_store: ThumbnailStoreKind::Sqlite,
+            thumbnail_dir: None,
+            thumbnail_cache_mb: 32,
+            thumbnail_workers: 0,
+            pregenerate: false,
+            pregenerate_workers: 0,
+            embed_descriptions: false,
+            ffmpeg: "ffmpeg".to_string(),
+            rendition_dir: None,
+            backup_dir: None,
+            admin_password: None,
+            password_min_length: 8,
+            password_min_classes: 1,
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn load(args: &Args) -> anyhow::Result<Self> {
+        let config_path = args.config.clone().or_else(|| {
+            let path = Path::new(args.path.as_deref().unwrap_or(".")).join(CONFIG_FILE_NAME);
+            path.exists().then_some(path)
+        });
+
+        let mut config = if let Some(config_path) = &config_path {
+            Self::from_file(config_path)?
+        } else {
+            Self::default()
+        };
+
+        if let Some(path) = &args.path {
+            config.path = PathBuf::from(path);
+        }
+        if let Some(port) = args.port {
+            config.port = port;
+        }
+        if let Some(host) = &args.host {
+            config.host = host.clone();
+        }
+        if let Some(cors_origin) = &args.cors_origin {
+            config.cors_origin = cors_origin.clone();
+        }
+        if let Some(cleanup_period) = args.cleanup_period {
+            config.cleanup_period = cleanup_period;
+        }
+        if let Some(upload_limit) = args.upload_limit {
+            config.upload_limit = upload_limit;
+        }
+        if let Some(tls_cert) = &args.tls_cert {
+            config.tls_cert = Some(tls_cert.clone());
+        }
+        if let Some(tls_key) = &args.tls_key {
+            config.tls_key = Some(tls_key.clone());
+        }
+        if let Some(http_redirect_port) = args.http_redirect_port {
+            config.http_redirect_port = Some(http_redirect_port);
+        }
+        if let Some(thumbnail_store) = args.thumbnail_store {
+            config.thumbnail_store = thumbnail_store;
+        }
+        if let Some(thumbnail_dir) = &args.thumbnail_dir {
+            config.thumbnail_dir = Some(thumbnail_dir.clone());
+        }
+        if let Some(thumbnail_cache_mb) = args.thumbnail_cache_mb {
+            config.thumbnail_cache_mb = thumbnail_cache_mb;
+        }
+        if let Some(thumbnail_workers) = args.thumbnail_workers {
+            config.thumbnail_workers = thumbnail_workers;
+        }
+        if let Some(pregenerate) = flag(args.pregenerate, args.no_pregenerate) {
+            config.pregenerate = pregenerate;
+        }
+        if let Some(pregenerate_workers) = args.pregenerate_workers {
+            config.pregenerate_workers = pregenerate_workers;
+        }
+        if let Some(embed_descriptions) = flag(args.embed_descriptions, args.no_embed_descriptions)
+        {
+            config.embed_descriptions = embed_descriptions;
+        }
+        if let Some(ffmpeg) = &args.ffmpeg {
+            config.ffmpeg = ffmpeg.clone();
+        }
+        if let Some(rendition_dir) = &args.rendition_dir {
+            config.rendition_dir = Some(rendition_dir.clone());
+        }
+        if let Some(backup_dir) = &args.backup_dir {
+            config.backup_dir = Some(backup_dir.clone());
+        }
+        if let Some(admin_password) = &args.admin_password {
+            config.admin_password = Some(admin_password.clone());
+        }
+        if let Some(password_min_length) = args.password_min_length {
+            config.password_min_length = password_min_length;
+        }
+        if let Some(password_min_classes) = args.password_min_classes {
+            config.password_min_classes = password_min_classes;
+        }
+
+        config.validate().with_context(|| match &config_path {
+            Some(config_path) => format!("Invalid configuration (config file: {config_path:?})"),
+            None => "Invalid configuration".to_string(),
+        })?;
+
+        Ok(config)
+    }
+
+    fn from_file(config_path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(config_path)
+            .with_context(|| format!("Failed to read the config file {config_path:?}"))?;
+        let mut config: Self = toml::from_str(&text)
+            .with_context(|| format!("Failed to parse the config file {config_path:?}"))?;
+        if let Some(dir) = config_path.parent() {
+            config.path = dir.join(&config.path);
+            config.tls_cert = config.tls_cert.map(|path| dir.join(path));
+            config.tls_key = config.tls_key.map(|path| dir.join(path));
+            config.thumbnail_dir = config.thumbnail_dir.map(|path| dir.join(path));
+            config.rendition_dir = config.rendition_dir.map(|path| dir.join(path));
+            config.backup_dir = config.backup_dir.map(|path| dir.join(path));
+        }
+        Ok(config)
+    }
+
+    pub(crate) fn thumbnail_dir(&self) -> PathBuf {
+        self.thumbnail_dir
+            .clone()
+            .unwrap_or_else(|| self.path.join(THUMBNAIL_DIR_NAME))
+    }
+
+    pub(crate) fn rendition_dir(&self) -> PathBuf {
+        self.rendition_dir
+            .clone()
+            .unwrap_or_else(|| self.path.join(RENDITION_DIR_NAME))
+    }
+
+    pub(crate) fn backup_dir(&self) -> PathBuf {
+        self.backup_dir
+            .clone()
+            .unwrap_or_else(|| self.path.join(BACKUP_DIR_NAME))
+    }
+
+    pub(crate) fn password_policy(&self) -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: self.password_min_length,
+            min_classes: self.password_min_classes,
+        }
+    }
+
+    pub(crate) fn tls_enabled(&self) -> bool {
+        self.tls_cert.is_some() && self.tls_key.is_some()
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !self.path.is_dir() {
+            bail!("path: {:?} is not a directory", self.path);
+        }
+        if self.host.is_empty() {
+            bail!("host: must not be empty. Set \"0.0.0.0\" to listen to all addresses.");
+        }
+        if self.cors_origin.is_empty() {
+            bail!("cors_origin: must not be empty. Set \"*\" to allow any origin.");
+        }
+        if self.cleanup_period == 0 {
+            bail!("cleanup_period: must be at least 1 second");
+        }
+        if self.upload_limit == 0 {
+            bail!("upload_limit: must be greater than 0");
+        }
+        if self.admin_password.as_deref() == Some("") {
+            bail!("admin_password: must not be empty");
+        }
+        if self.password_min_length == 0 {
+            bail!("password_min_length: must be at least 1");
+        }
+        if 4 < self.password_min_classes {
+            bail!("password_min_classes: must be at most 4");
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            bail!("tls_cert and tls_key: must be given together");
+        }
+        for path in self.tls_cert.iter().chain(self.tls_key.iter()) {
+            if !path.is_file() {
+                bail!("tls_cert or tls_key: {path:?} is not a file");
+            }
+        }
+        if let Some(http_redirect_port) = self.http_redirect_port {
+            if !self.tls_enabled() {
+                bail!("http_redirect_port: requires tls_cert and tls_key");
+            }
+            if http_redirect_port == self.port {
+                bail!("http_redirect_port: must be different from port");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The value of a boolean option given by `--option[=BOOL]` or `--no-option`
+fn flag(value: Option<bool>, negated: bool) -> Option<bool> {
+    if negated {
+        Some(false)
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::Cli;
+
+    fn load_args(args: &[&str]) -> anyhow::Result<Config> {
+        let cli = Cli::try_parse_from([&["massphoto"], args].concat())?;
+        Config::load(&cli.args)
+    }
+
+    #[test]
+    fn later_sources_take_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        std::fs::write(
+            dir.path().join(CONFIG_FILE_NAME),
+            "port = 9000\ncleanup_period = 10\nupload_limit = 100\nembed_descriptions = true\n",
+        )
+        .unwrap();
+
+        let config = load_args(&[root]).unwrap();
+        assert_eq!(config.host, Config::default().host);
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.cleanup_period, 10);
+        assert!(config.embed_descriptions);
+
+        // Only this test sets these variables.
+        std::env::set_var("MASSPHOTO_CLEANUP_PERIOD", "20");
+        std::env::set_var("MASSPHOTO_UPLOAD_LIMIT", "200");
+        std::env::set_var("MASSPHOTO_EMBED_DESCRIPTIONS", "false");
+        let env = load_args(&[root]);
+        let cli = load_args(&[root, "--upload-limit", "300", "--embed-descriptions"]);
+        let negated = load_args(&[root, "--no-embed-descriptions"]);
+        std::env::remove_var("MASSPHOTO_CLEANUP_PERIOD");
+        std::env::remove_var("MASSPHOTO_UPLOAD_LIMIT");
+        std::env::remove_var("MASSPHOTO_EMBED_DESCRIPTIONS");
+
+        let env = env.unwrap();
+        assert_eq!(env.port, 9000);
+        assert_eq!(env.cleanup_period, 20);
+        assert_eq!(env.upload_limit, 200);
+        assert!(!env.embed_descriptions);
+        let cli = cli.unwrap();
+        assert_eq!(cli.cleanup_period, 20);
+        assert_eq!(cli.upload_limit, 300);
+        assert!(cli.embed_descriptions);
+        assert!(!negated.unwrap().embed_descriptions);
+    }
+
+    #[test]
+    fn boolean_options_can_be_switched_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        std::fs::write(
+            dir.path().join(CONFIG_FILE_NAME),
+            "pregenerate = true\nembed_descriptions = true\n",
+        )
+        .unwrap();
+
+        let config = load_args(&[root, "--no-pregenerate", "--embed-descriptions=false"]).unwrap();
+        assert!(!config.pregenerate);
+        assert!(!config.embed_descriptions);
+        let config = load_args(&["--no-pregenerate", "--pregenerate", root]).unwrap();
+        assert!(config.pregenerate);
+    }
+
+    #[test]
+    fn relative_paths_are_relative_to_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("photos")).unwrap();
+        std::fs::create_dir(dir.path().join("etc")).unwrap();
+        let config_path = dir.path().join("etc").join("massphoto.toml");
+        std::fs::write(
+            &config_path,
+            "path = \"../photos\"\nthumbnail_dir = \"thumbs\"\nbackup_dir = \"/var/backups\"\n",
+        )
+        .unwrap();
+
+        let config = load_args(&["--config", config_path.to_str().unwrap()]).unwrap();
+        assert_eq!(config.path, dir.path().join("etc").join("../photos"));
+        assert_eq!(
+            config.thumbnail_dir(),
+            dir.path().join("etc").join("thumbs")
+        );
+        assert_eq!(config.backup_dir(), Path::new("/var/backups"));
+        assert_eq!(config.rendition_dir(), config.path.join(RENDITION_DIR_NAME));
+
+        // A path given on the command line is relative to the working directory.
+        let root = dir.path().join("photos");
+        let config = load_args(&[
+            root.to_str().unwrap(),
+            "--config",
+            config_path.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(config.path, root);
+    }
+
+    #[test]
+    fn validate_rejects_invalid_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("cert.pem");
+        std::fs::write(&cert, "").unwrap();
+        let valid = Config {
+            path: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        valid.validate().unwrap();
+
+        let invalid = [
+            Config {
+                path: cert.clone(),
+                ..valid.clone()
+            },
+            Config {
+                host: String::new(),
+                ..valid.clone()
+            },
+            Config {
+                cleanup_period: 0,
+                ..valid.clone()
+            },
+            Config {
+                admin_password: Some(String::new()),
+                ..valid.clone()
+            },
+            Config {
+                password_min_classes: 5,
+                ..valid.clone()
+            },
+            Config {
+                tls_cert: Some(cert.clone()),
+                ..valid.clone()
+            },
+            Config {
+                tls_cert: Some(cert.clone()),
+                tls_key: Some(dir.path().join("missing.pem")),
+                ..valid.clone()
+            },
+            Config {
+                http_redirect_port: Some(8080),
+                ..valid.clone()
+            },
+            Config {
+                tls_cert: Some(cert.clone()),
+                tls_key: Some(cert.clone()),
+                http_redirect_port: Some(valid.port),
+                ..valid.clone()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+        Config {
+            tls_cert: Some(cert.clone()),
+            tls_key: Some(cert),
+            http_redirect_port: Some(8080),
+            ..valid
+        }
+        .validate()
+        .unwrap();
+    }
+
+    #[test]
+    fn invalid_file_fails_to_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        std::fs::write(dir.path().join(CONFIG_FILE_NAME), "unknown = 1\n").unwrap();
+        assert!(load_args(&[root]).is_err());
+        std::fs::write(
+            dir.path().join(CONFIG_FILE_NAME),
+            "password_min_length = 0\n",
+        )
+        .unwrap();
+        assert!(load_args(&[root]).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! Cross-site request forgery protection.
+//!
+//! Each session gets a random token, which is handed to the frontend by `/sessions`. Every
+//! state-changing request has to send it back in the `X-CSRF-Token` header. A cross-site
+//! attacker can make the browser send the session cookie, but cannot read the token.
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+    web, Error, HttpResponse,
+};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use rand::RngCore;
+
+use crate::{api_token::bearer_token, session::SESSION_COOKIE, MyData};
+
+pub(crate) const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Generate a random token encoded in hex
+pub(crate) fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Compare in constant time to avoid leaking the token by timing
+fn token_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// A middleware that verifies the CSRF token on every request with a method other than GET,
+/// HEAD or OPTIONS.
+pub(crate) struct Csrf;
+
+impl<S, B> Transform<S, ServiceRequest> for Csrf
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = CsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfMiddleware { service }))
+    }
+}
+
+pub(crate) struct CsrfMiddleware<S> {
+    service: S,
+}
+
+impl<S> CsrfMiddleware<S> {
+    fn verify(req: &ServiceRequest) -> bool {
+        if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+            return true;
+        }
+        // A cross-site request cannot set the Authorization header, and an API token is not sent
+        // by the browser by itself.
+        if bearer_token(req.headers()).is_some() {
+            return true;
+        }
+        let Some(data) = req.app_data::<web::Data<MyData>>() else {
+            return false;
+        };
+        let Some(token) = req
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return false;
+        };
+        let sessions = data.sessions.read().unwrap();
+        req.cookie(SESSION_COOKIE)
+            .and_then(|cookie| sessions.get(cookie.value()))
+            .map(|session| token_eq(&session.csrf_token, token))
+            .unwrap_or(false)
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if !Self::verify(&req) {
+            println!("CSRF token mismatch on {} {}", req.method(), req.path());
+            let res = req.into_response(
+                HttpResponse::Forbidden().body("CSRF token mismatch. Please reload the browser."),
+            );
+            return Box::pin(ready(Ok(res.map_into_right_body())));
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header::AUTHORIZATION, test::TestRequest};
+
+    #[test]
+    fn compare_tokens() {
+        let token = generate_token();
+        assert!(token_eq(&token, &token.to_uppercase().to_lowercase()));
+        assert!(!token_eq(&token, &generate_token()));
+        assert!(!token_eq(&token, &token[1..]));
+        assert!(!token_eq(&token, ""));
+        assert!(token_eq("", ""));
+        let mut flipped = token.clone().into_bytes();
+        flipped[63] = if flipped[63] == b'0' { b'1' } else { b'0' };
+        assert!(!token_eq(&token, std::str::from_utf8(&flipped).unwrap()));
+    }
+
+    #[test]
+    fn token_format() {
+        let token = generate_token();
+        assert_eq!(token.len(), 64);
+        assert!(token.bytes().all(|b| b.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn verify_methods() {
+        let verify = CsrfMiddleware::<()>::verify;
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            assert!(verify(
+                &TestRequest::default().method(method).to_srv_request()
+            ));
+        }
+        let post = TestRequest::post().insert_header((CSRF_HEADER, "token"));
+        assert!(!verify(&post.to_srv_request()));
+        let bearer = TestRequest::post().insert_header((AUTHORIZATION, "Bearer mpt_token"));
+        assert!(verify(&bearer.to_srv_request()));
+    }
+}
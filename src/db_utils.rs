@@ -2,6 +2,7 @@ use std::{
     collections::HashMap,
     path::{Path, PathBuf},
     sync::{Mutex, RwLock},
+    time::Duration,
 };
 
 use actix_web::web;
@@ -9,138 +10,181 @@ use dunce::canonicalize;
 use rusqlite::Connection;
 
 use crate::{
-    cache::{CacheEntry, CachePayload},
-    files::load_cache,
-    measure_time, Args, MyData,
+    access::load_access_control,
+    api_token::prune_token_sessions,
+    cache::{CachePayload, ThumbnailCache},
+    config::Config,
+    files::{load_cache, Thumbnailer},
+    measure_time,
+    migration::migrate,
+    pregenerate::Pregenerator,
+    rate_limit::load_rate_limiter,
+    thumb_store::{new_store, ThumbnailStore},
+    totp::{encrypt_plain_secrets, TotpKey, TOTP_KEY_FILE_NAME},
+    transcode::Transcoder,
+    MyData,
 };
 
-const CURRENT_VERSION: (usize, usize, usize) = (0, 1, 0);
+/// The DB file in the album root
+pub(crate) const DB_FILE_NAME: &str = "sqliter.db";
 
-pub(crate) fn init_db(args: &Args) -> anyhow::Result<web::Data<MyData>> {
-    let path = Path::new(&args.path);
-    let db_path = path.join("sqliter.db");
-    let conn = Connection::open(&db_path)?;
+/// How long a connection waits for another connection to release a write lock on the DB
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
-    if !table_exists(&conn, "schema_version") {
-        // Keep track of when to apply migration
-        conn.execute_batch(&format!(
-            "CREATE TABLE schema_version (
-            major INTEGER NOT NULL,
-            minor INTEGER NOT NULL,
-            release INTEGER NOT NULL
-        );
-        INSERT INTO schema_version (major, minor, release) VALUES ({}, {}, {});",
-            CURRENT_VERSION.0, CURRENT_VERSION.1, CURRENT_VERSION.2,
-        ))
-        .unwrap();
-        println!("table \"schema_version\" created!");
-    } else {
-        let version: (usize, usize, usize) = conn
-            .query_row("SELECT * from schema_version", [], |row| {
-                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-            })
-            .unwrap();
-        if CURRENT_VERSION.0 < version.0 {
-            return Err(anyhow::anyhow!(
-                "The database schema is newer than this program."
-            ));
-        }
+pub(crate) type DbPool = r2d2::Pool<SqliteConnectionManager>;
+pub(crate) type DbConnection = r2d2::PooledConnection<SqliteConnectionManager>;
+
+/// Opens connections to the SQLite DB file for the pool. The DB is put in WAL mode, so that
+/// readers do not block a writer and vice versa.
+pub(crate) struct SqliteConnectionManager {
+    path: PathBuf,
+}
+
+impl r2d2::ManageConnection for SqliteConnectionManager {
+    type Connection = Connection;
+    type Error = rusqlite::Error;
+
+    fn connect(&self) -> Result<Connection, rusqlite::Error> {
+        let conn = Connection::open(&self.path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        Ok(conn)
     }
 
-    if !table_exists(&conn, "file") {
-        conn.execute(
-            "CREATE table file (
-            path TEXT PRIMARY KEY,
-            modified REAL,
-            desc TEXT,
-            data BLOB
-        )",
-            [],
-        )
-        .unwrap();
-        println!("table \"file\" created!");
+    fn is_valid(&self, conn: &mut Connection) -> Result<(), rusqlite::Error> {
+        conn.execute_batch("")
     }
 
-    if !table_exists(&conn, "album") {
-        conn.execute(
-            "CREATE TABLE album (
-                path TEXT PRIMARY KEY,
-                password TEXT,
-                desc TEXT,
-                owner INTEGER NOT NULL
-            )",
-            [],
-        )
-        .unwrap();
-        println!("table \"album\" created!");
+    fn has_broken(&self, _conn: &mut Connection) -> bool {
+        false
     }
+}
 
-    crate::user::init_table(&conn)?;
+pub(crate) fn init_db(config: &Config) -> anyhow::Result<web::Data<MyData>> {
+    let path = config.path.as_path();
+    let db_path = path.join(DB_FILE_NAME);
+    let pool = r2d2::Pool::new(SqliteConnectionManager {
+        path: db_path.clone(),
+    })?;
+    let mut conn = pool.get()?;
+
+    migrate(&mut conn, &config.backup_dir())?;
+    let totp_key = TotpKey::load_or_create(&path.join(TOTP_KEY_FILE_NAME))?;
+    let encrypted = encrypt_plain_secrets(&mut conn, &totp_key)?;
+    if 0 < encrypted {
+        println!("Encrypted {encrypted} TOTP secrets stored in plain text");
+    }
 
     println!("tables opened");
 
     let mut cache = HashMap::new();
     load_cache(&mut cache, &conn, &Path::new(path))?;
+    let access = load_access_control(&conn)?;
+    let rate_limiter = load_rate_limiter(&conn)?;
+
+    drop(conn);
 
     let data = web::Data::new(MyData {
-        path: Mutex::new(canonicalize(PathBuf::from(path))?),
-        cache: Mutex::new(cache),
-        conn: Mutex::new(conn),
+        path: canonicalize(PathBuf::from(path))?,
+        cache: RwLock::new(cache),
+        thumbnails: Mutex::new(ThumbnailCache::new(
+            config.thumbnail_cache_mb as usize * 1024 * 1024,
+        )),
+        access: RwLock::new(access),
+        pool,
         // stats: Mutex::default(),
         sessions: RwLock::default(),
+        rate_limiter: Mutex::new(rate_limiter),
+        secure_cookies: config.tls_enabled(),
+        thumbnailer: Thumbnailer::new(config.thumbnail_workers),
+        thumb_store: new_store(config.thumbnail_store, &config.thumbnail_dir()),
+        pregenerator: Pregenerator::new(config.pregenerate_workers),
+        transcoder: Transcoder::new(&config.ffmpeg, config.rendition_dir()),
+        backup_dir: config.backup_dir(),
+        embed_descriptions: config.embed_descriptions,
+        password_policy: config.password_policy(),
+        totp_key,
     });
     Ok(data)
 }
 
-pub(crate) fn write_db(
-    data: &MyData,
-    cache: &mut HashMap<PathBuf, CacheEntry>,
-) -> Result<(), rusqlite::Error> {
-    let filter = |(_, entry): &(&PathBuf, &CacheEntry)| entry.new;
-    println!(
-        "Saving {}/{} cached thumbnails...",
-        cache.iter().filter(filter).count(),
-        cache.len()
-    );
-
-    let mut db = data.conn.lock().unwrap();
+/// Write the thumbnails made since the last write to the DB. The cache is not locked while
+/// writing, so that requests are not blocked by a large write.
+pub(crate) fn write_db(data: &MyData) -> anyhow::Result<()> {
+    let new_entries: Vec<_> = {
+        let cache = data.cache.read().unwrap();
+        println!(
+            "Saving {}/{} cached thumbnails...",
+            cache.values().filter(|entry| entry.new).count(),
+            cache.len()
+        );
+        cache
+            .iter()
+            .filter(|(_, entry)| entry.new)
+            .map(|(key, entry)| {
+                let data = match &entry.payload {
+                    CachePayload::File(payload) => Some(payload.data.clone()),
+                    // TODO: currently, albums don't have thumbnail caches
+                    CachePayload::Album(_) => None,
+                };
+                (key.clone(), entry.modified, entry.desc.clone(), data)
+            })
+            .collect()
+    };
 
+    let mut db = data.pool.get()?;
     let tx = db.transaction()?;
-
-    for (key, value) in cache.iter_mut().filter(|(_, entry)| entry.new) {
-        let path_str = key.as_os_str();
-        match &mut value.payload {
-            CachePayload::File(payload) => {
-                let byte_contents = std::mem::take(&mut payload.data);
-                if tx
-                    .query_row(
-                        "SELECT path FROM file WHERE path=?1",
-                        [path_str.to_str()],
-                        |row| row.get::<_, String>(0),
-                    )
-                    .is_ok()
-                {
-                    tx.execute(
-                        "UPDATE file SET modified = ?2, data = ?3 WHERE path = ?1",
-                        rusqlite::params![path_str.to_str(), value.modified, byte_contents],
-                    )?;
-                } else {
-                    tx.execute(
-                        "INSERT INTO file (path, modified, data) VALUES (?1, ?2, ?3)",
-                        rusqlite::params![path_str.to_str(), value.modified, byte_contents],
-                    )?;
-                }
-            }
-            CachePayload::Album(_value) => {
-                // TODO: currently, albums don't have thumbnail caches
-            }
+    for (key, modified, desc, byte_contents) in &new_entries {
+        if let Some(byte_contents) = byte_contents {
+            save_thumbnail(
+                &tx,
+                data.thumb_store.as_ref(),
+                key,
+                *modified,
+                desc.as_deref(),
+                byte_contents,
+            )?;
         }
-        value.new = false;
     }
     tx.commit()?;
+
+    // The thumbnails are in the DB now, so drop them from memory unless they have been remade
+    // while writing.
+    let mut cache = data.cache.write().unwrap();
+    for (key, modified, _, _) in new_entries {
+        let Some(entry) = cache.get_mut(&key) else {
+            continue;
+        };
+        if entry.modified != modified {
+            continue;
+        }
+        if let CachePayload::File(payload) = &mut entry.payload {
+            payload.data = vec![];
+        }
+        entry.new = false;
+    }
     Ok(())
 }
 
+/// Record the modified date of the file and save its thumbnail to the store. The description is
+/// kept if `desc` is `None`.
+pub(crate) fn save_thumbnail(
+    conn: &Connection,
+    store: &dyn ThumbnailStore,
+    path: &Path,
+    modified: f64,
+    desc: Option<&str>,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT INTO file (path, modified, desc) VALUES (?1, ?2, ?3)
+            ON CONFLICT(path) DO UPDATE SET modified = ?2, desc = COALESCE(?3, desc)",
+        rusqlite::params![path.to_str(), modified, desc],
+    )?;
+    store.save(conn, path, data)
+}
+
 pub(crate) fn table_exists(conn: &Connection, name: &str) -> bool {
     conn.query_row(
         "SELECT name FROM sqlite_master WHERE type='table' AND name=?1",
@@ -150,19 +194,23 @@ pub(crate) fn table_exists(conn: &Connection, name: &str) -> bool {
     .is_ok()
 }
 
+pub(crate) fn column_exists(conn: &Connection, table: &str, column: &str) -> bool {
+    conn.query_row(
+        "SELECT name FROM pragma_table_info(?1) WHERE name=?2",
+        [table, column],
+        |row| row.get(0) as rusqlite::Result<String>,
+    )
+    .is_ok()
+}
+
 pub(crate) async fn periodic_cleanup(data: web::Data<MyData>, cleanup_period: u64) {
     let mut interval = actix_rt::time::interval(std::time::Duration::from_secs(cleanup_period));
     let mut i = 0;
     loop {
         interval.tick().await;
-        let mut all_files = 0;
-        let (mut cached_files, mut cache_size) = (0, 0);
-        let (res, tim) = measure_time(|| -> rusqlite::Result<()> {
-            let Ok(mut cache) = data.cache.try_lock() else {
-                return Ok(());
-            };
-            all_files = cache.len();
-            (cached_files, cache_size) = cache.values().fold((0, 0), |mut acc, entry| {
+        let (all_files, cached_files, cache_size) = {
+            let cache = data.cache.read().unwrap();
+            let (cached_files, cache_size) = cache.values().fold((0, 0), |mut acc, entry| {
                 match entry.payload {
                     CachePayload::File(ref f) => {
                         if !f.data.is_empty() {
@@ -174,16 +222,45 @@ pub(crate) async fn periodic_cleanup(data: web::Data<MyData>, cleanup_period: u6
                 }
                 acc
             });
-            i += 1;
-            write_db(&data, &mut cache)?;
-            Ok(())
-        });
+            (cache.len(), cached_files, cache_size)
+        };
+        i += 1;
+        // Writing to the DB blocks, so keep it off the async executor.
+        let data_copy = data.clone();
+        let (res, tim) = match web::block(move || measure_time(|| write_db(&data_copy))).await {
+            Ok(res) => res,
+            Err(e) => (Err(e.into()), 0.),
+        };
         println!(
             "Periodic Housekeeping {i} in {tim} s: {}/{} images, est. size: {}kb",
             cached_files,
             all_files,
             cache_size as f64 / 1024.
         );
+        {
+            let thumbnails = data.thumbnails.lock().unwrap();
+            let stats = thumbnails.stats();
+            println!(
+                "Thumbnail cache: {} entries, {}/{}kb, hits: {}, misses: {}, evictions: {}",
+                thumbnails.len(),
+                thumbnails.size() / 1024,
+                thumbnails.budget() / 1024,
+                stats.hits,
+                stats.misses,
+                stats.evictions
+            );
+        }
+        let data_copy = data.clone();
+        match web::block(move || prune_token_sessions(&data_copy)).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(pruned)) => println!("Removed {pruned} sessions of invalid API tokens"),
+            Ok(Err(e)) => println!("Error in pruning API token sessions: {e}"),
+            Err(e) => println!("Error in pruning API token sessions: {e}"),
+        }
+        let pruned = data.rate_limiter.lock().unwrap().prune();
+        if 0 < pruned {
+            println!("Forgot {pruned} stale failed login records");
+        }
         if let Err(e) = res {
             // A failure to saving the file is not a fatal error. Print on console and carry on.
             println!("Error in periodic write_db: {e}");
@@ -1,20 +1,36 @@
 mod auth;
+mod batch;
+mod http_cache;
 mod images;
 mod load_cache;
 mod scan_dir;
+mod thumbnailer;
+mod xmp;
 
-use self::scan_dir::{scan_dir, ScanDirResult};
-use crate::{session::find_session, MyData};
-use actix_web::{error, web, HttpRequest, HttpResponse};
+use self::{
+    http_cache::{cached_response, CachePolicy, Validators},
+    scan_dir::{scan_dir, ScanDirResult},
+};
+use crate::{map_err, session::find_session, MyData};
+use actix_web::{error, http::header::EntityTag, web, HttpRequest, HttpResponse};
 
-use std::{include_str, path::PathBuf};
+use std::{
+    include_str,
+    path::{Path, PathBuf},
+};
 
 pub(crate) use self::{
-    auth::{authorized, get_owner, set_album_lock, set_owner, CheckAuth},
+    auth::{
+        authorized_path, get_owner, set_album_lock, set_album_public, set_owner, validate_path,
+        CheckAuth,
+    },
+    batch::execute_batch,
     images::{
-        delete_file, get_file, get_file_thumb, get_image_desc, move_file, set_image_desc, upload,
+        delete_file, embed_desc, get_file, get_file_modified, get_file_thumb, get_image_desc,
+        make_thumbnail, move_file, new_image_desc, set_image_desc, upload,
     },
     load_cache::load_cache,
+    thumbnailer::{worker_count, Thumbnailer},
 };
 
 const THUMBNAIL_SIZE: u32 = 100;
@@ -72,15 +88,16 @@ pub(crate) async fn get_bundle_css() -> HttpResponse {
 pub(crate) async fn get_file_list_root(
     data: web::Data<MyData>,
     req: HttpRequest,
-) -> actix_web::Result<web::Json<ScanDirResult>> {
+) -> actix_web::Result<HttpResponse> {
     let sessions = data.sessions.read().unwrap();
     let session = find_session(&req, &sessions);
-    let path = data.path.lock().unwrap();
-    let cache = data.cache.lock().unwrap();
+    let path = &data.path;
+    let cache = data.cache.read().unwrap();
+    let access = data.access.read().unwrap();
 
-    let res = scan_dir(&path, &cache, &path, session)?;
+    let res = scan_dir(path, &cache, &access, path, session)?;
 
-    Ok(web::Json(res))
+    file_list_response(&req, &res)
 }
 
 #[actix_web::get("/file_list/{path:.*}")]
@@ -88,29 +105,59 @@ pub(crate) async fn get_file_list(
     path: web::Path<PathBuf>,
     data: web::Data<MyData>,
     req: HttpRequest,
-) -> actix_web::Result<web::Json<ScanDirResult>> {
+) -> actix_web::Result<HttpResponse> {
     let sessions = data.sessions.read().unwrap();
     let session = find_session(&req, &sessions);
     let path = path.into_inner();
-    let root_path = data.path.lock().unwrap();
-    let cache = data.cache.lock().unwrap();
+    let root_path = &data.path;
+    let cache = data.cache.read().unwrap();
+    let access = data.access.read().unwrap();
 
-    if cache
-        .get(&path)
-        .map(|entry| authorized(&path, entry, session, CheckAuth::Read).is_err())
-        .unwrap_or(false)
-    {
+    if authorized_path(&path, session, &cache, &access, CheckAuth::Read).is_err() {
         println!("Album {path:?} is locked");
         return Err(error::ErrorForbidden(
             "Forbidden to access password protected album",
         ));
     }
     let abs_path = root_path.join(&path);
-    let res = scan_dir(&root_path, &cache, &abs_path, session)?;
+    let res = scan_dir(root_path, &cache, &access, &abs_path, session)?;
 
     println!("File list for {path:?}");
 
-    Ok(web::Json(res))
+    file_list_response(&req, &res)
+}
+
+/// Respond with the file list, or 304 if the client has the same one. The ETag is the hash of the
+/// list, which reflects both the directory contents and what the session is allowed to see.
+fn file_list_response(req: &HttpRequest, res: &ScanDirResult) -> actix_web::Result<HttpResponse> {
+    let body = serde_json::to_vec(res).map_err(map_err)?;
+    let validators = Validators {
+        etag: EntityTag::new_strong(sha256::digest(body.as_slice())),
+        last_modified: None,
+    };
+    Ok(
+        match cached_response(req, &validators, CachePolicy::Revalidate) {
+            Ok(mut builder) => builder.content_type("application/json").body(body),
+            Err(not_modified) => *not_modified,
+        },
+    )
+}
+
+/// Returns true if the file has an extension of an image that we can make a thumbnail of.
+pub(crate) fn is_image(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| {
+            let ext = ext.to_ascii_lowercase();
+            ext == "jpg" || ext == "png"
+        })
+        .unwrap_or(false)
+}
+
+/// Returns true if the file or directory is hidden from the albums, i.e. its name starts with a dot.
+pub(crate) fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.'))
 }
 
 /// Standard's `Path` can be used for last segment of file extensions,
@@ -1,6 +1,8 @@
 //! Authentication related methods, i.e. involves both the file cache and the user accounts.
 
+use super::is_hidden;
 use crate::{
+    access::{AccessControl, Role},
     cache::{CacheEntry, CacheMap, CachePayload},
     map_err,
     session::{get_valid_session, Session},
@@ -24,54 +26,85 @@ pub(crate) enum CheckAuth {
     Ownership,
     /// Check for read access
     Read,
+    /// Check for permission to upload files
+    Upload,
+    /// Check for permission to move, delete or describe files
+    Edit,
 }
 
-/// Returns true when the path is accessible
-pub(crate) fn authorized(
+impl CheckAuth {
+    fn required_role(self) -> Role {
+        match self {
+            Self::Read => Role::Viewer,
+            Self::Upload => Role::Contributor,
+            Self::Edit => Role::Editor,
+            Self::Ownership => Role::Owner,
+        }
+    }
+
+    fn forbidden_message(self) -> &'static str {
+        match self {
+            Self::Read => "Not authorized to access",
+            Self::Upload => "Not authorized to upload to this album. Ask the owner to grant you the contributor role.",
+            Self::Edit => "Not authorized to modify this album. Ask the owner to grant you the editor role.",
+            Self::Ownership => "Owner is different from the current session user. Ask the administrator to give you the ownership of this album.",
+        }
+    }
+}
+
+/// Returns the effective role of the session user on the path.
+///
+/// The admin is the owner of everything. Otherwise, ownership and grants on the path and all of
+/// its ancestors are inherited down the directory tree, and the highest of them wins.
+pub(crate) fn effective_role(
     path: &Path,
-    cache_entry: &CacheEntry,
     session: Option<&Session>,
-    check_auth: CheckAuth,
-) -> actix_web::Result<()> {
-    if !cache_entry.is_locked() {
-        return Ok(());
-    }
-    let session = session
-        .ok_or_else(|| error::ErrorForbidden("Session is invalid. Try reloading the browser"))?;
+    cache: &CacheMap,
+    access: &AccessControl,
+) -> Option<Role> {
+    let session = session?;
     if session.is_admin {
-        return Ok(());
-    }
-    if cache_entry
-        .owner()
-        .zip(session.user_id)
-        .map(|(owner, user)| owner == user)
-        .unwrap_or(false)
-    {
-        return Ok(());
-    }
-    // When we want to know about only ownership, do not care about temporary authentication.
-    if matches!(check_auth, CheckAuth::Ownership) {
-        Err(error::ErrorForbidden(
-            "Owner is different from the current session user. Ask the administrator to give you the ownership of this album.",
-        ))
-    } else if !session.auth_dirs.contains(path) {
-        Err(error::ErrorForbidden("Not authorized to access"))
-    } else {
-        Ok(())
+        return Some(Role::Owner);
     }
+    let user_id = session.user_id?;
+    let groups = access.groups_of(user_id);
+    path.ancestors()
+        .filter_map(|dir| {
+            if cache.get(dir).and_then(|entry| entry.owner()) == Some(user_id) {
+                Some(Role::Owner)
+            } else {
+                access.granted_role(dir, user_id, &groups)
+            }
+        })
+        .max()
 }
 
-/// Return containing directory if the path was a file. Return itself if the path was a directory
-fn get_containing_directory<'a>(
-    path: &'a Path,
-    cache: &'a CacheMap,
-) -> Option<(&'a Path, &'a CacheEntry)> {
-    cache.get(path).and_then(|entry| match entry.payload {
-        CachePayload::File(_) => path
-            .parent()
-            .and_then(|p| get_containing_directory(p, cache)),
-        CachePayload::Album(_) => Some((path, entry)),
-    })
+/// Returns true when the path is readable without any role.
+///
+/// Every locked album among the path and its ancestors must have been unlocked by password in
+/// this session. An unlock applies to the whole subtree of the album, but a nested album with its
+/// own password needs to be unlocked separately. The walk stops at the nearest album with the
+/// public override flag, so that it does not inherit the locks of its ancestors.
+fn readable(path: &Path, session: Option<&Session>, cache: &CacheMap) -> bool {
+    for dir in path.ancestors() {
+        let Some(entry) = cache.get(dir) else {
+            continue;
+        };
+        let CachePayload::Album(ref album) = entry.payload else {
+            continue;
+        };
+        if entry.is_locked()
+            && !session
+                .map(|session| session.auth_dirs.contains(dir))
+                .unwrap_or(false)
+        {
+            return false;
+        }
+        if album.public {
+            break;
+        }
+    }
+    true
 }
 
 /// Check if the path is valid (i.e. a valid string and does not contain "..")
@@ -94,23 +127,31 @@ pub(crate) fn authorized_path(
     path: &Path,
     session: Option<&Session>,
     cache: &CacheMap,
+    access: &AccessControl,
     check_auth: CheckAuth,
 ) -> actix_web::Result<()> {
-    let entry = get_containing_directory(path, cache);
-    // Our app authenticate per album. Check the containing album authentication.
-    let Some((parent, entry)) = entry else {
-        // Files in the root directory are considered owned by the admin.
-        if session.map(|s| !s.is_admin).unwrap_or(false)
-            && matches!(check_auth, CheckAuth::Ownership)
-        {
-            return Err(error::ErrorForbidden(
-        "Owner is different from the current session user. Ask the administrator to give you the ownership of this album.",
-            ));
-        }
+    // Hidden entries such as the thumbnail store and the DB backups are not part of the albums.
+    if path
+        .components()
+        .any(|component| is_hidden(Path::new(component.as_os_str())))
+    {
+        return Err(error::ErrorNotFound("No such file or album"));
+    }
+    if effective_role(path, session, cache, access)
+        .map(|role| check_auth.required_role() <= role)
+        .unwrap_or(false)
+    {
         return Ok(());
-    };
-    let res = authorized(parent, &entry, session, check_auth);
-    res
+    }
+    if matches!(check_auth, CheckAuth::Read) && readable(path, session, cache) {
+        return Ok(());
+    }
+    if session.is_none() {
+        return Err(error::ErrorForbidden(
+            "Session is invalid. Try reloading the browser",
+        ));
+    }
+    Err(error::ErrorForbidden(check_auth.forbidden_message()))
 }
 
 #[actix_web::post("/albums/{file:.*}/lock")]
@@ -125,10 +166,9 @@ pub(crate) async fn set_album_lock(
     let user_id = session
         .user_id
         .ok_or_else(|| error::ErrorBadRequest("You need to login to lock an album"))?;
-    let mut cache = data.cache.lock().map_err(map_err)?;
-    if !session.is_admin {
-        authorized_path(&path, Some(session), &cache, CheckAuth::Ownership)?;
-    }
+    let mut cache = data.cache.write().unwrap();
+    let access = data.access.read().unwrap();
+    authorized_path(&path, Some(session), &cache, &access, CheckAuth::Ownership)?;
     let password = bytes.as_ref();
     let hash = if password.is_empty() {
         "".to_string()
@@ -144,7 +184,7 @@ pub(crate) async fn set_album_lock(
         CacheEntry::album_with_owner(user_id)
     });
 
-    let db = data.conn.lock().unwrap();
+    let db = data.conn()?;
 
     let CachePayload::Album(ref mut payload) = entry.payload else {
         return Err(error::ErrorInternalServerError(
@@ -171,6 +211,69 @@ pub(crate) async fn set_album_lock(
     Ok(HttpResponse::Ok().content_type("text/plain").body("ok"))
 }
 
+#[derive(Deserialize)]
+struct SetPublicParams {
+    public: bool,
+}
+
+/// Set or clear the public override flag, which makes a sub-album readable even if one of its
+/// ancestors is locked.
+#[actix_web::post("/albums/{path:.*}/public")]
+pub(crate) async fn set_album_public(
+    data: web::Data<MyData>,
+    path: web::Path<PathBuf>,
+    params: web::Json<SetPublicParams>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    let sessions = data.sessions.read().map_err(map_err)?;
+    let session = get_valid_session(&req, &sessions)?;
+    let user_id = session
+        .user_id
+        .ok_or_else(|| error::ErrorBadRequest("You need to login to change an album"))?;
+    let mut cache = data.cache.write().unwrap();
+    let access = data.access.read().unwrap();
+    authorized_path(&path, Some(session), &cache, &access, CheckAuth::Ownership)?;
+
+    let mut inserted = false;
+    let entry = cache.entry(path.clone()).or_insert_with(|| {
+        inserted = true;
+        CacheEntry::album_with_owner(user_id)
+    });
+
+    let CachePayload::Album(ref mut payload) = entry.payload else {
+        return Err(error::ErrorBadRequest("Only an album can be made public"));
+    };
+    payload.public = params.public;
+
+    let db = data.conn()?;
+    let updated = if inserted {
+        db.execute(
+            "INSERT INTO album (path, desc, password, owner, public) VALUES (?1, ?2, ?3, ?4, ?5)",
+            rusqlite::params![
+                path.to_str(),
+                entry.desc,
+                payload.password_hash,
+                user_id,
+                params.public
+            ],
+        )
+        .map_err(map_err)?
+    } else {
+        db.execute(
+            "UPDATE album SET public = ?2 WHERE path = ?1",
+            rusqlite::params![path.to_str(), params.public],
+        )
+        .map_err(map_err)?
+    };
+
+    println!(
+        "set_album_public {path:?} to {}, inserted: {inserted}, updated: {updated}",
+        params.public
+    );
+
+    Ok("Ok")
+}
+
 #[actix_web::get("/albums/{path:.*}/owner")]
 pub(crate) async fn get_owner(
     data: web::Data<MyData>,
@@ -179,10 +282,9 @@ pub(crate) async fn get_owner(
 ) -> Result<String> {
     let sessions = data.sessions.read().unwrap();
     let session = get_valid_session(&req, &sessions)?;
-    let cache = data.cache.lock().map_err(map_err)?;
-    if !session.is_admin {
-        authorized_path(&path, Some(session), &cache, CheckAuth::Read)?;
-    }
+    let cache = data.cache.read().unwrap();
+    let access = data.access.read().unwrap();
+    authorized_path(&path, Some(session), &cache, &access, CheckAuth::Read)?;
     let owner = cache
         .get(&*path)
         .and_then(|entry| entry.owner())
@@ -210,7 +312,7 @@ pub(crate) async fn set_owner(
         ));
     }
     let user_id = params.user_id;
-    let mut cache = data.cache.lock().map_err(map_err)?;
+    let mut cache = data.cache.write().unwrap();
 
     let mut inserted = false;
     let entry = cache.entry(path.clone()).or_insert_with(|| {
@@ -221,7 +323,7 @@ pub(crate) async fn set_owner(
         payload.owner = user_id;
     }
 
-    let conn = data.conn.lock().unwrap();
+    let conn = data.conn()?;
 
     let updated = if inserted {
         conn.execute(
@@ -241,3 +343,220 @@ pub(crate) async fn set_owner(
 
     Ok("Ok")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::{Grant, Group, Principal};
+
+    fn session(user_id: usize) -> Session {
+        let mut session = Session::new();
+        session.user_id = Some(user_id);
+        session
+    }
+
+    fn cache_with_albums(albums: &[(&str, usize)]) -> CacheMap {
+        albums
+            .iter()
+            .map(|(path, owner)| (PathBuf::from(path), CacheEntry::album_with_owner(*owner)))
+            .collect()
+    }
+
+    fn grant(access: &mut AccessControl, path: &str, principal: Principal, role: Role) {
+        access
+            .grants
+            .entry(PathBuf::from(path))
+            .or_default()
+            .push(Grant { principal, role });
+    }
+
+    fn user(user_id: usize) -> Principal {
+        Principal {
+            user_id: Some(user_id),
+            group_id: None,
+        }
+    }
+
+    fn group(group_id: usize) -> Principal {
+        Principal {
+            user_id: None,
+            group_id: Some(group_id),
+        }
+    }
+
+    #[test]
+    fn admin_owns_everything() {
+        let mut admin = session(1);
+        admin.is_admin = true;
+        let cache = cache_with_albums(&[("a", 2)]);
+        let access = AccessControl::default();
+        for path in ["", "a", "a/b.jpg", "elsewhere"] {
+            assert_eq!(
+                effective_role(Path::new(path), Some(&admin), &cache, &access),
+                Some(Role::Owner)
+            );
+        }
+    }
+
+    #[test]
+    fn ownership_is_inherited() {
+        let cache = cache_with_albums(&[("a", 2), ("a/b", 3)]);
+        let access = AccessControl::default();
+        let role = |path: &str, session: Option<&Session>| {
+            effective_role(Path::new(path), session, &cache, &access)
+        };
+        assert_eq!(role("a/b/c.jpg", Some(&session(2))), Some(Role::Owner));
+        assert_eq!(role("a/b/c.jpg", Some(&session(3))), Some(Role::Owner));
+        assert_eq!(role("a/x.jpg", Some(&session(3))), None);
+        assert_eq!(role("a", Some(&session(4))), None);
+        assert_eq!(role("a", Some(&Session::new())), None);
+        assert_eq!(role("a", None), None);
+    }
+
+    #[test]
+    fn highest_role_wins() {
+        let cache = cache_with_albums(&[("a", 2)]);
+        let mut access = AccessControl::default();
+        access.groups.insert(
+            7,
+            Group {
+                name: "family".to_string(),
+                members: [3].into(),
+            },
+        );
+        grant(&mut access, "a", user(3), Role::Viewer);
+        grant(&mut access, "a/b", group(7), Role::Editor);
+        grant(&mut access, "a/b/c", user(3), Role::Contributor);
+        // Grants do not lower the inherited ownership.
+        grant(&mut access, "a/b", user(2), Role::Viewer);
+        let role = |path: &str, user_id: usize| {
+            effective_role(Path::new(path), Some(&session(user_id)), &cache, &access)
+        };
+        assert_eq!(role("a", 3), Some(Role::Viewer));
+        assert_eq!(role("a/b", 3), Some(Role::Editor));
+        assert_eq!(role("a/b/c/d.jpg", 3), Some(Role::Editor));
+        assert_eq!(role("x", 3), None);
+        assert_eq!(role("a/b/c", 2), Some(Role::Owner));
+        // Not a member of the group
+        assert_eq!(role("a/b", 4), None);
+    }
+
+    #[test]
+    fn check_auth_requires_role() {
+        let cache = cache_with_albums(&[("a", 2)]);
+        let mut access = AccessControl::default();
+        grant(&mut access, "a", user(3), Role::Contributor);
+        let check = |user_id: usize, check_auth: CheckAuth| {
+            authorized_path(
+                Path::new("a/b.jpg"),
+                Some(&session(user_id)),
+                &cache,
+                &access,
+                check_auth,
+            )
+            .is_ok()
+        };
+        assert!(check(3, CheckAuth::Read));
+        assert!(check(3, CheckAuth::Upload));
+        assert!(!check(3, CheckAuth::Edit));
+        assert!(!check(3, CheckAuth::Ownership));
+        for check_auth in [
+            CheckAuth::Read,
+            CheckAuth::Upload,
+            CheckAuth::Edit,
+            CheckAuth::Ownership,
+        ] {
+            assert!(check(2, check_auth), "{check_auth:?}");
+        }
+        // Without any role, only reading an unlocked album is allowed.
+        assert!(check(4, CheckAuth::Read));
+        assert!(!check(4, CheckAuth::Upload));
+        assert_eq!(CheckAuth::Read.required_role(), Role::Viewer);
+        assert_eq!(CheckAuth::Upload.required_role(), Role::Contributor);
+        assert_eq!(CheckAuth::Edit.required_role(), Role::Editor);
+        assert_eq!(CheckAuth::Ownership.required_role(), Role::Owner);
+    }
+
+    /// Albums owned by the user 1, locked if `locked`, public if `public`
+    fn cache_with_locks(albums: &[(&str, bool, bool)]) -> CacheMap {
+        albums
+            .iter()
+            .map(|(path, locked, public)| {
+                let mut entry = CacheEntry::album_with_owner(1);
+                if let CachePayload::Album(ref mut album) = entry.payload {
+                    if *locked {
+                        album.password_hash = sha256::digest("password");
+                    }
+                    album.public = *public;
+                }
+                (PathBuf::from(path), entry)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn locked_parent_hides_child() {
+        let cache = cache_with_locks(&[("a", true, false), ("a/b", false, false)]);
+        for path in ["a", "a/b", "a/b/c.jpg"] {
+            assert!(!readable(Path::new(path), None, &cache), "{path}");
+            assert!(
+                !readable(Path::new(path), Some(&session(2)), &cache),
+                "{path}"
+            );
+        }
+        assert!(readable(Path::new("x/y.jpg"), None, &cache));
+        assert!(readable(Path::new(""), None, &cache));
+    }
+
+    #[test]
+    fn unlock_grants_subtree() {
+        let cache = cache_with_locks(&[
+            ("a", true, false),
+            ("a/b", false, false),
+            ("a/b/c", true, false),
+        ]);
+        let mut unlocked = session(2);
+        unlocked.auth_dirs.insert(PathBuf::from("a"));
+        assert!(readable(Path::new("a"), Some(&unlocked), &cache));
+        assert!(readable(Path::new("a/b/d.jpg"), Some(&unlocked), &cache));
+        // A nested album with its own password needs its own unlock.
+        assert!(!readable(Path::new("a/b/c/d.jpg"), Some(&unlocked), &cache));
+        unlocked.auth_dirs.insert(PathBuf::from("a/b/c"));
+        assert!(readable(Path::new("a/b/c/d.jpg"), Some(&unlocked), &cache));
+
+        // Unlocking only the nested album does not open its locked ancestor.
+        let mut nested_only = session(3);
+        nested_only.auth_dirs.insert(PathBuf::from("a/b/c"));
+        assert!(!readable(
+            Path::new("a/b/c/d.jpg"),
+            Some(&nested_only),
+            &cache
+        ));
+    }
+
+    #[test]
+    fn public_child_stops_walk() {
+        let cache = cache_with_locks(&[
+            ("a", true, false),
+            ("a/pub", false, true),
+            ("a/pub/locked", true, false),
+            ("a/other", false, false),
+        ]);
+        assert!(readable(Path::new("a/pub"), None, &cache));
+        assert!(readable(Path::new("a/pub/e.jpg"), None, &cache));
+        assert!(!readable(Path::new("a/other/e.jpg"), None, &cache));
+        assert!(!readable(Path::new("a"), None, &cache));
+        // A lock below the public album still applies.
+        assert!(!readable(Path::new("a/pub/locked/e.jpg"), None, &cache));
+        let mut unlocked = session(2);
+        unlocked.auth_dirs.insert(PathBuf::from("a/pub/locked"));
+        assert!(readable(
+            Path::new("a/pub/locked/e.jpg"),
+            Some(&unlocked),
+            &cache
+        ));
+        // A public album that is locked itself still needs the password.
+        let cache = cache_with_locks(&[("a", false, false), ("a/pub", true, true)]);
+        assert!(!readable(Path::new("a/pub/e.jpg"), None, &cache));
+    }
+}
@@ -0,0 +1,697 @@
+//! Batch operations on many files at once, executed with a single authorization pass and
+//! a single DB transaction.
+
+use super::{
+    auth::{authorized_path, validate_path, CheckAuth},
+    embed_desc, get_file_modified,
+};
+use crate::{
+    access::AccessControl,
+    cache::{CacheEntry, CacheMap, CachePayload, FilePayload, ThumbnailCache},
+    map_err,
+    session::{find_session, Session},
+    sidecar::ADMIN_USER_ID,
+    MyData,
+};
+use actix_web::{error, web, HttpRequest, Result};
+use anyhow::{anyhow, bail};
+use rusqlite::{params, Transaction};
+use serde::{Deserialize, Serialize};
+
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum BatchOp {
+    /// Move a file into the `dest` directory
+    Move {
+        path: PathBuf,
+        dest: String,
+    },
+    Delete {
+        path: PathBuf,
+    },
+    SetDesc {
+        path: PathBuf,
+        desc: String,
+    },
+    SetOwner {
+        path: PathBuf,
+        user_id: usize,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct BatchParams {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct BatchItemResult {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl BatchItemResult {
+    fn ok() -> Self {
+        Self {
+            ok: true,
+            error: None,
+        }
+    }
+
+    fn err(e: impl ToString) -> Self {
+        Self {
+            ok: false,
+            error: Some(e.to_string()),
+        }
+    }
+}
+
+/// Changes to the in-memory cache which are applied only after the transaction is committed,
+/// so that the cache never gets ahead of the DB.
+enum CacheUpdate {
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Remove(PathBuf),
+    /// `album_owner` is the owner of the album row, if the path is an album
+    SetDesc {
+        path: PathBuf,
+        desc: String,
+        album_owner: Option<usize>,
+    },
+    SetOwner {
+        path: PathBuf,
+        user_id: usize,
+    },
+}
+
+#[actix_web::post("/batch")]
+pub(crate) async fn execute_batch(
+    data: web::Data<MyData>,
+    params: web::Json<BatchParams>,
+    req: HttpRequest,
+) -> Result<web::Json<Vec<BatchItemResult>>> {
+    let sessions = data.sessions.read().unwrap();
+    let session = find_session(&req, &sessions);
+    let root_dir = &data.path;
+    let mut cache = data.cache.write().unwrap();
+    let access = data.access.read().unwrap();
+
+    // Authorize every operation before touching anything.
+    let authorizations: Vec<_> = params
+        .ops
+        .iter()
+        .map(|op| authorize_op(op, session, &cache, &access))
+        .collect();
+
+    let mut db = data.conn()?;
+    let mut tx = db.transaction().map_err(map_err)?;
+    let mut results = Vec::with_capacity(params.ops.len());
+    let mut updates = vec![];
+
+    for (op, auth) in params.ops.iter().zip(authorizations) {
+        if let Err(e) = auth {
+            results.push(BatchItemResult::err(e));
+            continue;
+        }
+        match execute_op(op, root_dir, &cache, &mut tx) {
+            Ok(update) => {
+                updates.push(update);
+                results.push(BatchItemResult::ok());
+            }
+            Err(e) => {
+                println!("Batch operation {op:?} failed: {e}");
+                results.push(BatchItemResult::err(e));
+            }
+        }
+    }
+
+    if let Err(e) = tx.commit() {
+        let mut thumbnails = data.thumbnails.lock().unwrap();
+        return Err(report_uncommitted(&mut cache, &mut thumbnails, &updates, e));
+    }
+
+    let descs: Vec<_> = updates
+        .iter()
+        .filter_map(|update| match update {
+            CacheUpdate::SetDesc { path, desc, .. } => Some((path.clone(), desc.clone())),
+            _ => None,
+        })
+        .collect();
+    let mut thumbnails = data.thumbnails.lock().unwrap();
+    for update in updates {
+        apply_cache_update(&mut cache, &mut thumbnails, update);
+    }
+    drop(thumbnails);
+
+    // Do not hold the locks while rewriting the files.
+    drop(access);
+    drop(cache);
+    drop(sessions);
+    for (path, desc) in descs {
+        embed_desc(&data, &path, &desc);
+    }
+
+    println!(
+        "Batch executed {}/{} operations",
+        results.iter().filter(|res| res.ok).count(),
+        results.len()
+    );
+
+    Ok(web::Json(results))
+}
+
+/// The files are already moved or deleted when the transaction fails to commit, and they cannot
+/// be put back. Tell which ones, and drop their old paths from the cache, which would otherwise
+/// keep entries of files that no longer exist.
+fn report_uncommitted(
+    cache: &mut CacheMap,
+    thumbnails: &mut ThumbnailCache,
+    updates: &[CacheUpdate],
+    e: rusqlite::Error,
+) -> error::Error {
+    let changes: Vec<_> = updates
+        .iter()
+        .filter_map(|update| match update {
+            CacheUpdate::Move { from, to } => {
+                thumbnails.remove_subtree(from);
+                cache.retain(|path, _| !path.starts_with(from));
+                Some(format!("moved {from:?} to {to:?}"))
+            }
+            CacheUpdate::Remove(path) => {
+                thumbnails.remove(path);
+                cache.remove(path);
+                Some(format!("deleted {path:?}"))
+            }
+            _ => None,
+        })
+        .collect();
+    if changes.is_empty() {
+        return map_err(e);
+    }
+    let message = format!(
+        "Failed to commit the batch: {e}. The database does not record these changes of the files: {}",
+        changes.join(", ")
+    );
+    println!("{message}");
+    error::ErrorInternalServerError(message)
+}
+
+fn authorize_op(
+    op: &BatchOp,
+    session: Option<&Session>,
+    cache: &CacheMap,
+    access: &AccessControl,
+) -> Result<()> {
+    match op {
+        BatchOp::Move { path, dest } => {
+            validate_path(path)?;
+            validate_path(Path::new(dest))?;
+            authorized_path(path, session, cache, access, CheckAuth::Edit)?;
+            authorized_path(Path::new(dest), session, cache, access, CheckAuth::Upload)
+        }
+        BatchOp::Delete { path } => {
+            validate_path(path)?;
+            authorized_path(path, session, cache, access, CheckAuth::Edit)
+        }
+        BatchOp::SetDesc { path, .. } => {
+            validate_path(path)?;
+            authorized_path(path, session, cache, access, CheckAuth::Edit)
+        }
+        BatchOp::SetOwner { path, .. } => {
+            validate_path(path)?;
+            if session.map(|s| s.is_admin).unwrap_or(false) {
+                Ok(())
+            } else {
+                Err(error::ErrorForbidden(
+                    "Only the admin is allowed to change owner",
+                ))
+            }
+        }
+    }
+}
+
+/// Matches the row of the path `?1` and the rows under it, given [`subtree_prefix`] of it as `?2`.
+/// `LIKE` is not used, since it ignores the case and treats `_` in file names as a wildcard.
+const SUBTREE_CONDITION: &str = "(path = ?1 OR substr(path, 1, length(?2)) = ?2)";
+
+fn subtree_prefix(path: &str) -> String {
+    format!("{path}{}", std::path::MAIN_SEPARATOR)
+}
+
+fn path_str(path: &Path) -> anyhow::Result<&str> {
+    path.to_str()
+        .ok_or_else(|| anyhow!("{path:?} is not a valid string"))
+}
+
+/// Execute a single operation in its own savepoint. The DB changes are made first, then the
+/// filesystem, so that a filesystem failure can roll back the DB changes of this item. If the whole
+/// transaction fails to commit afterwards, [`report_uncommitted`] tells the files changed already.
+fn execute_op(
+    op: &BatchOp,
+    root_dir: &Path,
+    cache: &CacheMap,
+    tx: &mut Transaction,
+) -> anyhow::Result<CacheUpdate> {
+    let sp = tx.savepoint()?;
+    let update = match op {
+        BatchOp::Move { path, dest } => {
+            let dest = Path::new(dest);
+            let dest_path = path
+                .file_name()
+                .map(|path| dest.join(path))
+                .unwrap_or_else(|| PathBuf::from(dest));
+            if root_dir.join(&dest_path).exists() {
+                bail!("{dest_path:?} already exists");
+            }
+            let (from, to) = (path_str(path)?, path_str(&dest_path)?);
+            for table in ["file", "album"] {
+                // Only stale rows can be left at the destination, since it does not exist.
+                sp.execute(
+                    &format!("DELETE FROM {table} WHERE {SUBTREE_CONDITION}"),
+                    params![to, subtree_prefix(to)],
+                )?;
+                // A moved directory takes the rows of its descendants with it.
+                sp.execute(
+                    &format!(
+                        "UPDATE {table} SET path = ?3 || substr(path, length(?1) + 1)
+                        WHERE {SUBTREE_CONDITION}"
+                    ),
+                    params![from, subtree_prefix(from), to],
+                )?;
+            }
+            std::fs::rename(root_dir.join(path), root_dir.join(&dest_path))?;
+            println!("Moved {path:?} to {dest_path:?}");
+            CacheUpdate::Move {
+                from: path.clone(),
+                to: dest_path,
+            }
+        }
+        BatchOp::Delete { path } => {
+            sp.execute("DELETE FROM file WHERE path = ?1", [path.to_str()])?;
+            std::fs::remove_file(root_dir.join(path))?;
+            println!("Deleted {path:?}");
+            CacheUpdate::Remove(path.clone())
+        }
+        BatchOp::SetDesc { path, desc } => {
+            let abs_path = root_dir.join(path);
+            if !abs_path.exists() {
+                bail!("{path:?} does not exist");
+            }
+            let mut album_owner = None;
+            match cache.get(path) {
+                _ if abs_path.is_dir() => {
+                    // An album without a row yet gets the owner it inherits, so that adding the
+                    // row does not change anyone's role.
+                    let owner = path
+                        .ancestors()
+                        .find_map(|dir| cache.get(dir).and_then(CacheEntry::owner))
+                        .unwrap_or(ADMIN_USER_ID);
+                    sp.execute(
+                        "INSERT INTO album (path, desc, password, owner) VALUES (?1, ?2, '', ?3)
+                        ON CONFLICT(path) DO UPDATE SET desc = excluded.desc",
+                        params![path.to_str(), desc, owner],
+                    )?;
+                    album_owner = Some(owner);
+                }
+                entry => {
+                    sp.execute(
+                        "INSERT INTO file (path, modified, desc) VALUES (?1, ?2, ?3)
+                        ON CONFLICT(path) DO UPDATE SET desc = excluded.desc",
+                        params![
+                            path.to_str(),
+                            match entry {
+                                Some(entry) => entry.modified,
+                                None => get_file_modified(&abs_path)?,
+                            },
+                            desc
+                        ],
+                    )?;
+                }
+            }
+            CacheUpdate::SetDesc {
+                path: path.clone(),
+                desc: desc.clone(),
+                album_owner,
+            }
+        }
+        BatchOp::SetOwner { path, user_id } => {
+            let abs_path = root_dir.join(path);
+            if !abs_path.exists() {
+                bail!("{path:?} does not exist");
+            }
+            let entry = cache.get(path);
+            if !abs_path.is_dir() || entry.map(|entry| entry.owner().is_none()).unwrap_or(false) {
+                bail!("Only an album can have an owner");
+            }
+            sp.execute(
+                "INSERT INTO album (path, desc, password, owner) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT(path) DO UPDATE SET owner = excluded.owner",
+                params![
+                    path.to_str(),
+                    entry.and_then(|entry| entry.desc.as_ref()),
+                    entry.and_then(|entry| entry.password_hash()).unwrap_or(""),
+                    user_id
+                ],
+            )?;
+            CacheUpdate::SetOwner {
+                path: path.clone(),
+                user_id: *user_id,
+            }
+        }
+    };
+    sp.commit()?;
+    Ok(update)
+}
+
+fn apply_cache_update(cache: &mut CacheMap, thumbnails: &mut ThumbnailCache, update: CacheUpdate) {
+    match update {
+        CacheUpdate::Move { from, to } => {
+            thumbnails.remove_subtree(&from);
+            let moved: Vec<_> = cache
+                .keys()
+                .filter(|path| path.starts_with(&from))
+                .cloned()
+                .collect();
+            for path in moved {
+                let rest = path.strip_prefix(&from).expect("Filtered by the prefix");
+                // Joining an empty path would add a trailing separator.
+                let new_path = if rest.as_os_str().is_empty() {
+                    to.clone()
+                } else {
+                    to.join(rest)
+                };
+                if let Some(entry) = cache.remove(&path) {
+                    cache.insert(new_path, entry);
+                }
+            }
+        }
+        CacheUpdate::Remove(path) => {
+            thumbnails.remove(&path);
+            cache.remove(&path);
+        }
+        CacheUpdate::SetDesc {
+            path,
+            desc,
+            album_owner,
+        } => {
+            cache
+                .entry(path)
+                .or_insert_with(|| match album_owner {
+                    Some(owner) => CacheEntry::album_with_owner(owner),
+                    None => CacheEntry {
+                        new: false,
+                        modified: 0.,
+                        desc: None,
+                        payload: CachePayload::File(FilePayload { data: vec![] }),
+                    },
+                })
+                .desc = Some(desc);
+        }
+        CacheUpdate::SetOwner { path, user_id } => {
+            let entry = cache
+                .entry(path)
+                .or_insert_with(|| CacheEntry::album_with_owner(user_id));
+            if let CachePayload::Album(ref mut payload) = entry.payload {
+                payload.owner = user_id;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web::Bytes;
+    use rusqlite::Connection;
+
+    fn setup() -> (tempfile::TempDir, Connection) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migration::migrate(&mut conn, Path::new("")).unwrap();
+        (dir, conn)
+    }
+
+    fn file_entry() -> CacheEntry {
+        CacheEntry {
+            new: false,
+            modified: 1.,
+            desc: None,
+            payload: CachePayload::File(FilePayload { data: vec![] }),
+        }
+    }
+
+    fn paths(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn
+            .prepare(&format!("SELECT path FROM {table} ORDER BY path"))
+            .unwrap();
+        let paths = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        paths
+    }
+
+    fn run(
+        op: BatchOp,
+        root: &Path,
+        cache: &CacheMap,
+        conn: &mut Connection,
+    ) -> Result<CacheUpdate, String> {
+        let mut tx = conn.transaction().unwrap();
+        let res = execute_op(&op, root, cache, &mut tx).map_err(|e| e.to_string());
+        tx.commit().unwrap();
+        res
+    }
+
+    #[test]
+    fn move_directory_with_descendants() {
+        let (dir, mut conn) = setup();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("a_b/sub")).unwrap();
+        std::fs::write(root.join("a_b/sub/x.jpg"), b"x").unwrap();
+        std::fs::create_dir(root.join("dest")).unwrap();
+        for path in ["a_b/sub/x.jpg", "a_b/y.jpg", "aXb/z.jpg", "A_B/w.jpg"] {
+            conn.execute("INSERT INTO file (path, modified) VALUES (?1, 1)", [path])
+                .unwrap();
+        }
+        conn.execute(
+            "INSERT INTO album (path, desc, password, owner) VALUES ('a_b/sub', '', '', 1)",
+            [],
+        )
+        .unwrap();
+        let mut cache = CacheMap::new();
+        cache.insert("a_b/sub/x.jpg".into(), file_entry());
+        cache.insert("a_b/sub".into(), CacheEntry::album_with_owner(1));
+        cache.insert("aXb/z.jpg".into(), file_entry());
+
+        let op = BatchOp::Move {
+            path: "a_b".into(),
+            dest: "dest".into(),
+        };
+        let mut thumbnails = ThumbnailCache::new(1024);
+        thumbnails.insert("a_b/sub/x.jpg".into(), Bytes::from_static(b"thumb"));
+        thumbnails.insert("aXb/z.jpg".into(), Bytes::from_static(b"thumb"));
+        let update = run(op, root, &cache, &mut conn).unwrap();
+        apply_cache_update(&mut cache, &mut thumbnails, update);
+
+        assert!(root.join("dest/a_b/sub/x.jpg").exists());
+        assert_eq!(
+            paths(&conn, "file"),
+            [
+                "A_B/w.jpg",
+                "aXb/z.jpg",
+                "dest/a_b/sub/x.jpg",
+                "dest/a_b/y.jpg"
+            ]
+        );
+        assert_eq!(paths(&conn, "album"), ["dest/a_b/sub"]);
+        let mut keys: Vec<_> = cache.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                PathBuf::from("aXb/z.jpg"),
+                "dest/a_b/sub".into(),
+                "dest/a_b/sub/x.jpg".into()
+            ]
+        );
+        assert!(thumbnails.get(&"a_b/sub/x.jpg".into()).is_none());
+        assert!(thumbnails.get(&"aXb/z.jpg".into()).is_some());
+    }
+
+    #[test]
+    fn move_refuses_existing_destination() {
+        let (dir, mut conn) = setup();
+        let root = dir.path();
+        std::fs::create_dir(root.join("dest")).unwrap();
+        std::fs::write(root.join("x.jpg"), b"new").unwrap();
+        std::fs::write(root.join("dest/x.jpg"), b"old").unwrap();
+        for path in ["x.jpg", "dest/x.jpg"] {
+            conn.execute("INSERT INTO file (path, modified) VALUES (?1, 1)", [path])
+                .unwrap();
+        }
+
+        let op = BatchOp::Move {
+            path: "x.jpg".into(),
+            dest: "dest".into(),
+        };
+        assert!(run(op, root, &CacheMap::new(), &mut conn).is_err());
+        assert_eq!(std::fs::read(root.join("dest/x.jpg")).unwrap(), b"old");
+        assert_eq!(std::fs::read(root.join("x.jpg")).unwrap(), b"new");
+        assert_eq!(paths(&conn, "file"), ["dest/x.jpg", "x.jpg"]);
+    }
+
+    #[test]
+    fn set_desc_and_owner_require_existing_paths() {
+        let (dir, mut conn) = setup();
+        let root = dir.path();
+        std::fs::create_dir(root.join("album")).unwrap();
+        std::fs::write(root.join("album/x.jpg"), b"x").unwrap();
+        let cache = CacheMap::new();
+
+        let set_desc = |path: &str| BatchOp::SetDesc {
+            path: path.into(),
+            desc: "desc".into(),
+        };
+        let set_owner = |path: &str| BatchOp::SetOwner {
+            path: path.into(),
+            user_id: 1,
+        };
+        assert!(run(set_desc("missing.jpg"), root, &cache, &mut conn).is_err());
+        assert!(run(set_owner("missing"), root, &cache, &mut conn).is_err());
+        assert!(run(set_owner("album/x.jpg"), root, &cache, &mut conn).is_err());
+        assert!(run(set_desc("album/x.jpg"), root, &cache, &mut conn).is_ok());
+        assert!(run(set_owner("album"), root, &cache, &mut conn).is_ok());
+
+        assert_eq!(paths(&conn, "file"), ["album/x.jpg"]);
+        assert_eq!(paths(&conn, "album"), ["album"]);
+        let modified: f64 = conn
+            .query_row("SELECT modified FROM file", [], |row| row.get(0))
+            .unwrap();
+        assert!(0. < modified);
+    }
+
+    #[test]
+    fn set_desc_adds_album_row() {
+        let (dir, mut conn) = setup();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("owned/sub")).unwrap();
+        std::fs::create_dir(root.join("top")).unwrap();
+        let mut cache = CacheMap::new();
+        cache.insert("owned".into(), CacheEntry::album_with_owner(2));
+        let mut thumbnails = ThumbnailCache::new(1024);
+
+        for path in ["owned/sub", "top", "owned"] {
+            let op = BatchOp::SetDesc {
+                path: path.into(),
+                desc: format!("{path} desc"),
+            };
+            let update = run(op, root, &cache, &mut conn).unwrap();
+            apply_cache_update(&mut cache, &mut thumbnails, update);
+        }
+
+        assert!(paths(&conn, "file").is_empty());
+        let mut stmt = conn
+            .prepare("SELECT path, desc, owner FROM album ORDER BY path")
+            .unwrap();
+        let rows: Vec<(String, String, usize)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                ("owned".into(), "owned desc".into(), 2),
+                ("owned/sub".into(), "owned/sub desc".into(), 2),
+                ("top".into(), "top desc".into(), ADMIN_USER_ID),
+            ]
+        );
+        let entry = &cache[Path::new("owned/sub")];
+        assert_eq!(entry.owner(), Some(2));
+        assert_eq!(entry.desc.as_deref(), Some("owned/sub desc"));
+        assert_eq!(cache[Path::new("owned")].owner(), Some(2));
+    }
+
+    #[test]
+    fn uncommitted_file_changes_are_reported() {
+        let mut cache = CacheMap::new();
+        cache.insert("a".into(), CacheEntry::album_with_owner(1));
+        cache.insert("a/x.jpg".into(), file_entry());
+        cache.insert("y.jpg".into(), file_entry());
+        cache.insert("z.jpg".into(), file_entry());
+        let mut thumbnails = ThumbnailCache::new(1024);
+        thumbnails.insert("a/x.jpg".into(), Bytes::from_static(b"thumb"));
+        let updates = [
+            CacheUpdate::Move {
+                from: "a".into(),
+                to: "b/a".into(),
+            },
+            CacheUpdate::Remove("y.jpg".into()),
+            CacheUpdate::SetDesc {
+                path: "z.jpg".into(),
+                desc: "desc".into(),
+                album_owner: None,
+            },
+        ];
+
+        let err = report_uncommitted(
+            &mut cache,
+            &mut thumbnails,
+            &updates,
+            rusqlite::Error::InvalidQuery,
+        )
+        .to_string();
+        assert!(err.contains(r#"moved "a" to "b/a""#), "{err}");
+        assert!(err.contains(r#"deleted "y.jpg""#), "{err}");
+        assert!(!err.contains("z.jpg"), "{err}");
+        assert_eq!(cache.keys().collect::<Vec<_>>(), [Path::new("z.jpg")]);
+        assert!(thumbnails.get(&"a/x.jpg".into()).is_none());
+
+        let err = report_uncommitted(
+            &mut cache,
+            &mut thumbnails,
+            &updates[2..],
+            rusqlite::Error::InvalidQuery,
+        )
+        .to_string();
+        assert!(!err.contains("database does not record"), "{err}");
+    }
+
+    #[test]
+    fn authorize_rejects_parent_paths() {
+        let mut session = Session::new();
+        session.is_admin = true;
+        let cache = CacheMap::new();
+        let access = AccessControl::default();
+        let ops = [
+            BatchOp::SetDesc {
+                path: "../x.jpg".into(),
+                desc: String::new(),
+            },
+            BatchOp::SetOwner {
+                path: "a/../..".into(),
+                user_id: 1,
+            },
+            BatchOp::Move {
+                path: "x.jpg".into(),
+                dest: "..".into(),
+            },
+        ];
+        for op in &ops {
+            assert!(authorize_op(op, Some(&session), &cache, &access).is_err());
+        }
+        let op = BatchOp::SetOwner {
+            path: "a".into(),
+            user_id: 1,
+        };
+        assert!(authorize_op(&op, Some(&session), &cache, &access).is_ok());
+        assert!(authorize_op(&op, None, &cache, &access).is_err());
+    }
+}
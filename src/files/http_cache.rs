@@ -0,0 +1,90 @@
+//! HTTP caching headers and conditional requests.
+//!
+//! The responses depend on the session, so they are all `private`. Thumbnails and file lists are
+//! revalidated on every use with their ETags, which is cheap with 304 responses. A thumbnail
+//! requested with the version in the file list (`?v=`) never changes, so it is cached as
+//! `immutable`.
+
+use std::time::SystemTime;
+
+use actix_web::{
+    http::header::{
+        CacheControl, CacheDirective, ETag, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch,
+        LastModified,
+    },
+    HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+
+const IMMUTABLE_MAX_AGE: u32 = 365 * 24 * 3600;
+
+pub(super) enum CachePolicy {
+    /// The client shall ask the server whether its copy is still fresh on every use.
+    Revalidate,
+    /// The URL identifies the exact content, so the client can keep it forever.
+    Immutable,
+}
+
+/// The validators of a response
+pub(super) struct Validators {
+    pub etag: EntityTag,
+    pub last_modified: Option<SystemTime>,
+}
+
+/// A version string of the thumbnail of a file: the bits of the `f64` modified date in hex, which
+/// change whenever the file is modified. It is opaque to clients, not a date.
+pub(crate) fn thumb_version(modified: f64) -> String {
+    format!("{:x}", modified.to_bits())
+}
+
+/// Returns true if the client already has the representation with the validators. If-None-Match
+/// takes precedence over If-Modified-Since as RFC 9110 says.
+fn is_fresh(req: &HttpRequest, validators: &Validators) -> bool {
+    if let Some(if_none_match) = req.get_header::<IfNoneMatch>() {
+        return match if_none_match {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(items) => items.iter().any(|etag| etag.weak_eq(&validators.etag)),
+        };
+    }
+    match (
+        req.get_header::<IfModifiedSince>(),
+        validators.last_modified,
+    ) {
+        (Some(IfModifiedSince(since)), Some(last_modified)) => {
+            // HTTP dates have a precision of a second.
+            HttpDate::from(last_modified) <= since
+        }
+        _ => false,
+    }
+}
+
+/// Start a response with the caching headers, or return 304 Not Modified if the client already
+/// has it.
+pub(super) fn cached_response(
+    req: &HttpRequest,
+    validators: &Validators,
+    policy: CachePolicy,
+) -> Result<HttpResponseBuilder, Box<HttpResponse>> {
+    let fresh = is_fresh(req, validators);
+    let mut builder = if fresh {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    builder.insert_header(ETag(validators.etag.clone()));
+    if let Some(last_modified) = validators.last_modified {
+        builder.insert_header(LastModified(last_modified.into()));
+    }
+    builder.insert_header(CacheControl(match policy {
+        CachePolicy::Revalidate => vec![CacheDirective::Private, CacheDirective::NoCache],
+        CachePolicy::Immutable => vec![
+            CacheDirective::Private,
+            CacheDirective::MaxAge(IMMUTABLE_MAX_AGE),
+            CacheDirective::Extension("immutable".to_string(), None),
+        ],
+    }));
+    if fresh {
+        Err(Box::new(builder.finish()))
+    } else {
+        Ok(builder)
+    }
+}
@@ -1,10 +1,12 @@
 use super::{
     auth::{authorized_path, validate_path, CheckAuth},
+    http_cache::{cached_response, thumb_version, CachePolicy, Validators},
+    is_image,
+    xmp::{read_caption, write_caption},
     THUMBNAIL_SIZE,
 };
 use crate::{
     cache::{CacheEntry, CachePayload, FilePayload},
-    files::load_cache::load_cache_single,
     map_err,
     session::find_session,
     MyData,
@@ -12,11 +14,12 @@ use crate::{
 use actix_files::NamedFile;
 use actix_web::{
     error,
-    http::header::LastModified,
+    http::header::EntityTag,
     web::{self, Bytes},
     HttpRequest, HttpResponse, Result,
 };
 use image::{io::Reader as ImageReader, ImageOutputFormat};
+use serde::Deserialize;
 
 use std::{
     fs,
@@ -33,10 +36,11 @@ pub(crate) async fn get_file(
 ) -> Result<NamedFile> {
     let sessions = data.sessions.read().unwrap();
     let session = find_session(&req, &sessions);
-    let root_dir = data.path.lock().map_err(map_err)?;
+    let root_dir = &data.path;
     let abs_path = root_dir.join(&*path);
-    let cache = data.cache.lock().unwrap();
-    authorized_path(&path, session, &cache, CheckAuth::Read)?;
+    let cache = data.cache.read().unwrap();
+    let access = data.access.read().unwrap();
+    authorized_path(&path, session, &cache, &access, CheckAuth::Read)?;
     println!("Opening {:?}", abs_path);
     Ok(NamedFile::open(abs_path)?)
 }
@@ -50,12 +54,14 @@ pub(crate) async fn delete_file(
     validate_path(&*path)?;
     let sessions = data.sessions.read().unwrap();
     let session = find_session(&req, &sessions);
-    let root_dir = data.path.lock().map_err(map_err)?;
+    let root_dir = &data.path;
     let abs_path = root_dir.join(&*path);
-    let cache = data.cache.lock().unwrap();
-    authorized_path(&path, session, &cache, CheckAuth::Ownership)?;
+    let cache = data.cache.read().unwrap();
+    let access = data.access.read().unwrap();
+    authorized_path(&path, session, &cache, &access, CheckAuth::Edit)?;
     println!("Deleting {:?}", abs_path);
     std::fs::remove_file(&*abs_path)?;
+    data.thumbnails.lock().unwrap().remove(&path);
     Ok(HttpResponse::Ok().content_type("text/plain").body("Ok"))
 }
 
@@ -69,7 +75,7 @@ pub(crate) async fn move_file(
     validate_path(&*path)?;
     let sessions = data.sessions.read().unwrap();
     let session = find_session(&req, &sessions);
-    let root_dir = data.path.lock().map_err(map_err)?;
+    let root_dir = &data.path;
     let abs_path = root_dir.join(&*path);
     let dest = Path::new(&dest);
     let dest_path = path
@@ -78,18 +84,20 @@ pub(crate) async fn move_file(
         .unwrap_or_else(|| PathBuf::from(dest));
     let dest_abs_path = root_dir.join(&dest_path);
     validate_path(&dest_abs_path)?;
-    let mut cache = data.cache.lock().unwrap();
-    authorized_path(&path, session, &cache, CheckAuth::Ownership)?;
-    authorized_path(&dest, session, &cache, CheckAuth::Ownership)?;
+    let mut cache = data.cache.write().unwrap();
+    let access = data.access.read().unwrap();
+    authorized_path(&path, session, &cache, &access, CheckAuth::Edit)?;
+    authorized_path(dest, session, &cache, &access, CheckAuth::Upload)?;
     println!("Moving {path:?} to {dest_path:?}");
 
     std::fs::rename(&*abs_path, &dest_abs_path)?;
+    data.thumbnails.lock().unwrap().remove(&path);
     let entry = cache.remove(&*path);
     if let Some(entry) = entry {
         println!("Found an entry to move for {:?}", &*path);
         let payload = entry.payload.clone();
         if let CachePayload::File(payload) = payload {
-            let mut db = data.conn.lock().unwrap();
+            let mut db = data.conn()?;
             let tx = db.transaction().map_err(map_err)?;
             tx.execute(
                 "DELETE FROM file WHERE path = ?1",
@@ -109,30 +117,32 @@ pub(crate) async fn move_file(
     Ok(HttpResponse::Ok().content_type("text/plain").body("Ok"))
 }
 
+#[derive(Deserialize)]
+pub(crate) struct ThumbQuery {
+    /// The version of the thumbnail in the file list. The response is immutable if it is given
+    /// and matches.
+    v: Option<String>,
+}
+
 #[actix_web::get("/thumbs/{path:.*}")]
 pub(crate) async fn get_file_thumb(
     data: web::Data<MyData>,
     path: web::Path<PathBuf>,
+    query: web::Query<ThumbQuery>,
     req: HttpRequest,
 ) -> Result<HttpResponse> {
-    let result = |out, modified| {
-        let mut builder = HttpResponse::Ok();
-        builder.content_type("image/jpg");
-        if let Some(modified) = unix_to_system_time(modified) {
-            builder.insert_header(LastModified(modified.into()));
-        }
-        Ok(builder.body(out))
-    };
-
     let abs_path;
+    let cached;
+    let mut builder;
     {
         static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
         let sessions = data.sessions.read().map_err(map_err)?;
         let session = find_session(&req, &sessions);
-        let root_dir = data.path.lock().map_err(map_err)?;
+        let root_dir = &data.path;
         abs_path = root_dir.join(&*path);
-        let cache = data.cache.lock().map_err(map_err)?;
-        authorized_path(&path, session, &cache, CheckAuth::Read)?;
+        let cache = data.cache.read().unwrap();
+        let access = data.access.read().unwrap();
+        authorized_path(&path, session, &cache, &access, CheckAuth::Read)?;
         let start = START.get_or_init(|| std::time::Instant::now());
         println!(
             "[{:?}] [{:?}] Opening {:?}",
@@ -141,57 +151,137 @@ pub(crate) async fn get_file_thumb(
             path
         );
 
-        if let Some(entry) = cache.get(&*path) {
+        let file_modified = get_file_modified(&abs_path).ok();
+        builder = match file_modified {
+            Some(modified) => {
+                let version = thumb_version(modified);
+                let policy = if query.v.as_ref() == Some(&version) {
+                    CachePolicy::Immutable
+                } else {
+                    CachePolicy::Revalidate
+                };
+                let validators = Validators {
+                    etag: EntityTag::new_strong(version),
+                    last_modified: unix_to_system_time(modified),
+                };
+                match cached_response(&req, &validators, policy) {
+                    Ok(builder) => builder,
+                    Err(not_modified) => return Ok(*not_modified),
+                }
+            }
+            None => HttpResponse::Ok(),
+        };
+        builder.content_type("image/jpg");
+
+        cached = match cache.get(&*path) {
             // Defaults true because some filesystems do not support file modified dates. I don't know such a
             // filesystem, but Rust documentation says so.
-            if get_file_modified(&abs_path)
-                .map(|date| date <= entry.modified)
-                .unwrap_or(true)
+            Some(entry)
+                if file_modified
+                    .map(|date| date <= entry.modified)
+                    .unwrap_or(true) =>
             {
                 if let CachePayload::File(payload) = &entry.payload {
-                    let data =
-                        load_cache_single(&data.conn.lock().unwrap(), &path).map_err(map_err)?;
-                    if !data.is_empty() {
-                        return result(data, entry.modified);
-                    }
+                    // A thumbnail made after the last write_db is only on memory.
                     if !payload.data.is_empty() {
-                        return result(payload.data.clone(), entry.modified);
+                        return Ok(builder.body(payload.data.clone()));
                     }
+                    true
                 } else {
                     return Err(error::ErrorInternalServerError(
                         "Album does not have thumbnail",
                     ));
                 }
-            } else {
-                println!("Found thumbnail cache in db, but it is older than the file")
             }
+            Some(_) => {
+                println!("Found thumbnail cache in db, but it is older than the file");
+                false
+            }
+            None => false,
+        };
+        // Drop all locks here before reading the DB or entering CPU intense processing
+    }
+
+    if cached {
+        if let Some(out) = data.thumbnails.lock().unwrap().get(&path) {
+            return Ok(builder.body(out));
+        }
+        let out = data
+            .thumb_store
+            .load(&*data.conn()?, &path)
+            .map_err(map_err)?;
+        if let Some(out) = out {
+            let out = Bytes::from(out);
+            data.thumbnails
+                .lock()
+                .unwrap()
+                .insert(path.into_inner(), out.clone());
+            return Ok(builder.body(out));
         }
-        // Drop all mutex locks here before entering CPU intense processing
     }
 
-    let img = ImageReader::open(&abs_path)?
-        .decode()
-        .map_err(|err| error::ErrorInternalServerError(err.to_string()))?;
-    let thumbnail = img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
-    let mut out = vec![];
-    thumbnail
-        .write_to(&mut Cursor::new(&mut out), ImageOutputFormat::Jpeg(85))
-        .map_err(|err| error::ErrorInternalServerError(err.to_string()))?;
+    let out = data.thumbnailer.make(&abs_path).await?;
 
     let modified = get_file_modified(&abs_path).unwrap_or(0.);
+    let desc = new_image_desc(&data, &path, &abs_path);
 
-    let mut cache = data.cache.lock().map_err(map_err)?;
-    cache.insert(
-        path.into_inner(),
-        CacheEntry {
-            new: true,
-            modified,
-            desc: None,
-            payload: CachePayload::File(FilePayload { data: out.clone() }),
-        },
-    );
+    let mut cache = data.cache.write().unwrap();
+    let entry = cache.entry(path.clone()).or_insert_with(|| CacheEntry {
+        new: true,
+        modified,
+        desc,
+        payload: CachePayload::File(FilePayload { data: vec![] }),
+    });
+    // Keep the data until write_db saves it to the DB.
+    entry.new = true;
+    entry.modified = modified;
+    entry.payload = CachePayload::File(FilePayload { data: out.to_vec() });
+    drop(cache);
+
+    let out = Bytes::from(out.to_vec());
+    data.thumbnails
+        .lock()
+        .unwrap()
+        .insert(path.into_inner(), out.clone());
+
+    Ok(builder.body(out))
+}
+
+/// Decode an image file and encode its thumbnail in JPEG. It is CPU intensive; do not call it on
+/// the async executor or while holding any locks.
+pub(crate) fn make_thumbnail(abs_path: &Path) -> anyhow::Result<Vec<u8>> {
+    let img = ImageReader::open(abs_path)?.decode()?;
+    let thumbnail = img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    let mut out = vec![];
+    thumbnail.write_to(&mut Cursor::new(&mut out), ImageOutputFormat::Jpeg(85))?;
+    Ok(out)
+}
 
-    result(out, modified)
+/// The description embedded in an image that is not in the cache yet, if `embed_descriptions` is
+/// enabled. The descriptions of the images in the cache are already in the DB.
+pub(crate) fn new_image_desc(data: &MyData, path: &Path, abs_path: &Path) -> Option<String> {
+    if !data.embed_descriptions || data.cache.read().unwrap().contains_key(path) {
+        return None;
+    }
+    match read_caption(abs_path) {
+        Ok(desc) => desc,
+        Err(e) => {
+            println!("Failed to read the description embedded in {abs_path:?}: {e}");
+            None
+        }
+    }
+}
+
+/// Write the description into the image file as well, if `embed_descriptions` is enabled.
+/// Failures are only logged, since the description is already saved in the DB.
+pub(crate) fn embed_desc(data: &MyData, path: &Path, desc: &str) {
+    let abs_path = data.path.join(path);
+    if !data.embed_descriptions || !is_image(&abs_path) {
+        return;
+    }
+    if let Err(e) = write_caption(&abs_path, desc) {
+        println!("Failed to embed the description in {abs_path:?}: {e}");
+    }
 }
 
 /// Return modified date in days since Unix epoch
@@ -215,8 +305,9 @@ pub(crate) async fn get_image_desc(
 ) -> Result<HttpResponse> {
     let sessions = data.sessions.read().unwrap();
     let session = find_session(&req, &sessions);
-    let cache = data.cache.lock().map_err(map_err)?;
-    authorized_path(&path, session, &cache, CheckAuth::Read)?;
+    let cache = data.cache.read().unwrap();
+    let access = data.access.read().unwrap();
+    authorized_path(&path, session, &cache, &access, CheckAuth::Read)?;
     let Some(entry) = cache.get(&*path) else {
         return Err(error::ErrorNotFound("Entry not found"));
     };
@@ -240,8 +331,9 @@ pub(crate) async fn set_image_desc(
     let session = find_session(&req, &sessions);
     let desc = std::str::from_utf8(&bytes).unwrap();
 
-    let mut cache = data.cache.lock().map_err(map_err)?;
-    authorized_path(&path, session, &cache, CheckAuth::Ownership)?;
+    let mut cache = data.cache.write().unwrap();
+    let access = data.access.read().unwrap();
+    authorized_path(&path, session, &cache, &access, CheckAuth::Edit)?;
 
     println!("Description updated on {path:?}: {desc}");
 
@@ -257,7 +349,7 @@ pub(crate) async fn set_image_desc(
     });
     entry.desc = Some(desc.to_string());
 
-    let mut db = data.conn.lock().unwrap();
+    let mut db = data.conn()?;
 
     let tx = db.transaction().map_err(map_err)?;
 
@@ -279,6 +371,12 @@ pub(crate) async fn set_image_desc(
 
     println!("inserted: {inserted}, updated: {updated}");
 
+    // Do not hold the locks while rewriting the file.
+    drop(access);
+    drop(cache);
+    drop(sessions);
+    embed_desc(&data, &path, desc);
+
     Ok(HttpResponse::Ok().content_type("text/plain").body("ok"))
 }
 
@@ -292,10 +390,11 @@ pub(crate) async fn upload(
     validate_path(&*path)?;
     let sessions = data.sessions.read().unwrap();
     let session = find_session(&req, &sessions);
-    let root_dir = data.path.lock().map_err(map_err)?;
+    let root_dir = &data.path;
     let abs_path = root_dir.join(&*path);
-    let cache = data.cache.lock().unwrap();
-    authorized_path(&path, session, &cache, CheckAuth::Ownership)?;
+    let cache = data.cache.read().unwrap();
+    let access = data.access.read().unwrap();
+    authorized_path(&path, session, &cache, &access, CheckAuth::Upload)?;
     println!("Uploading {:?}", abs_path);
     std::fs::write(abs_path, bytes)?;
     Ok(HttpResponse::Ok().content_type("text/plain").body("ok"))
@@ -55,9 +55,10 @@ pub(crate) fn load_cache(
         desc: Option<String>,
         password: String,
         owner: usize,
+        public: bool,
     }
 
-    let mut stmt = conn.prepare("SELECT path, desc, password, owner FROM album")?;
+    let mut stmt = conn.prepare("SELECT path, desc, password, owner, public FROM album")?;
     let album_iter = stmt.query_map([], |row| {
         Ok(Album {
             path: row.get(0)?,
@@ -65,6 +66,7 @@ pub(crate) fn load_cache(
             desc: row.get(1).ok(),
             password: row.get(2)?,
             owner: row.get(3)?,
+            public: row.get(4)?,
         })
     })?;
 
@@ -79,6 +81,7 @@ pub(crate) fn load_cache(
                 payload: CachePayload::Album(AlbumPayload {
                     password_hash: album.password,
                     owner: album.owner,
+                    public: album.public,
                 }),
             },
         );
@@ -90,12 +93,3 @@ pub(crate) fn load_cache(
     );
     Ok(())
 }
-
-pub(crate) fn load_cache_single(conn: &Connection, path: &Path) -> anyhow::Result<Vec<u8>> {
-    let mut stmt = conn.prepare("SELECT data FROM file WHERE path = ?1")?;
-    let mut rows = stmt.query([path.to_str().unwrap()])?;
-    Ok(rows
-        .next()?
-        .ok_or_else(|| anyhow::anyhow!("Specified path entry was not found on the db"))?
-        .get(0)?)
-}
@@ -6,9 +6,18 @@ use std::{
 
 use serde::Serialize;
 
-use crate::{cache::CacheMap, session::Session};
+use crate::{
+    access::{AccessControl, Role},
+    cache::CacheMap,
+    session::Session,
+};
 
-use super::{auth::authorized_path, authorized, has_extension_segments, CheckAuth};
+use super::{
+    auth::{authorized_path, effective_role},
+    get_file_modified, has_extension_segments,
+    http_cache::thumb_version,
+    is_hidden, CheckAuth,
+};
 
 #[derive(Serialize)]
 pub(super) struct ScanDirResult {
@@ -16,12 +25,16 @@ pub(super) struct ScanDirResult {
     dirs: Vec<Dir>,
     has_any_video: bool,
     owned: bool,
+    /// The role of the session user on this directory
+    role: Option<Role>,
 }
 
 #[derive(Serialize)]
 struct Dir {
     path: String,
     image_first: Option<String>,
+    /// The version of the thumbnail of `image_first`
+    image_first_version: Option<String>,
     file_count: usize,
     locked: bool,
 }
@@ -32,11 +45,14 @@ struct File {
     basename: String,
     label: String,
     video: bool,
+    /// The version of the thumbnail, to be given as `?v=` to `/thumbs/`
+    version: Option<String>,
 }
 
 pub(super) fn scan_dir(
     root_path: &Path,
     cache: &CacheMap,
+    access: &AccessControl,
     path: &Path,
     session: Option<&Session>,
 ) -> std::io::Result<ScanDirResult> {
@@ -52,17 +68,23 @@ pub(super) fn scan_dir(
         let Some(file_name) = path.file_name().and_then(|s| s.to_str()) else {
             continue;
         };
+        if is_hidden(&path) {
+            continue;
+        }
         let Ok(rel_path) = path.strip_prefix(root_path) else {
             continue;
         };
         if path.is_dir() {
-            let locked = cache
-                .get(rel_path)
-                .map(|entry| authorized(&rel_path, entry, session, CheckAuth::Read).is_err())
-                .unwrap_or(false);
+            let locked =
+                authorized_path(rel_path, session, cache, access, CheckAuth::Read).is_err();
+            let image_first = image_first(&path);
             dirs.push(Dir {
                 path: String::from(file_name),
-                image_first: image_first(&path).and_then(|image_path| {
+                image_first_version: image_first
+                    .as_deref()
+                    .and_then(|image_path| get_file_modified(image_path).ok())
+                    .map(thumb_version),
+                image_first: image_first.and_then(|image_path| {
                     image_path
                         .file_name()?
                         .to_str()
@@ -99,20 +121,23 @@ pub(super) fn scan_dir(
                     .to_string(),
                 label: String::from(file_name),
                 video,
+                version: get_file_modified(&path).ok().map(thumb_version),
             });
         }
     }
 
-    let owned = path
-        .strip_prefix(root_path)
-        .map(|path| authorized_path(&path, session, cache, CheckAuth::Ownership).is_ok())
+    let rel_path = path.strip_prefix(root_path).ok();
+    let owned = rel_path
+        .map(|path| authorized_path(path, session, cache, access, CheckAuth::Ownership).is_ok())
         .unwrap_or(false);
+    let role = rel_path.and_then(|path| effective_role(path, session, cache, access));
 
     Ok(ScanDirResult {
         dirs,
         files,
         has_any_video,
         owned,
+        role,
     })
 }
 
@@ -0,0 +1,91 @@
+//! Making thumbnails on demand off the async executor.
+//!
+//! Decoding an image takes hundreds of milliseconds, so it runs on the blocking thread pool with a
+//! limit on the number of concurrent jobs. Concurrent requests for the same file share a single
+//! job instead of decoding it twice.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use actix_web::{error, web};
+use tokio::sync::{OnceCell, Semaphore};
+
+use super::images::make_thumbnail;
+use crate::map_err;
+
+type ThumbnailResult = Result<Arc<Vec<u8>>, Arc<anyhow::Error>>;
+
+pub(crate) struct Thumbnailer {
+    permits: Semaphore,
+    /// Jobs in progress by the absolute path of the image
+    in_flight: Mutex<HashMap<PathBuf, Arc<OnceCell<ThumbnailResult>>>>,
+}
+
+/// Resolve the number of worker threads, where 0 means the number of available CPUs.
+pub(crate) fn worker_count(workers: usize) -> usize {
+    if workers == 0 {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    } else {
+        workers
+    }
+}
+
+impl Thumbnailer {
+    pub(crate) fn new(workers: usize) -> Self {
+        Self {
+            permits: Semaphore::new(worker_count(workers)),
+            in_flight: Mutex::default(),
+        }
+    }
+
+    /// Make a thumbnail of the image, or wait for the job of another request for the same image.
+    pub(crate) async fn make(&self, abs_path: &Path) -> actix_web::Result<Arc<Vec<u8>>> {
+        let cell = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(abs_path.to_owned())
+            .or_default()
+            .clone();
+
+        // If the request that started the job is cancelled, one of the waiters takes it over.
+        let res = cell
+            .get_or_init(|| async {
+                let _permit = self
+                    .permits
+                    .acquire()
+                    .await
+                    .expect("The semaphore is never closed");
+                let path = abs_path.to_owned();
+                match web::block(move || make_thumbnail(&path)).await {
+                    Ok(res) => res.map(Arc::new).map_err(Arc::new),
+                    Err(e) => Err(Arc::new(anyhow::anyhow!("{e}"))),
+                }
+            })
+            .await
+            .clone();
+
+        // Do not keep the result, since the file may be modified later.
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(abs_path)
+            .map(|c| Arc::ptr_eq(c, &cell))
+            .unwrap_or(false)
+        {
+            in_flight.remove(abs_path);
+        }
+
+        res.map_err(|e| match e.downcast_ref::<std::io::Error>() {
+            // A missing file should be 404 rather than 500.
+            Some(io_err) if io_err.kind() == std::io::ErrorKind::NotFound => {
+                error::ErrorNotFound(io_err.to_string())
+            }
+            _ => map_err(e),
+        })
+    }
+}
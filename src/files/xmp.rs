@@ -0,0 +1,832 @@
+//! Descriptions embedded in image files.
+//!
+//! The description is written to XMP `dc:description`, which desktop photo tools read. Reading also
+//! falls back to the IPTC caption of JPEG files, which older tools write. Writing removes the IPTC
+//! caption, so that XMP is the only place of the description and the tools that prefer IPTC do not
+//! show a stale one. The XMP packet is kept as is except for the description, so that other
+//! metadata in it survives.
+//!
+//! Only the small subset of JPEG, PNG and XMP needed for this is parsed here, without an XML
+//! parser; packets that do not look like the usual ones made by photo tools are left untouched.
+
+use std::{io::Write, ops::Range, path::Path};
+
+use anyhow::{anyhow, bail};
+
+const JPEG_SOI: &[u8] = &[0xff, 0xd8];
+const JPEG_APP1: u8 = 0xe1;
+const JPEG_APP13: u8 = 0xed;
+const JPEG_SOS: u8 = 0xda;
+const JPEG_EOI: u8 = 0xd9;
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// The continuation of an XMP packet too large for a segment, which is kept as is
+const JPEG_EXTENDED_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+const JPEG_PHOTOSHOP_HEADER: &[u8] = b"Photoshop 3.0\0";
+/// A segment length is 16 bits including the length field itself
+const JPEG_MAX_SEGMENT: usize = 0xffff - 2;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+
+const DC_NAMESPACE: &str = r#" xmlns:dc="http://purl.org/dc/elements/1.1/""#;
+
+enum Format {
+    Jpeg,
+    Png,
+}
+
+fn format_of(path: &Path) -> Option<Format> {
+    let ext = path.extension()?.to_ascii_lowercase();
+    if ext == "jpg" || ext == "jpeg" {
+        Some(Format::Jpeg)
+    } else if ext == "png" {
+        Some(Format::Png)
+    } else {
+        None
+    }
+}
+
+/// Read the description embedded in the image, if any.
+pub(crate) fn read_caption(path: &Path) -> anyhow::Result<Option<String>> {
+    let Some(format) = format_of(path) else {
+        return Ok(None);
+    };
+    let bytes = std::fs::read(path)?;
+    let caption = match format {
+        Format::Jpeg => {
+            let segments = jpeg_segments(&bytes)?;
+            let xmp = segments
+                .iter()
+                .find_map(|segment| jpeg_xmp(&bytes, segment))
+                .and_then(|xmp| xmp_description(&String::from_utf8_lossy(xmp)));
+            xmp.or_else(|| {
+                segments
+                    .iter()
+                    .find_map(|segment| jpeg_iptc_caption(&bytes, segment))
+            })
+        }
+        Format::Png => png_chunks(&bytes)?
+            .iter()
+            .find_map(|chunk| png_xmp(&bytes, chunk))
+            .and_then(|xmp| xmp_description(&String::from_utf8_lossy(xmp))),
+    };
+    Ok(caption.filter(|caption| !caption.is_empty()))
+}
+
+/// Write the description into the image. The file is overwritten in place rather than replaced, so
+/// that it keeps its permissions, owner and hard links. Its modified date is restored, since only
+/// the metadata changes and the thumbnail made from it stays valid.
+pub(crate) fn write_caption(path: &Path, desc: &str) -> anyhow::Result<()> {
+    let Some(format) = format_of(path) else {
+        bail!("Descriptions cannot be embedded in {path:?}");
+    };
+    let bytes = std::fs::read(path)?;
+    let out = match format {
+        Format::Jpeg => write_jpeg(&bytes, desc)?,
+        Format::Png => write_png(&bytes, desc)?,
+    };
+    let Some(out) = out else {
+        return Ok(());
+    };
+    let modified = std::fs::metadata(path)?.modified()?;
+    let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+    file.write_all(&out)?;
+    file.set_len(out.len() as u64)?;
+    file.set_modified(modified)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// A segment with the marker, the range of the whole segment and the range of its payload
+struct JpegSegment {
+    marker: u8,
+    range: Range<usize>,
+    payload: Range<usize>,
+}
+
+/// Parse the segments before the image data. The returned segments do not include SOI.
+fn jpeg_segments(bytes: &[u8]) -> anyhow::Result<Vec<JpegSegment>> {
+    if !bytes.starts_with(JPEG_SOI) {
+        bail!("Not a JPEG file");
+    }
+    let mut segments = vec![];
+    let mut pos = JPEG_SOI.len();
+    loop {
+        let start = pos;
+        if bytes.get(pos) != Some(&0xff) {
+            bail!("Broken JPEG segment at {pos}");
+        }
+        // Markers may be preceded by fill bytes.
+        while bytes.get(pos) == Some(&0xff) {
+            pos += 1;
+        }
+        let marker = *bytes.get(pos).ok_or_else(|| anyhow!("Truncated JPEG"))?;
+        pos += 1;
+        if marker == JPEG_SOS || marker == JPEG_EOI {
+            // The rest is the image data, which is kept as is.
+            segments.push(JpegSegment {
+                marker,
+                range: start..bytes.len(),
+                payload: pos..bytes.len(),
+            });
+            return Ok(segments);
+        }
+        if marker == 0x01 || (0xd0..=0xd7).contains(&marker) {
+            // Standalone markers without a length
+            segments.push(JpegSegment {
+                marker,
+                range: start..pos,
+                payload: pos..pos,
+            });
+            continue;
+        }
+        let len = bytes
+            .get(pos..pos + 2)
+            .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+            .ok_or_else(|| anyhow!("Truncated JPEG"))?;
+        if len < 2 || bytes.len() < pos + len {
+            bail!("Broken JPEG segment length at {pos}");
+        }
+        segments.push(JpegSegment {
+            marker,
+            range: start..pos + len,
+            payload: pos + 2..pos + len,
+        });
+        pos += len;
+    }
+}
+
+/// The main XMP packet of the segment, if it is one. Extended XMP segments are not.
+fn jpeg_xmp<'a>(bytes: &'a [u8], segment: &JpegSegment) -> Option<&'a [u8]> {
+    let payload = &bytes[segment.payload.clone()];
+    (segment.marker == JPEG_APP1)
+        .then(|| payload.strip_prefix(JPEG_XMP_HEADER))
+        .flatten()
+}
+
+fn is_jpeg_extended_xmp(bytes: &[u8], segment: &JpegSegment) -> bool {
+    segment.marker == JPEG_APP1
+        && bytes[segment.payload.clone()].starts_with(JPEG_EXTENDED_XMP_HEADER)
+}
+
+/// Find the caption (2:120) in the IPTC resource of the Photoshop segment.
+fn jpeg_iptc_caption(bytes: &[u8], segment: &JpegSegment) -> Option<String> {
+    if segment.marker != JPEG_APP13 {
+        return None;
+    }
+    let mut resources = bytes[segment.payload.clone()].strip_prefix(JPEG_PHOTOSHOP_HEADER)?;
+    while resources.len() >= 7 && resources.starts_with(b"8BIM") {
+        let id = u16::from_be_bytes([resources[4], resources[5]]);
+        // The name is a Pascal string padded to an even length.
+        let name_len = resources[6] as usize;
+        let mut pos = 6 + ((name_len + 2) & !1);
+        let size = resources
+            .get(pos..pos + 4)
+            .map(|size| u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize)?;
+        pos += 4;
+        let data = resources.get(pos..pos + size)?;
+        if id == 0x0404 {
+            return iptc_caption(data);
+        }
+        resources = resources.get(pos + ((size + 1) & !1)..)?;
+    }
+    None
+}
+
+/// The payload of the Photoshop segment without the IPTC caption, or `None` if it has none.
+fn strip_jpeg_iptc_caption(bytes: &[u8], segment: &JpegSegment) -> Option<Vec<u8>> {
+    if segment.marker != JPEG_APP13 {
+        return None;
+    }
+    let mut resources = bytes[segment.payload.clone()].strip_prefix(JPEG_PHOTOSHOP_HEADER)?;
+    let mut out = JPEG_PHOTOSHOP_HEADER.to_vec();
+    let mut stripped = false;
+    while resources.len() >= 7 && resources.starts_with(b"8BIM") {
+        let id = u16::from_be_bytes([resources[4], resources[5]]);
+        let name_len = resources[6] as usize;
+        let size_pos = 6 + ((name_len + 2) & !1);
+        let size = resources
+            .get(size_pos..size_pos + 4)
+            .map(|size| u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize)?;
+        let data_pos = size_pos + 4;
+        let data = resources.get(data_pos..data_pos + size)?;
+        // The padding of the last resource may be missing.
+        let end = (data_pos + ((size + 1) & !1)).min(resources.len());
+        match (id == 0x0404).then(|| strip_iptc_caption(data)).flatten() {
+            Some(datasets) => {
+                out.extend_from_slice(&resources[..size_pos]);
+                out.extend_from_slice(&(datasets.len() as u32).to_be_bytes());
+                out.extend_from_slice(&datasets);
+                if datasets.len() % 2 == 1 {
+                    out.push(0);
+                }
+                stripped = true;
+            }
+            None => out.extend_from_slice(&resources[..end]),
+        }
+        resources = &resources[end..];
+    }
+    out.extend_from_slice(resources);
+    stripped.then_some(out)
+}
+
+/// The IPTC datasets without the caption (2:120), or `None` if there is none or they cannot be
+/// parsed.
+fn strip_iptc_caption(mut datasets: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(datasets.len());
+    let mut stripped = false;
+    while datasets.len() >= 5 && datasets[0] == 0x1c {
+        let len = u16::from_be_bytes([datasets[3], datasets[4]]) as usize;
+        if len & 0x8000 != 0 || datasets.len() < 5 + len {
+            return None;
+        }
+        if (datasets[1], datasets[2]) == (2, 120) {
+            stripped = true;
+        } else {
+            out.extend_from_slice(&datasets[..5 + len]);
+        }
+        datasets = &datasets[5 + len..];
+    }
+    out.extend_from_slice(datasets);
+    stripped.then_some(out)
+}
+
+fn iptc_caption(mut datasets: &[u8]) -> Option<String> {
+    while datasets.len() >= 5 && datasets[0] == 0x1c {
+        let (record, dataset) = (datasets[1], datasets[2]);
+        let len = u16::from_be_bytes([datasets[3], datasets[4]]) as usize;
+        if len & 0x8000 != 0 {
+            // Extended datasets are not used for captions.
+            return None;
+        }
+        let data = datasets.get(5..5 + len)?;
+        if (record, dataset) == (2, 120) {
+            // IPTC is usually in UTF-8 today, but old files are in Latin-1.
+            return Some(match std::str::from_utf8(data) {
+                Ok(s) => s.to_string(),
+                Err(_) => data.iter().map(|&b| b as char).collect(),
+            });
+        }
+        datasets = &datasets[5 + len..];
+    }
+    None
+}
+
+/// Returns `None` if the file does not need to change.
+fn write_jpeg(bytes: &[u8], desc: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let segments = jpeg_segments(bytes)?;
+    let old_xmp = segments
+        .iter()
+        .find_map(|segment| jpeg_xmp(bytes, segment))
+        .map(|xmp| String::from_utf8_lossy(xmp).into_owned());
+    let xmp_payload = match set_xmp_description(old_xmp.as_deref(), desc) {
+        Some(xmp) => {
+            let mut payload = JPEG_XMP_HEADER.to_vec();
+            payload.extend_from_slice(xmp.as_bytes());
+            if JPEG_MAX_SEGMENT < payload.len() {
+                bail!("The XMP packet is too large to embed in a JPEG segment");
+            }
+            Some(payload)
+        }
+        None => None,
+    };
+    let iptc_payloads: Vec<_> = segments
+        .iter()
+        .map(|segment| strip_jpeg_iptc_caption(bytes, segment))
+        .collect();
+    if xmp_payload.is_none() && iptc_payloads.iter().all(Option::is_none) {
+        return Ok(None);
+    }
+
+    // XMP goes after JFIF (APP0) and Exif (APP1) segments at the beginning, and before the
+    // extended XMP segments. Only the main packet is replaced; the extended XMP segments stay, since
+    // the main packet refers to them by the GUID.
+    let insert_at = segments
+        .iter()
+        .position(|segment| {
+            !(segment.marker == 0xe0 || segment.marker == JPEG_APP1)
+                || jpeg_xmp(bytes, segment).is_some()
+                || is_jpeg_extended_xmp(bytes, segment)
+        })
+        .unwrap_or(segments.len());
+    let mut out = Vec::with_capacity(bytes.len() + xmp_payload.as_ref().map_or(0, Vec::len) + 4);
+    out.extend_from_slice(JPEG_SOI);
+    for ((i, segment), iptc_payload) in segments.iter().enumerate().zip(iptc_payloads) {
+        if let Some(payload) = &xmp_payload {
+            if i == insert_at {
+                write_jpeg_segment(&mut out, JPEG_APP1, payload);
+            }
+            if jpeg_xmp(bytes, segment).is_some() {
+                continue;
+            }
+        }
+        match iptc_payload {
+            Some(payload) => write_jpeg_segment(&mut out, segment.marker, &payload),
+            None => out.extend_from_slice(&bytes[segment.range.clone()]),
+        }
+    }
+    Ok(Some(out))
+}
+
+fn write_jpeg_segment(out: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+    out.extend_from_slice(&[0xff, marker]);
+    out.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// A chunk with its type and the ranges of the whole chunk and its data
+struct PngChunk {
+    kind: [u8; 4],
+    range: Range<usize>,
+    data: Range<usize>,
+}
+
+fn png_chunks(bytes: &[u8]) -> anyhow::Result<Vec<PngChunk>> {
+    if !bytes.starts_with(PNG_SIGNATURE) {
+        bail!("Not a PNG file");
+    }
+    let mut chunks = vec![];
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < bytes.len() {
+        let header = bytes
+            .get(pos..pos + 8)
+            .ok_or_else(|| anyhow!("Truncated PNG"))?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let end = pos + 12 + len;
+        if bytes.len() < end {
+            bail!("Truncated PNG chunk at {pos}");
+        }
+        chunks.push(PngChunk {
+            kind,
+            range: pos..end,
+            data: pos + 8..pos + 8 + len,
+        });
+        pos = end;
+    }
+    Ok(chunks)
+}
+
+/// The text of an uncompressed XMP iTXt chunk
+fn png_xmp<'a>(bytes: &'a [u8], chunk: &PngChunk) -> Option<&'a [u8]> {
+    if &chunk.kind != b"iTXt" {
+        return None;
+    }
+    let rest = bytes[chunk.data.clone()].strip_prefix(PNG_XMP_KEYWORD)?;
+    let (&compressed, rest) = rest.split_first()?;
+    if compressed != 0 {
+        return None;
+    }
+    // Skip the compression method, the language tag and the translated keyword.
+    let rest = rest.get(1..)?;
+    let rest = &rest[rest.iter().position(|&b| b == 0)? + 1..];
+    Some(&rest[rest.iter().position(|&b| b == 0)? + 1..])
+}
+
+fn is_png_xmp(bytes: &[u8], chunk: &PngChunk) -> bool {
+    &chunk.kind == b"iTXt" && bytes[chunk.data.clone()].starts_with(PNG_XMP_KEYWORD)
+}
+
+fn write_png(bytes: &[u8], desc: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let chunks = png_chunks(bytes)?;
+    let old_xmp = chunks
+        .iter()
+        .find_map(|chunk| png_xmp(bytes, chunk))
+        .map(|xmp| String::from_utf8_lossy(xmp).into_owned());
+    let Some(xmp) = set_xmp_description(old_xmp.as_deref(), desc) else {
+        return Ok(None);
+    };
+    let mut data = PNG_XMP_KEYWORD.to_vec();
+    // Uncompressed, no language tag and no translated keyword
+    data.extend_from_slice(&[0, 0, 0, 0]);
+    data.extend_from_slice(xmp.as_bytes());
+
+    let mut out = Vec::with_capacity(bytes.len() + data.len() + 12);
+    out.extend_from_slice(PNG_SIGNATURE);
+    let mut inserted = false;
+    for chunk in &chunks {
+        // XMP must come before the image data.
+        if !inserted && (&chunk.kind == b"IDAT" || &chunk.kind == b"IEND") {
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(b"iTXt");
+            hasher.update(&data);
+            out.extend_from_slice(b"iTXt");
+            out.extend_from_slice(&data);
+            out.extend_from_slice(&hasher.finalize().to_be_bytes());
+            inserted = true;
+        }
+        if !is_png_xmp(bytes, chunk) {
+            out.extend_from_slice(&bytes[chunk.range.clone()]);
+        }
+    }
+    if !inserted {
+        bail!("PNG without image data");
+    }
+    Ok(Some(out))
+}
+
+/// The `x-default` (or the first) item of `dc:description`
+fn xmp_description(xmp: &str) -> Option<String> {
+    let start = xmp.find("<dc:description")?;
+    let end = start + xmp[start..].find("</dc:description>")?;
+    let element = &xmp[start..end];
+    let item = element
+        .match_indices("<rdf:li")
+        .map(|(i, _)| &element[i..])
+        .find(|item| item[..item.find('>').unwrap_or(0)].contains(r#"xml:lang="x-default""#))
+        .or_else(|| element.find("<rdf:li").map(|i| &element[i..]))?;
+    let text = &item[item.find('>')? + 1..];
+    let text = &text[..text.find("</rdf:li>")?];
+    Some(unescape_xml(text))
+}
+
+/// Returns the XMP packet with the description replaced, or `None` if nothing changes.
+fn set_xmp_description(xmp: Option<&str>, desc: &str) -> Option<String> {
+    let element = if desc.is_empty() {
+        String::new()
+    } else {
+        format!(
+            r#"<dc:description><rdf:Alt><rdf:li xml:lang="x-default">{}</rdf:li></rdf:Alt></dc:description>"#,
+            escape_xml(desc)
+        )
+    };
+
+    if let Some(xmp) = xmp {
+        if let Some(start) = xmp.find("<dc:description") {
+            let closing = "</dc:description>";
+            let end = start + xmp[start..].find(closing)? + closing.len();
+            return Some(format!("{}{element}{}", &xmp[..start], &xmp[end..]));
+        }
+        if desc.is_empty() {
+            return None;
+        }
+        if let Some(start) = xmp.find("<rdf:Description") {
+            let tag_end = start + xmp[start..].find('>')?;
+            let self_closing = xmp[..tag_end].ends_with('/');
+            let attrs_end = if self_closing { tag_end - 1 } else { tag_end };
+            let namespace = if xmp[start..tag_end].contains("xmlns:dc=") {
+                ""
+            } else {
+                DC_NAMESPACE
+            };
+            let rest = &xmp[tag_end + 1..];
+            return Some(if self_closing {
+                format!(
+                    "{}{namespace}>{element}</rdf:Description>{rest}",
+                    &xmp[..attrs_end]
+                )
+            } else {
+                format!("{}{namespace}>{element}{rest}", &xmp[..attrs_end])
+            });
+        }
+    }
+    if desc.is_empty() {
+        return None;
+    }
+    Some(format!(
+        r#"<?xpacket begin="{}" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""{DC_NAMESPACE}>
+   {element}
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#,
+        '\u{feff}'
+    ))
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn unescape_xml(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, RgbImage};
+    use std::io::Cursor;
+
+    fn encode(format: ImageOutputFormat) -> Vec<u8> {
+        let mut out = vec![];
+        RgbImage::new(8, 8)
+            .write_to(&mut Cursor::new(&mut out), format)
+            .unwrap();
+        out
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xff, marker];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    /// Insert the segments right after SOI.
+    fn jpeg_with(segments: &[Vec<u8>]) -> Vec<u8> {
+        let jpeg = encode(ImageOutputFormat::Jpeg(90));
+        let mut out = JPEG_SOI.to_vec();
+        for segment in segments {
+            out.extend_from_slice(segment);
+        }
+        out.extend_from_slice(&jpeg[JPEG_SOI.len()..]);
+        out
+    }
+
+    fn write_file(dir: &tempfile::TempDir, name: &str, bytes: &[u8]) -> std::path::PathBuf {
+        let path = dir.path().join(name);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn jpeg_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_file(&dir, "a.jpg", &encode(ImageOutputFormat::Jpeg(90)));
+        assert_eq!(read_caption(&path).unwrap(), None);
+
+        write_caption(&path, "Sunset & <sea>").unwrap();
+        assert_eq!(
+            read_caption(&path).unwrap().as_deref(),
+            Some("Sunset & <sea>")
+        );
+        write_caption(&path, "Replaced").unwrap();
+        assert_eq!(read_caption(&path).unwrap().as_deref(), Some("Replaced"));
+        let bytes = std::fs::read(&path).unwrap();
+        let segments = jpeg_segments(&bytes).unwrap();
+        let xmp_count = segments
+            .iter()
+            .filter(|segment| jpeg_xmp(&bytes, segment).is_some())
+            .count();
+        assert_eq!(xmp_count, 1);
+        image::load_from_memory(&bytes).unwrap();
+
+        write_caption(&path, "").unwrap();
+        assert_eq!(read_caption(&path).unwrap(), None);
+        let leftovers = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(leftovers, 1);
+    }
+
+    #[test]
+    fn keep_file_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_file(&dir, "a.jpg", &encode(ImageOutputFormat::Jpeg(90)));
+        let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let link = dir.path().join("link.jpg");
+        std::fs::hard_link(&path, &link).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        }
+
+        write_caption(&path, "Kept").unwrap();
+        let meta = std::fs::metadata(&path).unwrap();
+        assert_eq!(meta.modified().unwrap(), modified);
+        assert_eq!(read_caption(&link).unwrap().as_deref(), Some("Kept"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{MetadataExt, PermissionsExt};
+            assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+            assert_eq!(meta.nlink(), 2);
+        }
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_file(&dir, "a.png", &encode(ImageOutputFormat::Png));
+        write_caption(&path, "Ünïcode \"quoted\"").unwrap();
+        assert_eq!(
+            read_caption(&path).unwrap().as_deref(),
+            Some("Ünïcode \"quoted\"")
+        );
+
+        let bytes = std::fs::read(&path).unwrap();
+        for chunk in png_chunks(&bytes).unwrap() {
+            let crc_pos = chunk.data.end;
+            let crc = u32::from_be_bytes(bytes[crc_pos..crc_pos + 4].try_into().unwrap());
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&chunk.kind);
+            hasher.update(&bytes[chunk.data.clone()]);
+            assert_eq!(crc, hasher.finalize());
+        }
+        let chunks = png_chunks(&bytes).unwrap();
+        let xmp_at = chunks.iter().position(|chunk| is_png_xmp(&bytes, chunk));
+        let idat_at = chunks.iter().position(|chunk| &chunk.kind == b"IDAT");
+        assert!(xmp_at < idat_at);
+        image::load_from_memory(&bytes).unwrap();
+    }
+
+    #[test]
+    fn keep_other_xmp_fields() {
+        let xmp = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:Rating="5"/></rdf:RDF></x:xmpmeta>"#;
+        let added = set_xmp_description(Some(xmp), "New").unwrap();
+        assert!(added.contains(r#"xmp:Rating="5""#));
+        assert!(added.contains(DC_NAMESPACE));
+        assert!(added.ends_with("</rdf:Description></rdf:RDF></x:xmpmeta>"));
+        assert_eq!(xmp_description(&added).as_deref(), Some("New"));
+
+        let xmp = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF><rdf:Description xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:creator><rdf:Seq><rdf:li>Me</rdf:li></rdf:Seq></dc:creator><dc:description><rdf:Alt><rdf:li xml:lang="ja">古い</rdf:li><rdf:li xml:lang="x-default">Old</rdf:li></rdf:Alt></dc:description></rdf:Description></rdf:RDF></x:xmpmeta>"#;
+        assert_eq!(xmp_description(xmp).as_deref(), Some("Old"));
+        let replaced = set_xmp_description(Some(xmp), "New").unwrap();
+        assert!(
+            replaced.contains("<dc:creator><rdf:Seq><rdf:li>Me</rdf:li></rdf:Seq></dc:creator>")
+        );
+        assert_eq!(replaced.matches("xmlns:dc=").count(), 1);
+        assert_eq!(xmp_description(&replaced).as_deref(), Some("New"));
+
+        let removed = set_xmp_description(Some(xmp), "").unwrap();
+        assert!(removed.contains("<dc:creator>"));
+        assert_eq!(xmp_description(&removed), None);
+        assert_eq!(set_xmp_description(None, ""), None);
+    }
+
+    #[test]
+    fn keep_extended_xmp() {
+        let main = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF><rdf:Description rdf:about="" xmlns:xmpNote="http://ns.adobe.com/xmp/note/" xmpNote:HasExtendedXMP="0123456789ABCDEF0123456789ABCDEF"/></rdf:RDF></x:xmpmeta>"#;
+        let mut main_payload = JPEG_XMP_HEADER.to_vec();
+        main_payload.extend_from_slice(main.as_bytes());
+        let mut extended_payload = JPEG_EXTENDED_XMP_HEADER.to_vec();
+        extended_payload.extend_from_slice(b"0123456789ABCDEF0123456789ABCDEF");
+        extended_payload.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 0]);
+        extended_payload.extend_from_slice(b"more");
+        let extended = jpeg_segment(JPEG_APP1, &extended_payload);
+        let bytes = jpeg_with(&[jpeg_segment(JPEG_APP1, &main_payload), extended.clone()]);
+
+        let out = write_jpeg(&bytes, "Caption").unwrap().unwrap();
+        let segments = jpeg_segments(&out).unwrap();
+        let xmp_at = segments
+            .iter()
+            .position(|segment| jpeg_xmp(&out, segment).is_some())
+            .unwrap();
+        let extended_at = segments
+            .iter()
+            .position(|segment| is_jpeg_extended_xmp(&out, segment))
+            .unwrap();
+        assert!(xmp_at < extended_at);
+        assert_eq!(&out[segments[extended_at].range.clone()], &extended[..]);
+        let xmp = String::from_utf8_lossy(jpeg_xmp(&out, &segments[xmp_at]).unwrap());
+        assert!(xmp.contains("HasExtendedXMP"));
+        assert_eq!(xmp_description(&xmp).as_deref(), Some("Caption"));
+    }
+
+    fn iptc_dataset(record: u8, dataset: u8, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x1c, record, dataset];
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(data);
+        out
+    }
+
+    #[test]
+    fn read_iptc_caption() {
+        let mut datasets = iptc_dataset(2, 25, b"keyword");
+        datasets.extend(iptc_dataset(2, 120, "Caption ü".as_bytes()));
+        assert_eq!(iptc_caption(&datasets).as_deref(), Some("Caption ü"));
+        // Latin-1
+        assert_eq!(
+            iptc_caption(&iptc_dataset(2, 120, b"caf\xe9")).as_deref(),
+            Some("café")
+        );
+        assert_eq!(iptc_caption(&iptc_dataset(2, 25, b"keyword")), None);
+        // Truncated
+        assert_eq!(iptc_caption(&datasets[..datasets.len() - 1]), None);
+
+        let mut payload = JPEG_PHOTOSHOP_HEADER.to_vec();
+        // A resource before the IPTC one, with an odd size to be padded
+        payload.extend_from_slice(b"8BIM\x03\xed\x00\x00\x00\x00\x00\x03abc\x00");
+        payload.extend_from_slice(b"8BIM\x04\x04\x00\x00");
+        payload.extend_from_slice(&(datasets.len() as u32).to_be_bytes());
+        payload.extend_from_slice(&datasets);
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_file(
+            &dir,
+            "a.jpg",
+            &jpeg_with(&[jpeg_segment(JPEG_APP13, &payload)]),
+        );
+        assert_eq!(read_caption(&path).unwrap().as_deref(), Some("Caption ü"));
+
+        // Writing removes the IPTC caption, and keeps the other resources and datasets.
+        write_caption(&path, "From XMP").unwrap();
+        assert_eq!(read_caption(&path).unwrap().as_deref(), Some("From XMP"));
+        let bytes = std::fs::read(&path).unwrap();
+        let segments = jpeg_segments(&bytes).unwrap();
+        let photoshop = segments
+            .iter()
+            .find(|segment| segment.marker == JPEG_APP13)
+            .unwrap();
+        assert_eq!(jpeg_iptc_caption(&bytes, photoshop), None);
+        let payload = &bytes[photoshop.payload.clone()];
+        assert!(payload
+            .windows(14)
+            .any(|window| window == b"8BIM\x03\xed\x00\x00\x00\x00\x00\x03ab"));
+        let keyword = iptc_dataset(2, 25, b"keyword");
+        let mut iptc = b"8BIM\x04\x04\x00\x00".to_vec();
+        iptc.extend_from_slice(&(keyword.len() as u32).to_be_bytes());
+        iptc.extend_from_slice(&keyword);
+        assert!(payload.ends_with(&iptc));
+        image::load_from_memory(&bytes).unwrap();
+        write_caption(&path, "").unwrap();
+        assert_eq!(read_caption(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn escape_round_trip() {
+        let text = r#"a & b < c > d " e ' f"#;
+        let escaped = escape_xml(text);
+        assert_eq!(escaped, "a &amp; b &lt; c &gt; d &quot; e &apos; f");
+        assert_eq!(unescape_xml(&escaped), text);
+        assert_eq!(unescape_xml("&#x41;&#66;&unknown;&"), "AB&unknown;&");
+        assert_eq!(unescape_xml("&amp;lt;"), "&lt;");
+    }
+
+    #[test]
+    fn reject_malformed_files() {
+        let jpeg = encode(ImageOutputFormat::Jpeg(90));
+        let png = encode(ImageOutputFormat::Png);
+        let mut bad_length = jpeg_with(&[jpeg_segment(JPEG_APP13, b"Photoshop 3.0\0")]);
+        // The length of the segment points past the end of the file
+        bad_length[4..6].copy_from_slice(&0xfff0u16.to_be_bytes());
+        let bad_length = bad_length[..100].to_vec();
+        let mut zero_length = jpeg_with(&[jpeg_segment(JPEG_APP1, b"")]);
+        zero_length[4..6].copy_from_slice(&[0, 0]);
+        let mut no_idat = PNG_SIGNATURE.to_vec();
+        no_idat.extend_from_slice(&png[8..33]);
+        let mut bad_chunk_length = png.clone();
+        bad_chunk_length[33..37].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        let cases: &[(&str, &[u8])] = &[
+            ("empty.jpg", b""),
+            ("soi_only.jpg", JPEG_SOI),
+            ("truncated_marker.jpg", &[0xff, 0xd8, 0xff]),
+            ("truncated_length.jpg", &[0xff, 0xd8, 0xff, 0xe1, 0x00]),
+            ("bad_length.jpg", &bad_length),
+            ("zero_length.jpg", &zero_length),
+            ("no_marker.jpg", &[0xff, 0xd8, 0x00, 0x00]),
+            ("not_jpeg.jpg", &png),
+            ("truncated_header.png", &png[..12]),
+            ("truncated_chunk.png", &png[..png.len() - 3]),
+            ("bad_chunk_length.png", &bad_chunk_length),
+            ("no_idat.png", &no_idat),
+            ("not_png.png", &jpeg),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        for (name, bytes) in cases {
+            let path = write_file(&dir, name, bytes);
+            // Reading may succeed on a file that only cannot be written, but must not panic.
+            let _ = read_caption(&path);
+            assert!(write_caption(&path, "desc").is_err(), "{name}");
+            assert_eq!(std::fs::read(&path).unwrap(), *bytes, "{name}");
+        }
+        // Only the files written above, and no temporary files
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), cases.len());
+    }
+}
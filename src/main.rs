@@ -1,84 +1,301 @@
+mod access;
+mod api_token;
+mod backup;
 mod cache;
+mod config;
+mod csrf;
 mod db_utils;
 mod files;
+mod maintenance;
+mod migration;
+mod password;
+mod pregenerate;
+mod rate_limit;
 mod session;
+mod sidecar;
+mod thumb_store;
+mod tls;
+mod totp;
+mod transcode;
 mod user;
 
 use crate::{
-    cache::{clear_cache, CacheMap},
-    db_utils::{init_db, periodic_cleanup, write_db},
+    access::{
+        add_group_member, create_group, delete_grant, delete_group, list_grants, list_groups,
+        remove_group_member, set_grant, AccessControl,
+    },
+    api_token::{create_token, list_tokens, revoke_token, ApiToken},
+    backup::create_backup,
+    cache::{clear_cache, CacheMap, ThumbnailCache},
+    config::Config,
+    csrf::Csrf,
+    db_utils::{init_db, periodic_cleanup, write_db, DbConnection, DbPool},
     files::{
-        code, delete_file, get_bundle_css, get_file, get_file_list, get_file_list_root,
-        get_file_thumb, get_global_css, get_image_desc, get_owner, index, move_file,
-        set_album_lock, set_image_desc, set_owner, upload,
+        code, delete_file, execute_batch, get_bundle_css, get_file, get_file_list,
+        get_file_list_root, get_file_thumb, get_global_css, get_image_desc, get_owner, index,
+        move_file, set_album_lock, set_album_public, set_image_desc, set_owner, upload,
+        Thumbnailer,
+    },
+    maintenance::{run_maintenance, MaintenanceCommand},
+    password::{issue_reset_token, reset_password, PasswordPolicy},
+    pregenerate::{
+        get_pregenerate_status, pregenerate_thumbnails, start_pregenerate, Pregenerator,
     },
+    rate_limit::{clear_lockout, clear_lockouts, list_lockouts, RateLimiter},
     session::{authorize_album, create_session, Sessions},
+    sidecar::{export_metadata, import_metadata},
+    thumb_store::{ThumbnailStore, ThumbnailStoreKind},
+    tls::{redirect_server, server_config, watch_certificate, CertResolver},
+    totp::{
+        confirm_totp, disable_totp, enroll_totp, get_totp_status, login_totp, reset_totp, TotpKey,
+    },
+    transcode::{start_rendition_gc, stream_video, Transcoder},
     user::{
-        create_user, delete_user, list_users, login_user, logout_user, set_user_password,
-        status_user,
+        create_user, delete_user, get_setup_status, init_admin_password, list_users, login_user,
+        logout_user, set_user_password, status_user, update_user,
     },
 };
 use actix_cors::Cors;
 use actix_web::{error, web, App, Error, HttpServer};
-use clap::Parser;
+use clap::{builder::BoolishValueParser, Parser, Subcommand};
 
-use rusqlite::Connection;
 use std::{
     path::PathBuf,
-    sync::{Mutex, RwLock},
+    sync::{Arc, Mutex, RwLock},
     time::Instant,
 };
 
-/// The global state of the server. Mutable shared states shall be wrapped in a mutex.
+/// The global state of the server. Mutable shared states shall be wrapped in a lock. Prefer
+/// `RwLock` for states that are mostly read, so that concurrent requests do not serialize.
 struct MyData {
     /// The root path of the photoalbum
-    path: Mutex<PathBuf>,
-    cache: Mutex<CacheMap>,
-    conn: Mutex<Connection>,
+    path: PathBuf,
+    cache: RwLock<CacheMap>,
+    /// Recently used thumbnail bytes
+    thumbnails: Mutex<ThumbnailCache>,
+    /// Persistent storage of thumbnails
+    thumb_store: Box<dyn ThumbnailStore>,
+    /// Groups and per-album grants
+    access: RwLock<AccessControl>,
+    pool: DbPool,
     // stats: Mutex<StatsBundle>,
     sessions: RwLock<Sessions>,
+    /// Counters of failed password attempts
+    rate_limiter: Mutex<RateLimiter>,
+    /// Whether the cookies are only sent over HTTPS
+    secure_cookies: bool,
+    /// On-demand thumbnail generation
+    thumbnailer: Thumbnailer,
+    /// Background thumbnail generation
+    pregenerator: Pregenerator,
+    /// Lower bitrate renditions of videos
+    transcoder: Transcoder,
+    /// Where `POST /admin/backup` writes the backups
+    backup_dir: PathBuf,
+    /// Whether descriptions are embedded in the image files
+    embed_descriptions: bool,
+    /// Requirements of user passwords
+    password_policy: PasswordPolicy,
+    /// Encrypts the TOTP secrets in the DB
+    totp_key: TotpKey,
 }
 
 #[derive(Parser, Debug)]
-#[clap(author, version, about)]
+#[clap(author, version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// Arguments for `serve` when no subcommand is given
+    #[clap(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the server. This is the default if no subcommand is given.
+    Serve(Box<Args>),
+    #[clap(flatten)]
+    Maintenance(MaintenanceCommand),
+}
+
+/// Command line arguments of the server. Options that are not given fall back to the environment
+/// variables, the config file and the defaults in [`Config`], in this order.
+#[derive(clap::Args, Debug, Default)]
 struct Args {
-    #[clap(default_value = ".")]
-    path: String,
+    #[clap(
+        env = "MASSPHOTO_PATH",
+        help = "The root directory of the photo albums. [default: .]"
+    )]
+    path: Option<String>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_CONFIG",
+        help = "The config file. [default: massphoto.toml in the album root, if exists]"
+    )]
+    config: Option<PathBuf>,
+    #[clap(long, help = "Print the effective configuration in TOML and exit.")]
+    print_config: bool,
     #[clap(
         short,
         long,
-        default_value = "8808",
-        help = "The port number to listen to."
+        env = "MASSPHOTO_PORT",
+        help = "The port number to listen to. [default: 8808]"
     )]
-    port: u16,
+    port: Option<u16>,
     #[clap(
         short,
         long,
-        default_value = "127.0.0.1",
-        help = "The host address to listen to. By default, only the localhost can access."
+        env = "MASSPHOTO_HOST",
+        help = "The host address to listen to. By default, only the localhost can access. [default: 127.0.0.1]"
     )]
-    host: String,
+    host: Option<String>,
     #[clap(
         short,
         long,
-        default_value = "http://localhost:8808",
-        help = "The allowed Access-Control-Allow-Origin value. Set \"*\" to allow any origin."
+        env = "MASSPHOTO_CORS_ORIGIN",
+        help = "The allowed Access-Control-Allow-Origin value. Set \"*\" to allow any origin. [default: http://localhost:8808]"
     )]
-    cors_origin: String,
+    cors_origin: Option<String>,
     #[clap(
         short = 'P',
         long,
-        default_value = "120",
-        help = "Interval to auto-cleanup cache memory, in seconds."
+        env = "MASSPHOTO_CLEANUP_PERIOD",
+        help = "Interval to auto-cleanup cache memory, in seconds. [default: 120]"
     )]
-    cleanup_period: u64,
+    cleanup_period: Option<u64>,
     #[clap(
         short = 'u',
         long,
-        default_value = "5000000",
-        help = "Upload file size limit, in bytes."
+        env = "MASSPHOTO_UPLOAD_LIMIT",
+        help = "Upload file size limit, in bytes. [default: 5000000]"
+    )]
+    upload_limit: Option<u64>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_TLS_CERT",
+        help = "The TLS certificate chain file in PEM. Serves HTTPS if given with --tls-key."
+    )]
+    tls_cert: Option<PathBuf>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_TLS_KEY",
+        help = "The TLS private key file in PEM."
+    )]
+    tls_key: Option<PathBuf>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_HTTP_REDIRECT_PORT",
+        help = "The port to listen to plain HTTP and redirect to HTTPS."
+    )]
+    http_redirect_port: Option<u16>,
+    #[clap(
+        long,
+        arg_enum,
+        env = "MASSPHOTO_THUMBNAIL_STORE",
+        help = "Where to store thumbnails. [default: sqlite]"
+    )]
+    thumbnail_store: Option<ThumbnailStoreKind>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_THUMBNAIL_DIR",
+        help = "The directory to store thumbnails in with --thumbnail-store disk. [default: .thumbnails in the album root]"
+    )]
+    thumbnail_dir: Option<PathBuf>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_THUMBNAIL_CACHE_MB",
+        help = "Memory budget for recently used thumbnails, in megabytes. 0 disables the cache. [default: 32]"
+    )]
+    thumbnail_cache_mb: Option<u64>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_THUMBNAIL_WORKERS",
+        help = "Maximum number of thumbnails made concurrently on demand. 0 means the number of CPUs. [default: 0]"
+    )]
+    thumbnail_workers: Option<usize>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_PREGENERATE",
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new(),
+        help = "Pre-generate missing or outdated thumbnails in the background on startup. [default: false]"
+    )]
+    pregenerate: Option<bool>,
+    #[clap(
+        long,
+        overrides_with = "pregenerate",
+        help = "Do not pre-generate thumbnails, even if the config file or the environment enables it."
+    )]
+    no_pregenerate: bool,
+    #[clap(
+        long,
+        env = "MASSPHOTO_PREGENERATE_WORKERS",
+        help = "Number of threads to pre-generate thumbnails. 0 means the number of CPUs. [default: 0]"
+    )]
+    pregenerate_workers: Option<usize>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_EMBED_DESCRIPTIONS",
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new(),
+        help = "Also write descriptions into the XMP metadata of the image files, and read the embedded ones of new images. [default: false]"
+    )]
+    embed_descriptions: Option<bool>,
+    #[clap(
+        long,
+        overrides_with = "embed_descriptions",
+        help = "Do not embed descriptions, even if the config file or the environment enables it."
+    )]
+    no_embed_descriptions: bool,
+    #[clap(
+        long,
+        env = "MASSPHOTO_FFMPEG",
+        help = "The ffmpeg executable to make lower bitrate renditions of videos. An empty string disables transcoding. [default: ffmpeg]"
+    )]
+    ffmpeg: Option<String>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_RENDITION_DIR",
+        help = "The directory to store video renditions in. [default: .renditions in the album root]"
+    )]
+    rendition_dir: Option<PathBuf>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_BACKUP_DIR",
+        help = "The directory to store database backups made by POST /admin/backup and before migrations in. [default: .backups in the album root]"
     )]
-    upload_limit: u64,
+    backup_dir: Option<PathBuf>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_ADMIN_PASSWORD",
+        help = "The password to set to the admin on the first run. [default: generated and printed once]"
+    )]
+    admin_password: Option<String>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_PASSWORD_MIN_LENGTH",
+        help = "Minimum number of characters of user passwords. [default: 8]"
+    )]
+    password_min_length: Option<usize>,
+    #[clap(
+        long,
+        env = "MASSPHOTO_PASSWORD_MIN_CLASSES",
+        help = "Minimum number of the classes of characters in user passwords: lower case letters, upper case letters, digits and the others. [default: 1]"
+    )]
+    password_min_classes: Option<usize>,
+}
+
+impl MyData {
+    /// Get a DB connection from the pool.
+    fn conn(&self) -> actix_web::Result<DbConnection> {
+        self.pool.get().map_err(map_err)
+    }
 }
 
 fn map_err(err: impl ToString) -> Error {
@@ -86,29 +303,48 @@ fn map_err(err: impl ToString) -> Error {
 }
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    run()
-        .await
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+async fn main() -> anyhow::Result<()> {
+    // anyhow::Error prints the chain of causes, which helps to find configuration errors.
+    run().await
 }
 
 async fn run() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let cli = Cli::parse();
+    match cli.command {
+        None => serve(cli.args).await,
+        Some(Command::Serve(args)) => serve(*args).await,
+        Some(Command::Maintenance(command)) => run_maintenance(command),
+    }
+}
+
+async fn serve(args: Args) -> anyhow::Result<()> {
+    let config = Config::load(&args)?;
 
-    let data = init_db(&args)?;
+    if args.print_config {
+        print!("{}", toml::to_string_pretty(&config)?);
+        return Ok(());
+    }
+
+    let data = init_db(&config)?;
+    init_admin_password(
+        &*data.pool.get()?,
+        config.admin_password.as_deref(),
+        &config.password_policy(),
+    )?;
 
     let data_copy = data.clone();
-    let server_fut = HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         #[cfg(not(debug_assertions))]
         let cors = {
             let mut cors = Cors::default();
-            cors = if args.cors_origin == "*" {
+            cors = if config.cors_origin == "*" {
                 cors.allow_any_origin()
             } else {
-                cors.allowed_origin(&args.cors_origin)
+                cors.allowed_origin(&config.cors_origin)
             };
-            cors.allowed_methods(vec!["GET", "POST", "DELETE"])
+            cors.allowed_methods(vec!["GET", "POST", "PATCH", "DELETE"])
                 .allowed_header(actix_web::http::header::CONTENT_TYPE)
+                .allowed_header(csrf::CSRF_HEADER)
                 .max_age(3600)
         };
         #[cfg(debug_assertions)]
@@ -116,6 +352,8 @@ async fn run() -> anyhow::Result<()> {
 
         App::new()
             .app_data(data.clone())
+            .wrap(Csrf)
+            .wrap(ApiToken)
             .wrap(cors)
             .route("/", web::get().to(index))
             .service(code)
@@ -126,36 +364,86 @@ async fn run() -> anyhow::Result<()> {
             .service(list_users)
             .service(create_user)
             .service(delete_user)
+            .service(update_user)
             .service(status_user)
+            .service(get_setup_status)
             .service(login_user)
+            .service(login_totp)
+            .service(get_totp_status)
+            .service(enroll_totp)
+            .service(confirm_totp)
+            .service(disable_totp)
+            .service(reset_totp)
+            .service(list_tokens)
+            .service(create_token)
+            .service(revoke_token)
             .service(logout_user)
             .service(set_user_password)
+            .service(issue_reset_token)
+            .service(reset_password)
             .service(get_image_desc)
             .service(set_image_desc)
             .service(get_file_thumb)
             .service(get_file)
+            .service(stream_video)
             .service(delete_file)
             .service(move_file)
+            .service(execute_batch)
             .service(set_album_lock)
+            .service(set_album_public)
             .service(authorize_album)
             .service(get_owner)
             .service(set_owner)
+            .service(list_grants)
+            .service(set_grant)
+            .service(delete_grant)
+            .service(list_groups)
+            .service(create_group)
+            .service(delete_group)
+            .service(add_group_member)
+            .service(remove_group_member)
             .service(create_session)
+            .service(list_lockouts)
+            .service(clear_lockouts)
+            .service(clear_lockout)
+            .service(get_pregenerate_status)
+            .service(pregenerate_thumbnails)
             .service(clear_cache)
-            .app_data(web::PayloadConfig::new(args.upload_limit as usize))
+            .service(create_backup)
+            .service(export_metadata)
+            .service(import_metadata)
+            .app_data(web::PayloadConfig::new(config.upload_limit as usize))
             .service(upload)
-    })
-    .bind((args.host, args.port))?
+    });
+
+    let server_fut = if let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) {
+        let resolver = Arc::new(CertResolver::new(cert, key)?);
+        actix_rt::spawn(watch_certificate(resolver.clone()));
+        if let Some(http_redirect_port) = config.http_redirect_port {
+            actix_rt::spawn(redirect_server(
+                &config.host,
+                http_redirect_port,
+                config.port,
+            )?);
+        }
+        println!("Serving HTTPS on {}:{}", config.host, config.port);
+        server.bind_rustls((config.host.as_str(), config.port), server_config(resolver))?
+    } else {
+        server.bind((config.host.as_str(), config.port))?
+    }
     .run();
 
-    actix_rt::spawn(periodic_cleanup(data_copy.clone(), args.cleanup_period));
+    actix_rt::spawn(periodic_cleanup(data_copy.clone(), config.cleanup_period));
 
-    let result = server_fut.await;
+    if config.pregenerate {
+        start_pregenerate(data_copy.clone());
+    }
+    start_rendition_gc(&data_copy);
 
-    let mut cache = data_copy.cache.lock().unwrap();
+    let result = server_fut.await;
 
     let time_save_db = Instant::now();
-    write_db(&data_copy, &mut cache).expect("Error in saving cache");
+    write_db(&data_copy).expect("Error in saving cache");
     println!(
         "time save db: {} s",
         time_save_db.elapsed().as_micros() as f64 / 1e6
@@ -164,6 +452,14 @@ async fn run() -> anyhow::Result<()> {
     result.map_err(anyhow::Error::new)
 }
 
+/// Seconds since the UNIX epoch, the way the timestamps are stored in the DB
+pub(crate) fn now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time always exist since UNIX_EPOCH")
+        .as_secs_f64()
+}
+
 fn measure_time<T>(f: impl FnOnce() -> T) -> (T, f64) {
     let start = std::time::Instant::now();
     let ret = f();
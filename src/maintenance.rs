@@ -0,0 +1,586 @@
+//! Offline maintenance subcommands. They operate directly on the DB in the album root, so they
+//! work even when the admin password is lost. Do not run them while the server is running, since
+//! the server would overwrite the changes from its in-memory cache.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context};
+use clap::{Args, Subcommand};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    backup::{backup_db, new_backup_path, restore_db},
+    cache::{CacheEntry, CachePayload, FilePayload},
+    config::Config,
+    db_utils::{init_db, write_db, DB_FILE_NAME},
+    files::{get_file_modified, is_hidden, is_image, make_thumbnail},
+    password::PasswordPolicy,
+    sidecar::{export_sidecars, import_sidecars},
+    thumb_store::{migrate, new_store, ThumbnailStore, ThumbnailStoreKind},
+    user::delete_user_rows,
+};
+
+#[derive(Args, Debug)]
+pub(crate) struct Target {
+    #[clap(
+        long,
+        global = true,
+        env = "MASSPHOTO_PATH",
+        help = "The root directory of the photo albums. [default: .]"
+    )]
+    root: Option<String>,
+    #[clap(
+        long,
+        global = true,
+        env = "MASSPHOTO_CONFIG",
+        help = "The config file. [default: massphoto.toml in the album root, if exists]"
+    )]
+    config: Option<PathBuf>,
+    #[clap(
+        long,
+        global = true,
+        env = "MASSPHOTO_PASSWORD_MIN_LENGTH",
+        help = "Minimum number of characters of user passwords. [default: 8]"
+    )]
+    password_min_length: Option<usize>,
+    #[clap(
+        long,
+        global = true,
+        env = "MASSPHOTO_PASSWORD_MIN_CLASSES",
+        help = "Minimum number of the classes of characters in user passwords. [default: 1]"
+    )]
+    password_min_classes: Option<usize>,
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum MaintenanceCommand {
+    /// Manage user accounts
+    User {
+        #[clap(flatten)]
+        target: Target,
+        #[clap(subcommand)]
+        command: UserCommand,
+    },
+    /// Manage albums
+    Album {
+        #[clap(flatten)]
+        target: Target,
+        #[clap(subcommand)]
+        command: AlbumCommand,
+    },
+    /// Maintain the database
+    Db {
+        #[clap(flatten)]
+        target: Target,
+        #[clap(subcommand)]
+        command: DbCommand,
+    },
+    /// Maintain the thumbnail cache
+    Thumbs {
+        #[clap(flatten)]
+        target: Target,
+        #[clap(subcommand)]
+        command: ThumbsCommand,
+    },
+    /// Export or import the metadata as sidecar files in the albums
+    Metadata {
+        #[clap(flatten)]
+        target: Target,
+        #[clap(subcommand)]
+        command: MetadataCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum UserCommand {
+    /// Add a user
+    Add {
+        name: String,
+        #[clap(long, help = "The password. Prompted if not given.")]
+        password: Option<String>,
+        #[clap(long, help = "Make the user an admin.")]
+        admin: bool,
+    },
+    /// Delete a user and transfer the albums of the user to another
+    Del {
+        name: String,
+        #[clap(
+            long,
+            help = "The user to take over the albums. Defaults to the first other admin."
+        )]
+        new_owner: Option<String>,
+    },
+    /// Change the password of a user
+    Passwd {
+        name: String,
+        #[clap(long, help = "The password. Prompted if not given.")]
+        password: Option<String>,
+    },
+    /// List users
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum AlbumCommand {
+    /// Lock an album with a password
+    Lock {
+        path: PathBuf,
+        #[clap(long, help = "The password. Prompted if not given.")]
+        password: Option<String>,
+    },
+    /// Remove the password of an album
+    Unlock { path: PathBuf },
+    /// Change the owner of an album
+    Chown { path: PathBuf, user: String },
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum DbCommand {
+    /// Rebuild the database file to reclaim unused space
+    Vacuum,
+    /// Check the integrity of the database and report stale entries
+    Check,
+    /// Delete the entries of files and albums which no longer exist, and unreferenced thumbnails
+    Gc,
+    /// Make a consistent copy of the database. It is safe while the server is running, but the
+    /// thumbnails the server has not written yet are not included.
+    Backup {
+        #[clap(
+            help = "The backup file. [default: .backups/sqliter-<timestamp>.db in the album root]"
+        )]
+        dest: Option<PathBuf>,
+        #[clap(
+            long,
+            help = "Leave out the thumbnails in the database to make the backup smaller."
+        )]
+        no_thumbnails: bool,
+    },
+    /// Replace the database with a backup, after checking its integrity and schema version
+    Restore { src: PathBuf },
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum ThumbsCommand {
+    /// Regenerate thumbnails of the images under the path, recursively
+    Rebuild {
+        #[clap(default_value = "")]
+        path: PathBuf,
+    },
+    /// Move all the thumbnails to the store from the other one. Set `thumbnail_store` to the same
+    /// store afterwards.
+    Migrate {
+        #[clap(arg_enum)]
+        to: ThumbnailStoreKind,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum MetadataCommand {
+    /// Write the descriptions, owners and locks to `.massphoto.json` in each album
+    Export,
+    /// Read `.massphoto.json` in each album into the database
+    Import,
+}
+
+pub(crate) fn run_maintenance(command: MaintenanceCommand) -> anyhow::Result<()> {
+    match command {
+        MaintenanceCommand::User { target, command } => {
+            let config = target.load_config()?;
+            let data = init_db(&config)?;
+            let mut conn = data.pool.get()?;
+            run_user(&mut conn, &config.password_policy(), command)
+        }
+        MaintenanceCommand::Album { target, command } => {
+            let config = target.load_config()?;
+            let data = init_db(&config)?;
+            let conn = data.pool.get()?;
+            run_album(&conn, &config.path, command)
+        }
+        MaintenanceCommand::Db {
+            target,
+            command: DbCommand::Restore { src },
+        } => {
+            // Do not open the DB with init_db, which would migrate the DB that is being replaced.
+            let config = target.load_config()?;
+            restore_db(&src, &config.path.join(DB_FILE_NAME), &config.backup_dir())
+        }
+        MaintenanceCommand::Db {
+            target,
+            command:
+                DbCommand::Backup {
+                    dest,
+                    no_thumbnails,
+                },
+        } => {
+            let config = target.load_config()?;
+            let data = init_db(&config)?;
+            let dest = dest.unwrap_or_else(|| new_backup_path(&config.backup_dir()));
+            backup_db(&data, &dest, !no_thumbnails)?;
+            Ok(())
+        }
+        MaintenanceCommand::Db { target, command } => {
+            let config = target.load_config()?;
+            let data = init_db(&config)?;
+            let mut conn = data.pool.get()?;
+            run_db(&mut conn, &config.path, data.thumb_store.as_ref(), command)
+        }
+        MaintenanceCommand::Thumbs {
+            target,
+            command: ThumbsCommand::Rebuild { path },
+        } => {
+            let config = target.load_config()?;
+            let data = init_db(&config)?;
+            let mut count = 0;
+            rebuild_thumbs(&config.path, &path, &mut |rel_path, thumbnail, modified| {
+                let mut cache = data.cache.write().unwrap();
+                let entry = cache.entry(rel_path).or_insert_with(|| CacheEntry {
+                    new: true,
+                    modified,
+                    desc: None,
+                    payload: CachePayload::File(FilePayload { data: vec![] }),
+                });
+                entry.new = true;
+                entry.modified = modified;
+                entry.payload = CachePayload::File(FilePayload { data: thumbnail });
+                count += 1;
+            })?;
+            write_db(&data)?;
+            println!("Rebuilt {count} thumbnails");
+            Ok(())
+        }
+        MaintenanceCommand::Thumbs {
+            target,
+            command: ThumbsCommand::Migrate { to },
+        } => {
+            let config = target.load_config()?;
+            let data = init_db(&config)?;
+            let from = match to {
+                ThumbnailStoreKind::Sqlite => ThumbnailStoreKind::Disk,
+                ThumbnailStoreKind::Disk => ThumbnailStoreKind::Sqlite,
+            };
+            let thumbnail_dir = config.thumbnail_dir();
+            let count = migrate(
+                &mut *data.pool.get()?,
+                new_store(from, &thumbnail_dir).as_ref(),
+                new_store(to, &thumbnail_dir).as_ref(),
+            )?;
+            println!("Migrated {count} thumbnails from {from} to {to}");
+            if config.thumbnail_store != to {
+                println!("Set thumbnail_store = \"{to}\" in the config to use them");
+            }
+            Ok(())
+        }
+        MaintenanceCommand::Metadata { target, command } => {
+            let config = target.load_config()?;
+            let data = init_db(&config)?;
+            match command {
+                MetadataCommand::Export => export_sidecars(&data)?,
+                MetadataCommand::Import => import_sidecars(&data)?,
+            };
+            Ok(())
+        }
+    }
+}
+
+impl Target {
+    fn load_config(&self) -> anyhow::Result<Config> {
+        Config::load(&crate::Args {
+            path: self.root.clone(),
+            config: self.config.clone(),
+            password_min_length: self.password_min_length,
+            password_min_classes: self.password_min_classes,
+            ..Default::default()
+        })
+    }
+}
+
+/// A password of a user, which must satisfy the same policy as on the server
+fn user_password_or_prompt(
+    password: Option<String>,
+    policy: &PasswordPolicy,
+) -> anyhow::Result<String> {
+    let password = password_or_prompt(password)?;
+    policy.check(&password).map_err(|e| anyhow!(e))?;
+    Ok(password)
+}
+
+fn password_or_prompt(password: Option<String>) -> anyhow::Result<String> {
+    if let Some(password) = password {
+        return Ok(password);
+    }
+    print!("Password: ");
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn find_user_id(conn: &Connection, name: &str) -> anyhow::Result<usize> {
+    conn.query_row("SELECT id FROM user WHERE name = ?1", [name], |row| {
+        row.get(0)
+    })
+    .optional()?
+    .ok_or_else(|| anyhow!("User {name:?} not found"))
+}
+
+fn run_user(
+    conn: &mut Connection,
+    policy: &PasswordPolicy,
+    command: UserCommand,
+) -> anyhow::Result<()> {
+    match command {
+        UserCommand::Add {
+            name,
+            password,
+            admin,
+        } => {
+            if find_user_id(conn, &name).is_ok() {
+                bail!("User {name:?} already exists");
+            }
+            let password = user_password_or_prompt(password, policy)?;
+            conn.execute(
+                "INSERT INTO user (name, password, is_admin) VALUES (?1, ?2, ?3)",
+                params![name, sha256::digest(&password), admin],
+            )?;
+            println!("Added user {name:?} with id {}", conn.last_insert_rowid());
+        }
+        UserCommand::Del { name, new_owner } => {
+            let id = find_user_id(conn, &name)?;
+            let other_admin: Option<usize> = conn
+                .query_row(
+                    "SELECT id FROM user WHERE is_admin AND NOT disabled AND id != ?1 ORDER BY id",
+                    [id],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let Some(other_admin) = other_admin else {
+                bail!("There must be an enabled admin other than {name:?}");
+            };
+            let new_owner = match new_owner {
+                Some(new_owner) => find_user_id(conn, &new_owner)?,
+                None => other_admin,
+            };
+            if new_owner == id {
+                bail!("The new owner must be a user other than the deleted one");
+            }
+            let albums = delete_user_rows(conn, id, new_owner)?;
+            for album in &albums {
+                println!("Transferred album {album:?} to user {new_owner}");
+            }
+            println!("Deleted user {name:?}");
+        }
+        UserCommand::Passwd { name, password } => {
+            let id = find_user_id(conn, &name)?;
+            let password = user_password_or_prompt(password, policy)?;
+            conn.execute(
+                "UPDATE user SET password = ?1, initial_password = FALSE WHERE id = ?2",
+                params![sha256::digest(&password), id],
+            )?;
+            println!("Changed the password of user {name:?}");
+        }
+        UserCommand::List => {
+            let mut stmt = conn.prepare(
+                "SELECT id, name, password is not null and length(password) != 0, is_admin, disabled, display_name FROM user",
+            )?;
+            let mut rows = stmt.query([])?;
+            println!(
+                "{:>4} {:20} {:8} {:5} {:8} display name",
+                "id", "name", "password", "admin", "disabled"
+            );
+            while let Some(row) = rows.next()? {
+                let (id, name, password, is_admin, disabled): (usize, String, bool, bool, bool) = (
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                );
+                let display_name: Option<String> = row.get(5)?;
+                println!(
+                    "{id:>4} {name:20} {password:8} {is_admin:5} {disabled:8} {}",
+                    display_name.unwrap_or_default()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_album(conn: &Connection, root: &Path, command: AlbumCommand) -> anyhow::Result<()> {
+    let (path, password, owner) = match command {
+        AlbumCommand::Lock { path, password } => {
+            let password = password_or_prompt(password)?;
+            if password.is_empty() {
+                bail!("The password must not be empty. Use `album unlock` to remove the password.");
+            }
+            (path, Some(sha256::digest(&password)), None)
+        }
+        AlbumCommand::Unlock { path } => (path, Some(String::new()), None),
+        AlbumCommand::Chown { path, user } => {
+            let owner = match user.parse() {
+                Ok(id) => id,
+                Err(_) => find_user_id(conn, &user)?,
+            };
+            (path, None, Some(owner))
+        }
+    };
+    if path.to_str().map(|s| s.contains("..")).unwrap_or(true) || !root.join(&path).is_dir() {
+        bail!("{path:?} is not an album in {root:?}");
+    }
+    let path_str = path.to_str();
+
+    // Albums without a row are owned by the admin.
+    conn.execute(
+        "INSERT OR IGNORE INTO album (path, password, owner) VALUES (?1, '', 1)",
+        [path_str],
+    )?;
+    if let Some(password) = password {
+        conn.execute(
+            "UPDATE album SET password = ?2 WHERE path = ?1",
+            params![path_str, password],
+        )?;
+        println!(
+            "{} album {path:?}",
+            if password.is_empty() {
+                "Unlocked"
+            } else {
+                "Locked"
+            }
+        );
+    }
+    if let Some(owner) = owner {
+        conn.execute(
+            "UPDATE album SET owner = ?2 WHERE path = ?1",
+            params![path_str, owner],
+        )?;
+        println!("Changed the owner of album {path:?} to {owner}");
+    }
+    Ok(())
+}
+
+/// Paths in the table which no longer exist in the album root
+fn stale_paths(
+    conn: &Connection,
+    root: &Path,
+    table: &str,
+    exists: fn(&Path) -> bool,
+) -> anyhow::Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("SELECT DISTINCT path FROM {table}"))?;
+    let paths = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(paths
+        .into_iter()
+        .filter(|path| !exists(&root.join(path)))
+        .collect())
+}
+
+/// A table and the predicate for its paths to exist
+type StaleCheck = (&'static str, fn(&Path) -> bool);
+
+const STALE_CHECKS: [StaleCheck; 3] = [
+    ("file", Path::is_file),
+    ("album", Path::is_dir),
+    ("album_grant", Path::is_dir),
+];
+
+fn run_db(
+    conn: &mut Connection,
+    root: &Path,
+    store: &dyn ThumbnailStore,
+    command: DbCommand,
+) -> anyhow::Result<()> {
+    match command {
+        DbCommand::Vacuum => {
+            let size = |conn: &Connection| -> rusqlite::Result<u64> {
+                conn.query_row(
+                    "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+                    [],
+                    |row| row.get(0),
+                )
+            };
+            let before = size(conn)?;
+            conn.execute("VACUUM", [])?;
+            println!("Vacuumed the database: {before} -> {} bytes", size(conn)?);
+        }
+        DbCommand::Check => {
+            let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+            let messages = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            for message in &messages {
+                println!("integrity_check: {message}");
+            }
+            for (table, exists) in STALE_CHECKS {
+                let stale = stale_paths(conn, root, table, exists)?;
+                println!("{} stale entries in table {table:?}", stale.len());
+                for path in stale {
+                    println!("  {path}");
+                }
+            }
+            let orphans: usize = conn.query_row(
+                "SELECT count(*) FROM album WHERE owner NOT IN (SELECT id FROM user)",
+                [],
+                |row| row.get(0),
+            )?;
+            println!("{orphans} albums owned by deleted users");
+            if messages != ["ok"] {
+                bail!("The database is corrupted");
+            }
+        }
+        DbCommand::Gc => {
+            let tx = conn.transaction()?;
+            for (table, exists) in STALE_CHECKS {
+                let stale = stale_paths(&tx, root, table, exists)?;
+                for path in &stale {
+                    tx.execute(&format!("DELETE FROM {table} WHERE path = ?1"), [path])?;
+                }
+                println!("Deleted {} stale entries in table {table:?}", stale.len());
+            }
+            tx.commit()?;
+            println!("Deleted {} unreferenced thumbnails", store.gc(conn)?);
+        }
+        DbCommand::Backup { .. } | DbCommand::Restore { .. } => {
+            unreachable!("Handled in run_maintenance")
+        }
+    }
+    Ok(())
+}
+
+/// Make thumbnails of all the images under `path` and pass them to `f` with the relative path and
+/// the modified date.
+fn rebuild_thumbs(
+    root: &Path,
+    path: &Path,
+    f: &mut impl FnMut(PathBuf, Vec<u8>, f64),
+) -> anyhow::Result<()> {
+    if path.to_str().map(|s| s.contains("..")).unwrap_or(true) {
+        bail!("Invalid path {path:?}");
+    }
+    for entry in std::fs::read_dir(root.join(path))
+        .with_context(|| format!("Failed to read directory {path:?}"))?
+    {
+        let rel_path = path.join(entry?.file_name());
+        let abs_path = root.join(&rel_path);
+        if is_hidden(&abs_path) {
+            continue;
+        }
+        if abs_path.is_dir() {
+            rebuild_thumbs(root, &rel_path, f)?;
+        } else if is_image(&abs_path) {
+            match make_thumbnail(&abs_path) {
+                Ok(data) => {
+                    println!("Made thumbnail of {rel_path:?}");
+                    let modified = get_file_modified(&abs_path).unwrap_or(0.);
+                    f(rel_path, data, modified);
+                }
+                // A broken image should not stop the whole process.
+                Err(e) => println!("Failed to make thumbnail of {rel_path:?}: {e}"),
+            }
+        }
+    }
+    Ok(())
+}
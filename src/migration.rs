@@ -0,0 +1,457 @@
+//! Versioned schema migrations of the DB.
+//!
+//! The `schema_version` table records the version of the schema. On startup, the migrations newer
+//! than it are applied in order, each in a transaction together with the update of the version, so
+//! that a failed migration leaves the DB at the previous version. The DB file is backed up into the
+//! backup directory before applying any migration to an existing DB. A DB newer than this program is refused, since an
+//! older program may not understand or may even break the data.
+//!
+//! To change the schema, append a migration with a higher version to [`MIGRATIONS`]. Never edit a
+//! migration that has been released.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use rusqlite::{params, Connection, Transaction};
+
+use crate::db_utils::{column_exists, table_exists, DB_FILE_NAME};
+
+pub(crate) type SchemaVersion = (usize, usize, usize);
+
+struct Migration {
+    version: SchemaVersion,
+    description: &'static str,
+    apply: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+/// The migrations in the order of increasing versions.
+///
+/// The migrations after the initial one check whether the change is already there, because DBs
+/// made before the migrations were versioned got the same changes while staying at 0.1.0.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: (0, 1, 0),
+        description: "Create the file, album and user tables",
+        apply: create_initial_tables,
+    },
+    Migration {
+        version: (0, 2, 0),
+        description: "Add the public flag to albums",
+        apply: add_album_public,
+    },
+    Migration {
+        version: (0, 3, 0),
+        description: "Add user groups and album grants",
+        apply: create_access_tables,
+    },
+    Migration {
+        version: (0, 4, 0),
+        description: "Add login lockouts",
+        apply: create_login_lockout_table,
+    },
+    Migration {
+        version: (0, 5, 0),
+        description: "Add the thumbnail hash to files",
+        apply: add_file_thumb,
+    },
+    Migration {
+        version: (0, 6, 0),
+        description: "Add display names and the disabled flag to users",
+        apply: add_user_profile,
+    },
+    Migration {
+        version: (0, 7, 0),
+        description: "Mark the passwords generated on the first run",
+        apply: add_user_initial_password,
+    },
+    Migration {
+        version: (0, 8, 0),
+        description: "Add password reset tokens",
+        apply: create_password_reset_table,
+    },
+    Migration {
+        version: (0, 9, 0),
+        description: "Add two-factor authentication",
+        apply: create_totp_tables,
+    },
+    Migration {
+        version: (0, 10, 0),
+        description: "Add API tokens",
+        apply: create_api_token_table,
+    },
+];
+
+/// The version of the schema this program uses
+pub(crate) fn latest_version() -> SchemaVersion {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or((0, 0, 0))
+}
+
+pub(crate) fn format_version((major, minor, release): SchemaVersion) -> String {
+    format!("{major}.{minor}.{release}")
+}
+
+/// Returns the version of the schema, or `None` if the DB is new.
+pub(crate) fn schema_version(conn: &Connection) -> rusqlite::Result<Option<SchemaVersion>> {
+    if !table_exists(conn, "schema_version") {
+        return Ok(None);
+    }
+    conn.query_row(
+        "SELECT major, minor, release FROM schema_version",
+        [],
+        |row| Ok(Some((row.get(0)?, row.get(1)?, row.get(2)?))),
+    )
+}
+
+/// Bring the schema of the DB up to date. An existing DB is backed up into `backup_dir` first.
+pub(crate) fn migrate(conn: &mut Connection, backup_dir: &Path) -> anyhow::Result<()> {
+    let latest = latest_version();
+    let version = schema_version(conn)?;
+    if let Some(version) = version {
+        if latest < version {
+            bail!(
+                "The database schema {} is newer than this program ({}). \
+                Use a newer version of the program, or restore a backup made before the upgrade.",
+                format_version(version),
+                format_version(latest)
+            );
+        }
+    }
+
+    let current = version.unwrap_or((0, 0, 0));
+    let pending: Vec<_> = MIGRATIONS.iter().filter(|m| current < m.version).collect();
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(version) = version {
+        let backup_path = backup(conn, backup_dir, version)?;
+        println!(
+            "Backed up the database before migrating from {} to {}: {backup_path:?}",
+            format_version(version),
+            format_version(latest)
+        );
+    } else {
+        // Keep track of when to apply migration
+        conn.execute_batch(
+            "CREATE TABLE schema_version (
+                major INTEGER NOT NULL,
+                minor INTEGER NOT NULL,
+                release INTEGER NOT NULL
+            );
+            INSERT INTO schema_version (major, minor, release) VALUES (0, 0, 0);",
+        )?;
+        println!("table \"schema_version\" created!");
+    }
+
+    for migration in pending {
+        let tx = conn.transaction()?;
+        (migration.apply)(&tx).with_context(|| {
+            format!(
+                "Migration to {} ({}) failed",
+                format_version(migration.version),
+                migration.description
+            )
+        })?;
+        let (major, minor, release) = migration.version;
+        tx.execute(
+            "UPDATE schema_version SET major = ?1, minor = ?2, release = ?3",
+            params![major, minor, release],
+        )?;
+        tx.commit()?;
+        println!(
+            "Migrated the database to {}: {}",
+            format_version(migration.version),
+            migration.description
+        );
+    }
+    Ok(())
+}
+
+/// Copy the DB into `backup_dir` with the version and the time in the name, and return the path of
+/// the copy. The copy has the password hashes and the secrets, so it must not be put in the albums
+/// where it could be served. `VACUUM INTO` makes a consistent copy including the changes still in
+/// the WAL file.
+fn backup(conn: &Connection, backup_dir: &Path, version: SchemaVersion) -> anyhow::Result<PathBuf> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    std::fs::create_dir_all(backup_dir)
+        .with_context(|| format!("Failed to create the backup directory {backup_dir:?}"))?;
+    let backup_path = backup_dir.join(format!(
+        "{DB_FILE_NAME}.{}-{timestamp}.bak",
+        format_version(version)
+    ));
+    let backup_str = backup_path
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("Backup path {backup_path:?} is not UTF-8"))?;
+    conn.execute("VACUUM INTO ?1", [backup_str])
+        .with_context(|| format!("Failed to back up the database to {backup_path:?}"))?;
+    Ok(backup_path)
+}
+
+fn create_initial_tables(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS file (
+            path TEXT PRIMARY KEY,
+            modified REAL,
+            desc TEXT,
+            data BLOB
+        );
+        CREATE TABLE IF NOT EXISTS album (
+            path TEXT PRIMARY KEY,
+            password TEXT,
+            desc TEXT,
+            owner INTEGER NOT NULL
+        );",
+    )?;
+    if !table_exists(tx, "user") {
+        // See https://www.sqlite.org/autoinc.html for PRIMARY KEY and AUTOINCREMENT implications
+        // We want to avoid wrong user ids even though AUTOINCREMENT adds some overhead.
+        tx.execute_batch(
+            r#"CREATE TABLE user (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL,
+                password TEXT,
+                is_admin BOOL NOT NULL
+            );
+            INSERT INTO user (name, is_admin) VALUES ("admin", TRUE);"#,
+        )?;
+    }
+    Ok(())
+}
+
+fn add_album_public(tx: &Transaction) -> rusqlite::Result<()> {
+    if !column_exists(tx, "album", "public") {
+        tx.execute(
+            "ALTER TABLE album ADD COLUMN public BOOL NOT NULL DEFAULT FALSE",
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+fn create_access_tables(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS user_group (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE
+        );
+        CREATE TABLE IF NOT EXISTS group_member (
+            group_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            PRIMARY KEY (group_id, user_id)
+        );
+        CREATE TABLE IF NOT EXISTS album_grant (
+            path TEXT NOT NULL,
+            user_id INTEGER,
+            group_id INTEGER,
+            role TEXT NOT NULL,
+            UNIQUE (path, user_id, group_id)
+        );",
+    )
+}
+
+fn create_login_lockout_table(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS login_lockout (
+            key TEXT PRIMARY KEY,
+            failures INTEGER NOT NULL,
+            locked_until REAL NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_file_thumb(tx: &Transaction) -> rusqlite::Result<()> {
+    if !column_exists(tx, "file", "thumb") {
+        tx.execute("ALTER TABLE file ADD COLUMN thumb TEXT", [])?;
+    }
+    Ok(())
+}
+
+fn add_user_profile(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE user ADD COLUMN display_name TEXT;
+        ALTER TABLE user ADD COLUMN disabled BOOL NOT NULL DEFAULT FALSE;",
+    )
+}
+
+fn add_user_initial_password(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "ALTER TABLE user ADD COLUMN initial_password BOOL NOT NULL DEFAULT FALSE",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_password_reset_table(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE password_reset (
+            user_id INTEGER PRIMARY KEY,
+            token_hash TEXT NOT NULL UNIQUE,
+            expires REAL NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_totp_tables(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE totp (
+            user_id INTEGER PRIMARY KEY,
+            secret TEXT NOT NULL,
+            enabled BOOL NOT NULL,
+            last_step INTEGER
+        );
+        CREATE TABLE totp_recovery (
+            user_id INTEGER NOT NULL,
+            code_hash TEXT NOT NULL,
+            PRIMARY KEY (user_id, code_hash)
+        );",
+    )
+}
+
+fn create_api_token_table(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE api_token (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            scopes TEXT NOT NULL,
+            created REAL NOT NULL,
+            expires REAL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        access::AccessControl,
+        cache::CacheMap,
+        config::BACKUP_DIR_NAME,
+        files::{authorized_path, CheckAuth},
+        session::Session,
+    };
+
+    fn version_in_db(conn: &Connection) -> SchemaVersion {
+        schema_version(conn).unwrap().unwrap()
+    }
+
+    #[test]
+    fn versions_increase() {
+        assert!(MIGRATIONS
+            .windows(2)
+            .all(|pair| pair[0].version < pair[1].version));
+    }
+
+    #[test]
+    fn migrate_new_db() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn, Path::new("")).unwrap();
+        assert_eq!(version_in_db(&conn), latest_version());
+        for table in [
+            "file",
+            "album",
+            "user",
+            "album_grant",
+            "login_lockout",
+            "api_token",
+        ] {
+            assert!(table_exists(&conn, table), "{table}");
+        }
+        assert!(column_exists(&conn, "user", "disabled"));
+        let admins: usize = conn
+            .query_row("SELECT COUNT(*) FROM user WHERE is_admin", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(admins, 1);
+
+        // Nothing to do for an up-to-date DB
+        migrate(&mut conn, Path::new("")).unwrap();
+        assert_eq!(version_in_db(&conn), latest_version());
+    }
+
+    #[test]
+    fn migrate_old_db_with_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join(DB_FILE_NAME);
+        let mut conn = Connection::open(&db_path).unwrap();
+        // A DB made before the migrations were versioned, which already has some later changes
+        conn.execute_batch(
+            "CREATE TABLE schema_version (major INTEGER, minor INTEGER, release INTEGER);
+            INSERT INTO schema_version VALUES (0, 1, 0);",
+        )
+        .unwrap();
+        let tx = conn.transaction().unwrap();
+        create_initial_tables(&tx).unwrap();
+        add_album_public(&tx).unwrap();
+        add_file_thumb(&tx).unwrap();
+        tx.commit().unwrap();
+        conn.execute(
+            "INSERT INTO album (path, owner, public) VALUES ('a', 1, TRUE)",
+            [],
+        )
+        .unwrap();
+
+        let backup_dir = dir.path().join(BACKUP_DIR_NAME);
+        migrate(&mut conn, &backup_dir).unwrap();
+        assert_eq!(version_in_db(&conn), latest_version());
+        let public: bool = conn
+            .query_row("SELECT public FROM album WHERE path = 'a'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(public);
+
+        let backups: Vec<_> = std::fs::read_dir(&backup_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".bak"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert!(backups[0].starts_with(&format!("{DB_FILE_NAME}.0.1.0-")));
+        assert!(!std::fs::read_dir(dir.path()).unwrap().any(|entry| entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".bak")));
+
+        // Not even the admin can get the backup as a file in the albums.
+        let mut admin = Session::new();
+        admin.user_id = Some(1);
+        admin.is_admin = true;
+        let path = Path::new(BACKUP_DIR_NAME).join(&backups[0]);
+        for check_auth in [CheckAuth::Read, CheckAuth::Ownership] {
+            assert!(authorized_path(
+                &path,
+                Some(&admin),
+                &CacheMap::new(),
+                &AccessControl::default(),
+                check_auth
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn refuse_newer_db() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn, Path::new("")).unwrap();
+        let (major, minor, release) = latest_version();
+        conn.execute(
+            "UPDATE schema_version SET major = ?1, minor = ?2, release = ?3",
+            params![major, minor, release + 1],
+        )
+        .unwrap();
+        assert!(migrate(&mut conn, Path::new("")).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! Password policy and password resets by the admin.
+//!
+//! A user who forgot the password cannot change it, since changing requires the current one. The
+//! admin issues a one-time reset token instead, and hands it to the user out of band. The token is
+//! stored hashed, so a leaked DB does not give working tokens.
+
+use actix_web::{error, web, HttpRequest, Result};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    csrf::generate_token,
+    map_err, now,
+    rate_limit::{ip_key, LoginRateLimit},
+    session::{check_admin, remove_user_sessions},
+    user::user_exists,
+    MyData,
+};
+
+/// How long a reset token is valid, in seconds
+const RESET_TOKEN_TTL_SECS: f64 = 24. * 60. * 60.;
+
+#[derive(Debug, Clone)]
+pub(crate) struct PasswordPolicy {
+    pub min_length: usize,
+    /// Minimum number of the classes of characters: lower case letters, upper case letters,
+    /// digits and the others
+    pub min_classes: usize,
+}
+
+impl PasswordPolicy {
+    /// Returns the reason if the password is too weak.
+    pub(crate) fn check(&self, password: &str) -> std::result::Result<(), String> {
+        if password.is_empty() {
+            return Err("The password must not be empty".to_string());
+        }
+        if password.chars().count() < self.min_length {
+            return Err(format!(
+                "The password must be at least {} characters long",
+                self.min_length
+            ));
+        }
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_ascii_digit()),
+            password
+                .chars()
+                .any(|c| !c.is_lowercase() && !c.is_uppercase() && !c.is_ascii_digit()),
+        ]
+        .into_iter()
+        .filter(|has| *has)
+        .count();
+        if classes < self.min_classes {
+            return Err(format!(
+                "The password must contain at least {} of lower case letters, upper case letters, digits and symbols",
+                self.min_classes
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the password for a request handler.
+    pub(crate) fn check_request(&self, password: &str) -> Result<()> {
+        self.check(password).map_err(error::ErrorBadRequest)
+    }
+}
+
+#[derive(Serialize)]
+struct ResetTokenResult {
+    token: String,
+    /// In seconds since the UNIX epoch
+    expires: f64,
+}
+
+/// Issues a one-time token to reset the password of the user. A new token replaces the previous
+/// one of the user.
+#[actix_web::post("/users/{id}/reset_token")]
+pub(crate) async fn issue_reset_token(
+    data: web::Data<MyData>,
+    id: web::Path<usize>,
+    req: HttpRequest,
+) -> Result<web::Json<ResetTokenResult>> {
+    check_admin(&data, &req, "issue password reset tokens")?;
+    let conn = data.conn()?;
+    if !user_exists(&conn, *id).map_err(map_err)? {
+        return Err(error::ErrorNotFound("User not found"));
+    }
+    let token = generate_token();
+    let expires = now() + RESET_TOKEN_TTL_SECS;
+    conn.execute("DELETE FROM password_reset WHERE expires < ?1", [now()])
+        .map_err(map_err)?;
+    conn.execute(
+        "INSERT OR REPLACE INTO password_reset (user_id, token_hash, expires) VALUES (?1, ?2, ?3)",
+        params![*id, sha256::digest(&token), expires],
+    )
+    .map_err(map_err)?;
+    println!("Issued a password reset token for user {id}");
+    Ok(web::Json(ResetTokenResult { token, expires }))
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordParams {
+    token: String,
+    new_password: String,
+}
+
+/// Sets a new password with a reset token, and logs out all the sessions of the user.
+#[actix_web::post("/users/reset_password", wrap = "LoginRateLimit")]
+pub(crate) async fn reset_password(
+    data: web::Data<MyData>,
+    params: web::Json<ResetPasswordParams>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    data.password_policy.check_request(&params.new_password)?;
+    let mut sessions = data.sessions.write().unwrap();
+    let mut conn = data.conn()?;
+    let mut limiter = data.rate_limiter.lock().unwrap();
+    // Count the failures only by the client, since the token does not tell the target account.
+    let keys: Vec<_> = req
+        .peer_addr()
+        .map(|addr| ip_key(addr.ip()))
+        .into_iter()
+        .collect();
+    limiter.check_attempt(&keys)?;
+
+    let tx = conn.transaction().map_err(map_err)?;
+    let user_id: Option<usize> = tx
+        .query_row(
+            "SELECT user_id FROM password_reset WHERE token_hash = ?1 AND ?2 < expires",
+            params![sha256::digest(&params.token), now()],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(map_err)?;
+    let Some(user_id) = user_id else {
+        drop(tx);
+        limiter.record_failure(&conn, &keys).map_err(map_err)?;
+        return Err(error::ErrorNotAcceptable(
+            "The reset token is invalid or expired",
+        ));
+    };
+    tx.execute(
+        "UPDATE user SET password = ?1, initial_password = FALSE WHERE id = ?2",
+        params![sha256::digest(&params.new_password), user_id],
+    )
+    .map_err(map_err)?;
+    // The token is used only once.
+    tx.execute("DELETE FROM password_reset WHERE user_id = ?1", [user_id])
+        .map_err(map_err)?;
+    tx.commit().map_err(map_err)?;
+
+    let count = remove_user_sessions(&mut sessions, user_id, None);
+    println!("Reset the password of user {user_id} and invalidated {count} sessions");
+    Ok("Ok")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(min_length: usize, min_classes: usize) -> PasswordPolicy {
+        PasswordPolicy {
+            min_length,
+            min_classes,
+        }
+    }
+
+    #[test]
+    fn min_length() {
+        let policy = policy(8, 1);
+        assert!(policy.check("").is_err());
+        assert!(policy.check("1234567").is_err());
+        assert!(policy.check("12345678").is_ok());
+        // Counted in characters, not bytes
+        assert!(policy.check("あいうえおかきく").is_ok());
+        assert!(policy.check("あいうえおかき").is_err());
+    }
+
+    #[test]
+    fn min_classes() {
+        let three = policy(1, 3);
+        assert!(three.check("lowercase").is_err());
+        assert!(three.check("lowerUPPER").is_err());
+        assert!(three.check("lowerUPPER1").is_ok());
+        assert!(three.check("lower1!").is_ok());
+        assert!(three.check("UPPER 1").is_ok());
+        let four = policy(1, 4);
+        assert!(four.check("aA1!").is_ok());
+        assert!(four.check("aA1").is_err());
+    }
+
+    #[test]
+    fn empty_is_rejected_by_any_policy() {
+        assert!(policy(0, 0).check("").is_err());
+        assert!(policy(0, 0).check("x").is_ok());
+    }
+}
@@ -0,0 +1,226 @@
+//! Background pre-generation of thumbnails.
+//!
+//! A job scans the album root for images whose thumbnails are missing or older than the file, and
+//! makes them on a bounded pool of worker threads, so that the first visit to a big album does not
+//! decode every original on the actix workers. Each thumbnail is written to the DB as soon as it
+//! is made, instead of waiting for the next periodic cleanup.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
+
+use actix_web::{error, web, HttpRequest, Result};
+use serde::Serialize;
+
+use crate::{
+    cache::{CacheEntry, CachePayload, FilePayload},
+    db_utils::save_thumbnail,
+    files::{get_file_modified, is_hidden, is_image, make_thumbnail, new_image_desc, worker_count},
+    session::check_admin,
+    MyData,
+};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct PregenerateStatus {
+    running: bool,
+    /// Number of images queued in the current or the last job
+    total: usize,
+    done: usize,
+    failed: usize,
+    /// Seconds the current job has been running, or the last job took
+    elapsed: Option<f64>,
+}
+
+pub(crate) struct Pregenerator {
+    workers: usize,
+    status: Mutex<PregenerateStatus>,
+    started: Mutex<Option<Instant>>,
+}
+
+impl Pregenerator {
+    /// `workers` of 0 means the number of available CPUs.
+    pub(crate) fn new(workers: usize) -> Self {
+        Self {
+            workers: worker_count(workers),
+            status: Mutex::default(),
+            started: Mutex::default(),
+        }
+    }
+
+    fn elapsed(&self) -> Option<f64> {
+        self.started
+            .lock()
+            .unwrap()
+            .map(|started| started.elapsed().as_secs_f64())
+    }
+
+    fn status(&self) -> PregenerateStatus {
+        let mut status = self.status.lock().unwrap().clone();
+        if status.running {
+            status.elapsed = self.elapsed();
+        }
+        status
+    }
+}
+
+/// Start a pre-generation job in the background. Returns false if a job is already running.
+pub(crate) fn start_pregenerate(data: web::Data<MyData>) -> bool {
+    {
+        let mut status = data.pregenerator.status.lock().unwrap();
+        if status.running {
+            return false;
+        }
+        *status = PregenerateStatus {
+            running: true,
+            ..Default::default()
+        };
+        *data.pregenerator.started.lock().unwrap() = Some(Instant::now());
+    }
+    std::thread::spawn(move || run_job(&data));
+    true
+}
+
+fn run_job(data: &MyData) {
+    let root = data.path.clone();
+    let mut images = vec![];
+    if let Err(e) = collect_images(&root, Path::new(""), &mut images) {
+        println!("Error in scanning images to pre-generate thumbnails: {e}");
+    }
+
+    let queue: Vec<_> = {
+        let cache = data.cache.read().unwrap();
+        images
+            .into_iter()
+            .filter(|(rel_path, modified)| {
+                cache
+                    .get(rel_path)
+                    .map(|entry| entry.modified < *modified)
+                    .unwrap_or(true)
+            })
+            .map(|(rel_path, _)| rel_path)
+            .collect()
+    };
+
+    let pregenerator = &data.pregenerator;
+    pregenerator.status.lock().unwrap().total = queue.len();
+    println!(
+        "Pre-generating {} thumbnails with {} workers",
+        queue.len(),
+        pregenerator.workers
+    );
+
+    let queue = Mutex::new(queue);
+    std::thread::scope(|scope| {
+        for _ in 0..pregenerator.workers {
+            scope.spawn(|| loop {
+                let Some(rel_path) = queue.lock().unwrap().pop() else {
+                    break;
+                };
+                let res = pregenerate_one(data, &root, &rel_path);
+                let mut status = pregenerator.status.lock().unwrap();
+                match res {
+                    Ok(()) => status.done += 1,
+                    Err(e) => {
+                        // A broken image should not stop the whole job.
+                        println!("Failed to pre-generate thumbnail of {rel_path:?}: {e}");
+                        status.failed += 1;
+                    }
+                }
+            });
+        }
+    });
+
+    let mut status = pregenerator.status.lock().unwrap();
+    status.running = false;
+    status.elapsed = pregenerator.elapsed();
+    println!(
+        "Pre-generated {}/{} thumbnails ({} failed) in {} s",
+        status.done,
+        status.total,
+        status.failed,
+        status.elapsed.unwrap_or(0.)
+    );
+}
+
+/// Collect relative paths and modified dates of images under `path`, recursively.
+fn collect_images(
+    root: &Path,
+    path: &Path,
+    images: &mut Vec<(PathBuf, f64)>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(root.join(path))? {
+        let rel_path = path.join(entry?.file_name());
+        let abs_path = root.join(&rel_path);
+        if is_hidden(&abs_path) {
+            continue;
+        }
+        if abs_path.is_dir() {
+            collect_images(root, &rel_path, images)?;
+        } else if is_image(&abs_path) {
+            let modified = get_file_modified(&abs_path).unwrap_or(0.);
+            images.push((rel_path, modified));
+        }
+    }
+    Ok(())
+}
+
+fn pregenerate_one(data: &MyData, root: &Path, rel_path: &Path) -> anyhow::Result<()> {
+    // No locks are held while making the thumbnail.
+    let abs_path = root.join(rel_path);
+    let thumbnail = make_thumbnail(&abs_path)?;
+    let modified = get_file_modified(&abs_path)?;
+    let desc = new_image_desc(data, rel_path, &abs_path);
+
+    let mut conn = data.pool.get()?;
+    let tx = conn.transaction()?;
+    save_thumbnail(
+        &tx,
+        data.thumb_store.as_ref(),
+        rel_path,
+        modified,
+        desc.as_deref(),
+        &thumbnail,
+    )?;
+    tx.commit()?;
+    // The old thumbnail would be served from memory, since the entry below looks fresh.
+    data.thumbnails.lock().unwrap().remove(rel_path);
+    let mut cache = data.cache.write().unwrap();
+    let entry = cache
+        .entry(rel_path.to_owned())
+        .or_insert_with(|| CacheEntry {
+            new: false,
+            modified,
+            desc,
+            payload: CachePayload::File(FilePayload { data: vec![] }),
+        });
+    // The thumbnail is already in the DB, so it does not need to stay in memory.
+    entry.new = false;
+    entry.modified = modified;
+    entry.payload = CachePayload::File(FilePayload { data: vec![] });
+    Ok(())
+}
+
+#[actix_web::get("/admin/thumbnails")]
+pub(crate) async fn get_pregenerate_status(
+    data: web::Data<MyData>,
+    req: HttpRequest,
+) -> Result<web::Json<PregenerateStatus>> {
+    check_admin(&data, &req, "pre-generate thumbnails")?;
+    Ok(web::Json(data.pregenerator.status()))
+}
+
+#[actix_web::post("/admin/thumbnails")]
+pub(crate) async fn pregenerate_thumbnails(
+    data: web::Data<MyData>,
+    req: HttpRequest,
+) -> Result<web::Json<PregenerateStatus>> {
+    check_admin(&data, &req, "pre-generate thumbnails")?;
+    if !start_pregenerate(data.clone()) {
+        return Err(error::ErrorConflict(
+            "Thumbnail pre-generation is already running",
+        ));
+    }
+    Ok(web::Json(data.pregenerator.status()))
+}
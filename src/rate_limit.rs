@@ -0,0 +1,403 @@
+//! Brute-force protection for the endpoints that check passwords.
+//!
+//! Failed attempts are counted per client IP address and per target (user account or album).
+//! After a few free attempts, each further failure has to wait for an exponentially growing
+//! delay, and too many failures lock the target out for a while. Lockouts are recorded in the DB
+//! so that restarting the server does not reset them. The failures are forgotten after a quiet
+//! period, so that occasional typos from a shared IP address never add up to a lockout.
+
+use std::{collections::HashMap, net::IpAddr, path::Path};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    error,
+    http::header::RETRY_AFTER,
+    web, Error, HttpRequest, HttpResponse, Result,
+};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::{map_err, now, session::check_admin, MyData};
+
+/// Number of failures allowed without any delay
+const FREE_ATTEMPTS: u32 = 3;
+/// The delay after the first failure beyond the free attempts. It doubles on each failure.
+const BASE_DELAY_SECS: f64 = 1.;
+const MAX_DELAY_SECS: f64 = 300.;
+/// Number of failures that lock the target out
+const LOCKOUT_ATTEMPTS: u32 = 10;
+const LOCKOUT_SECS: f64 = 15. * 60.;
+/// The failures are counted from zero again after this long without any
+const FAILURE_WINDOW_SECS: f64 = LOCKOUT_SECS;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct Failures {
+    failures: u32,
+    /// Unix time in seconds until which further attempts are rejected
+    blocked_until: f64,
+    /// Unix time in seconds of the last failure
+    last_failure: f64,
+}
+
+impl Failures {
+    /// Whether the failures are neither blocking nor recent enough to count anymore
+    fn is_stale(&self, now: f64) -> bool {
+        self.blocked_until <= now && FAILURE_WINDOW_SECS <= now - self.last_failure
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    entries: HashMap<String, Failures>,
+}
+
+pub(crate) fn ip_key(addr: IpAddr) -> String {
+    format!("ip:{addr}")
+}
+
+pub(crate) fn account_key(name: &str) -> String {
+    format!("user:{name}")
+}
+
+pub(crate) fn album_key(path: &Path) -> String {
+    format!("album:{}", path.to_string_lossy())
+}
+
+/// Keys for the client of the request, to be combined with the target key.
+pub(crate) fn client_keys(req: &HttpRequest, target: String) -> Vec<String> {
+    req.peer_addr()
+        .map(|addr| ip_key(addr.ip()))
+        .into_iter()
+        .chain(std::iter::once(target))
+        .collect()
+}
+
+impl RateLimiter {
+    /// Returns the remaining seconds to wait if any of the keys is blocked.
+    pub(crate) fn check(&self, keys: &[String]) -> Option<f64> {
+        self.check_at(keys, now())
+    }
+
+    fn check_at(&self, keys: &[String], now: f64) -> Option<f64> {
+        keys.iter()
+            .filter_map(|key| self.entries.get(key))
+            .map(|entry| entry.blocked_until - now)
+            .filter(|remaining| 0. < *remaining)
+            .reduce(f64::max)
+    }
+
+    /// Returns an error response if any of the keys is blocked.
+    pub(crate) fn check_attempt(&self, keys: &[String]) -> Result<()> {
+        match self.check(keys) {
+            Some(remaining) => Err(error::InternalError::from_response(
+                "Too many failed attempts",
+                too_many_requests(remaining),
+            )
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Count a failed attempt on all the keys, and persist the lockouts if any.
+    pub(crate) fn record_failure(
+        &mut self,
+        conn: &Connection,
+        keys: &[String],
+    ) -> rusqlite::Result<()> {
+        self.record_failure_at(conn, keys, now())
+    }
+
+    fn record_failure_at(
+        &mut self,
+        conn: &Connection,
+        keys: &[String],
+        now: f64,
+    ) -> rusqlite::Result<()> {
+        for key in keys {
+            let entry = self.entries.entry(key.clone()).or_default();
+            if entry.is_stale(now) {
+                entry.failures = 0;
+            }
+            entry.failures += 1;
+            entry.last_failure = now;
+            if LOCKOUT_ATTEMPTS <= entry.failures {
+                entry.blocked_until = now + LOCKOUT_SECS;
+                conn.execute(
+                    "INSERT OR REPLACE INTO login_lockout (key, failures, locked_until)
+                    VALUES (?1, ?2, ?3)",
+                    params![key, entry.failures, entry.blocked_until],
+                )?;
+                println!("Locked out {key} after {} failures", entry.failures);
+            } else if FREE_ATTEMPTS <= entry.failures {
+                let delay = BASE_DELAY_SECS * 2f64.powi((entry.failures - FREE_ATTEMPTS) as i32);
+                entry.blocked_until = now + delay.min(MAX_DELAY_SECS);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the failures of the target on a successful attempt. The client IP address is kept
+    /// counting, otherwise an attacker could reset it with their own account.
+    pub(crate) fn record_success(&mut self, target: &str) {
+        self.entries.remove(target);
+    }
+
+    /// Forget the stale failures, so that the entries for made-up targets do not pile up. Returns
+    /// the number of the removed entries.
+    pub(crate) fn prune(&mut self) -> usize {
+        self.prune_at(now())
+    }
+
+    fn prune_at(&mut self, now: f64) -> usize {
+        let count = self.entries.len();
+        self.entries.retain(|_, entry| !entry.is_stale(now));
+        count - self.entries.len()
+    }
+}
+
+fn too_many_requests(remaining: f64) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, remaining.ceil().to_string()))
+        .body("Too many failed attempts. Try again later.")
+}
+
+/// Load the lockouts that are still in effect.
+pub(crate) fn load_rate_limiter(conn: &Connection) -> rusqlite::Result<RateLimiter> {
+    conn.execute("DELETE FROM login_lockout WHERE locked_until < ?1", [now()])?;
+    let mut stmt = conn.prepare("SELECT key, failures, locked_until FROM login_lockout")?;
+    let entries = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                Failures {
+                    failures: row.get(1)?,
+                    blocked_until: row.get(2)?,
+                    // Not persisted; the lockout started with the last failure.
+                    last_failure: row.get::<_, f64>(2)? - LOCKOUT_SECS,
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+    if !entries.is_empty() {
+        println!("Loaded {} login lockouts", entries.len());
+    }
+    Ok(RateLimiter { entries })
+}
+
+/// A middleware that rejects requests from a blocked client IP address before even parsing the
+/// request body. Attach it to the routes that check passwords.
+pub(crate) struct LoginRateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for LoginRateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = LoginRateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<std::result::Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(LoginRateLimitMiddleware { service }))
+    }
+}
+
+pub(crate) struct LoginRateLimitMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for LoginRateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, std::result::Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let blocked = req
+            .app_data::<web::Data<MyData>>()
+            .zip(req.peer_addr())
+            .and_then(|(data, addr)| {
+                let limiter = data.rate_limiter.lock().unwrap();
+                limiter.check(&[ip_key(addr.ip())])
+            });
+        if let Some(remaining) = blocked {
+            println!("Rejected a login attempt from {:?}", req.peer_addr());
+            let res = req.into_response(too_many_requests(remaining));
+            return Box::pin(ready(Ok(res.map_into_right_body())));
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+    }
+}
+
+#[derive(Serialize)]
+struct ListElementLockout {
+    key: String,
+    #[serde(flatten)]
+    failures: Failures,
+    locked: bool,
+}
+
+#[actix_web::get("/admin/lockouts")]
+pub(crate) async fn list_lockouts(
+    data: web::Data<MyData>,
+    req: HttpRequest,
+) -> Result<web::Json<Vec<ListElementLockout>>> {
+    check_admin(&data, &req, "manage lockouts")?;
+    let limiter = data.rate_limiter.lock().unwrap();
+    let now = now();
+    let mut lockouts: Vec<_> = limiter
+        .entries
+        .iter()
+        .map(|(key, failures)| ListElementLockout {
+            key: key.clone(),
+            failures: failures.clone(),
+            locked: now < failures.blocked_until,
+        })
+        .collect();
+    lockouts.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(web::Json(lockouts))
+}
+
+#[actix_web::delete("/admin/lockouts")]
+pub(crate) async fn clear_lockouts(
+    data: web::Data<MyData>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    check_admin(&data, &req, "manage lockouts")?;
+    let conn = data.conn()?;
+    let mut limiter = data.rate_limiter.lock().unwrap();
+    conn.execute("DELETE FROM login_lockout", [])
+        .map_err(map_err)?;
+    limiter.entries.clear();
+    println!("Cleared all login lockouts");
+    Ok("Ok")
+}
+
+#[actix_web::delete("/admin/lockouts/{key:.*}")]
+pub(crate) async fn clear_lockout(
+    data: web::Data<MyData>,
+    key: web::Path<String>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    check_admin(&data, &req, "manage lockouts")?;
+    let conn = data.conn()?;
+    let mut limiter = data.rate_limiter.lock().unwrap();
+    conn.execute("DELETE FROM login_lockout WHERE key = ?1", [&*key])
+        .map_err(map_err)?;
+    if limiter.entries.remove(&*key).is_none() {
+        return Err(error::ErrorNotFound("Lockout not found"));
+    }
+    println!("Cleared login lockout {key}");
+    Ok("Ok")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: f64 = 1_700_000_000.;
+
+    fn setup() -> (RateLimiter, Connection) {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migration::migrate(&mut conn, Path::new("")).unwrap();
+        (RateLimiter::default(), conn)
+    }
+
+    fn keys() -> Vec<String> {
+        vec![ip_key([127, 0, 0, 1].into()), account_key("alice")]
+    }
+
+    #[test]
+    fn backoff_and_lockout() {
+        let (mut limiter, conn) = setup();
+        let keys = keys();
+        // Loading drops the lockouts that have ended by the real time.
+        let now = now();
+        for _ in 1..FREE_ATTEMPTS {
+            limiter.record_failure_at(&conn, &keys, now).unwrap();
+            assert_eq!(limiter.check_at(&keys, now), None);
+        }
+        let mut delay = BASE_DELAY_SECS;
+        for _ in FREE_ATTEMPTS..LOCKOUT_ATTEMPTS {
+            limiter.record_failure_at(&conn, &keys, now).unwrap();
+            assert_eq!(
+                limiter.check_at(&keys, now),
+                Some(delay.min(MAX_DELAY_SECS))
+            );
+            delay *= 2.;
+        }
+        let lockouts: usize = conn
+            .query_row("SELECT COUNT(*) FROM login_lockout", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(lockouts, 0);
+
+        limiter.record_failure_at(&conn, &keys, now).unwrap();
+        assert_eq!(limiter.check_at(&keys, now), Some(LOCKOUT_SECS));
+        assert_eq!(limiter.check_at(&keys, now + LOCKOUT_SECS), None);
+        let lockouts: usize = conn
+            .query_row("SELECT COUNT(*) FROM login_lockout", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(lockouts, keys.len());
+        let loaded = load_rate_limiter(&conn).unwrap();
+        assert_eq!(loaded.entries.len(), keys.len());
+    }
+
+    #[test]
+    fn failures_decay_after_quiet_window() {
+        let (mut limiter, conn) = setup();
+        let keys = keys();
+        for i in 0..LOCKOUT_ATTEMPTS - 1 {
+            // Spread out so that the delays never block the next attempt
+            let now = NOW + i as f64 * MAX_DELAY_SECS;
+            limiter.record_failure_at(&conn, &keys, now).unwrap();
+        }
+        let last = NOW + (LOCKOUT_ATTEMPTS - 2) as f64 * MAX_DELAY_SECS;
+        let later = last + FAILURE_WINDOW_SECS;
+        limiter.record_failure_at(&conn, &keys, later).unwrap();
+        assert_eq!(limiter.entries[&keys[0]].failures, 1);
+        assert_eq!(limiter.check_at(&keys, later), None);
+    }
+
+    #[test]
+    fn success_resets_only_target() {
+        let (mut limiter, conn) = setup();
+        let keys = keys();
+        limiter.record_failure_at(&conn, &keys, NOW).unwrap();
+        limiter.record_success(&keys[1]);
+        assert!(limiter.entries.contains_key(&keys[0]));
+        assert!(!limiter.entries.contains_key(&keys[1]));
+    }
+
+    #[test]
+    fn prune_stale_entries() {
+        let (mut limiter, conn) = setup();
+        let stale = vec![album_key(Path::new("old"))];
+        let recent = vec![album_key(Path::new("recent"))];
+        let locked = vec![account_key("locked")];
+        limiter.record_failure_at(&conn, &stale, NOW).unwrap();
+        for _ in 0..LOCKOUT_ATTEMPTS {
+            limiter.record_failure_at(&conn, &locked, NOW + 1.).unwrap();
+        }
+        let now = NOW + FAILURE_WINDOW_SECS;
+        limiter.record_failure_at(&conn, &recent, now).unwrap();
+
+        assert_eq!(limiter.prune_at(now), 1);
+        assert!(!limiter.entries.contains_key(&stale[0]));
+        assert!(limiter.entries.contains_key(&recent[0]));
+        assert!(limiter.entries.contains_key(&locked[0]));
+        assert_eq!(limiter.prune_at(now + LOCKOUT_SECS + 1.), 2);
+        assert!(limiter.entries.is_empty());
+    }
+}
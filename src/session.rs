@@ -13,33 +13,63 @@ use actix_web::{
     HttpRequest, HttpResponse,
 };
 
-use crate::{cache::CachePayload, MyData};
+use serde::Serialize;
+
+use crate::{
+    api_token::{bearer_token, is_token_session_key, token_session_key, Scope},
+    cache::CachePayload,
+    csrf::generate_token,
+    map_err,
+    rate_limit::{album_key, client_keys, LoginRateLimit},
+    totp::PendingLogin,
+    MyData,
+};
 
 #[derive(Debug)]
 pub(crate) struct Session {
     pub user_id: Option<usize>,
     /// Should we query this every time?
     pub is_admin: bool,
+    /// Albums unlocked by password in this session. An unlock applies to the album's subtree.
     pub auth_dirs: HashSet<PathBuf>,
+    /// The token to be sent back in the header of state-changing requests
+    pub csrf_token: String,
+    /// Set when the password is verified but the second factor is not yet
+    pub pending_login: Option<PendingLogin>,
+    /// The scopes of the API token of this session. `None` for browser sessions, which can do
+    /// anything the user can.
+    pub scopes: Option<HashSet<Scope>>,
 }
 
 impl Session {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             user_id: None,
             is_admin: false,
             auth_dirs: HashSet::new(),
+            csrf_token: generate_token(),
+            pending_login: None,
+            scopes: None,
         }
     }
 }
 
 pub(crate) type Sessions = HashMap<String, Session>;
 
+pub(crate) const SESSION_COOKIE: &str = "massPhotoSessionId";
+
+#[derive(Serialize)]
+struct CreateSessionResult<'a> {
+    csrf_token: &'a str,
+}
+
 #[actix_web::get("/sessions")]
 pub(crate) async fn create_session(data: web::Data<MyData>, req: HttpRequest) -> HttpResponse {
     let mut sessions = data.sessions.write().unwrap();
-    if find_session(&req, &sessions).is_some() {
-        return HttpResponse::Ok().body("Ok");
+    if let Some(session) = find_session(&req, &sessions) {
+        return HttpResponse::Ok().json(CreateSessionResult {
+            csrf_token: &session.csrf_token,
+        });
     }
     let next_id = sha256::digest(
         &std::time::SystemTime::now()
@@ -48,32 +78,46 @@ pub(crate) async fn create_session(data: web::Data<MyData>, req: HttpRequest) ->
             .as_nanos()
             .to_le_bytes(),
     );
-    sessions.insert(next_id.clone(), Session::new());
+    let session = Session::new();
 
-    let cookie = Cookie::build("massPhotoSessionId", next_id)
+    let cookie = Cookie::build(SESSION_COOKIE, next_id.clone())
         .path("/")
         .expires(OffsetDateTime::now_utc().checked_add(Duration::DAY * 10))
         .http_only(true)
         .same_site(SameSite::Strict)
-        .secure(false)
+        .secure(data.secure_cookies)
         .finish();
-    HttpResponse::Ok()
+    let response = HttpResponse::Ok()
         // .header("Set-Cookie", cookie.to_string())
         .cookie(cookie)
-        .body("Ok")
+        .json(CreateSessionResult {
+            csrf_token: &session.csrf_token,
+        });
+    sessions.insert(next_id, session);
+    response
+}
+
+/// The key of the session of the request in the sessions: the one of the API token if given,
+/// otherwise the session cookie
+pub(crate) fn session_key(req: &HttpRequest) -> Option<String> {
+    if let Some(token) = bearer_token(req.headers()) {
+        return Some(token_session_key(token));
+    }
+    // A cookie must not pick the session of an API token, which is only for the bearer.
+    req.cookie(SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|key| !is_token_session_key(key))
 }
 
 pub(crate) fn find_session<'a>(req: &HttpRequest, sessions: &'a Sessions) -> Option<&'a Session> {
-    req.cookie("massPhotoSessionId")
-        .and_then(|cookie| sessions.get(cookie.value()))
+    session_key(req).and_then(|key| sessions.get(&key))
 }
 
 pub(crate) fn find_session_mut<'a>(
     req: &HttpRequest,
     sessions: &'a mut Sessions,
 ) -> Option<&'a mut Session> {
-    req.cookie("massPhotoSessionId")
-        .and_then(|cookie| sessions.get_mut(cookie.value()))
+    session_key(req).and_then(|key| sessions.get_mut(&key))
 }
 
 /// A convenience function that maps absent session into an error
@@ -93,7 +137,48 @@ pub(crate) fn get_valid_session_mut<'a>(
         .ok_or_else(|| error::ErrorBadRequest("Session expired. Please reload the browser."))
 }
 
-#[actix_web::post("/albums/{file:.*}/auth")]
+/// Fails unless the session of the request is the admin's. `action` completes the message "Only
+/// the admin can ...".
+pub(crate) fn check_admin(data: &MyData, req: &HttpRequest, action: &str) -> actix_web::Result<()> {
+    admin_session(req, &data.sessions.read().unwrap(), action)?;
+    Ok(())
+}
+
+/// The same as [`check_admin`] on the sessions that the caller keeps locked, and returns the
+/// session.
+pub(crate) fn admin_session<'a>(
+    req: &HttpRequest,
+    sessions: &'a Sessions,
+    action: &str,
+) -> actix_web::Result<&'a Session> {
+    let session = get_valid_session(req, sessions)?;
+    if !session.is_admin {
+        return Err(error::ErrorForbidden(format!(
+            "Only the admin can {action}"
+        )));
+    }
+    Ok(session)
+}
+
+/// Remove the sessions logged in as the user or in the middle of logging in, except the one with
+/// the session id `keep`, and return how many were removed.
+pub(crate) fn remove_user_sessions(
+    sessions: &mut Sessions,
+    user_id: usize,
+    keep: Option<&str>,
+) -> usize {
+    let count = sessions.len();
+    sessions.retain(|id, session| {
+        let pending = session
+            .pending_login
+            .as_ref()
+            .map(|pending| pending.user_id);
+        (session.user_id != Some(user_id) && pending != Some(user_id)) || Some(id.as_str()) == keep
+    });
+    count - sessions.len()
+}
+
+#[actix_web::post("/albums/{file:.*}/auth", wrap = "LoginRateLimit")]
 pub(crate) async fn authorize_album(
     path: web::Path<PathBuf>,
     data: web::Data<MyData>,
@@ -110,19 +195,25 @@ pub(crate) async fn authorize_album(
     let password = String::from_utf8(bytes.to_vec())
         .map_err(|e| error::ErrorBadRequest(format!("Password needs to be a UTF-8 string: {e}")))?;
 
-    let cache = data.cache.lock().unwrap();
-    let entry = cache
-        .get(&*path)
-        .ok_or_else(|| error::ErrorNotFound("Directory not found"))?;
-    let CachePayload::Album(ref album) = entry.payload else {
-        return Err(error::ErrorBadRequest("File cannot be locked"));
-    };
-
-    if album.password_hash == sha256::digest(password) {
-        session.auth_dirs.insert(path.into_inner());
-    } else {
+    let cache = data.cache.read().unwrap();
+    let conn = data.conn()?;
+    let mut limiter = data.rate_limiter.lock().unwrap();
+    let target = album_key(&path);
+    let keys = client_keys(&req, target.clone());
+    limiter.check_attempt(&keys)?;
+
+    // Respond the same way whether the album exists or not, to avoid probing album paths.
+    let hash = sha256::digest(password);
+    if !matches!(
+        cache.get(&*path).map(|entry| &entry.payload),
+        Some(CachePayload::Album(album)) if album.password_hash == hash
+    ) {
+        limiter.record_failure(&conn, &keys).map_err(map_err)?;
         return Err(error::ErrorNotAcceptable("Incorrect Password"));
     }
 
+    limiter.record_success(&target);
+    session.auth_dirs.insert(path.into_inner());
+
     Ok("Ok".to_owned())
 }
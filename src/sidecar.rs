@@ -0,0 +1,295 @@
+//! Export and import of the metadata as JSON sidecar files.
+//!
+//! The descriptions, the owners and the locks of albums are only in the DB, so they are lost when
+//! the photos are moved to another place or tool. The export writes a hidden `.massphoto.json` to
+//! each album that has any metadata, and the import reads them back into the DB and the cache, so
+//! that the metadata travels with the photos.
+//!
+//! Owners are written by user names rather than ids, since the ids differ between DBs. Lock
+//! passwords are written as the hashes the DB has, not in plain text.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use actix_web::{web, HttpRequest, Result};
+use anyhow::Context;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{AlbumPayload, CacheEntry, CachePayload, FilePayload},
+    files::is_hidden,
+    map_err,
+    session::check_admin,
+    MyData,
+};
+
+pub(crate) const SIDECAR_FILE_NAME: &str = ".massphoto.json";
+
+/// The owner of an album without a row, and of an album whose owner is not found on import
+pub(crate) const ADMIN_USER_ID: usize = 1;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Sidecar {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    desc: Option<String>,
+    /// The user name of the owner
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+    /// The SHA-256 hash of the lock password. Empty or missing if not locked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    public: bool,
+    /// Metadata of the files in the album by their names
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    files: BTreeMap<String, FileMetadata>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    desc: Option<String>,
+}
+
+impl Sidecar {
+    fn is_empty(&self) -> bool {
+        self.desc.is_none()
+            && self.owner.is_none()
+            && self.password_hash.is_none()
+            && !self.public
+            && self.files.is_empty()
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct SidecarSummary {
+    /// Number of sidecar files written or read
+    albums: usize,
+    /// Number of files with metadata in them
+    files: usize,
+}
+
+/// Collect relative paths of the albums under `path`, including itself, recursively.
+fn collect_albums(root: &Path, path: &Path, albums: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    albums.push(path.to_owned());
+    for entry in std::fs::read_dir(root.join(path))? {
+        let rel_path = path.join(entry?.file_name());
+        let abs_path = root.join(&rel_path);
+        if !is_hidden(&abs_path) && abs_path.is_dir() {
+            collect_albums(root, &rel_path, albums)?;
+        }
+    }
+    Ok(())
+}
+
+fn user_names(conn: &Connection) -> rusqlite::Result<HashMap<usize, String>> {
+    let mut stmt = conn.prepare("SELECT id, name FROM user")?;
+    let names = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect();
+    names
+}
+
+/// Write a sidecar to every album under the album root that has any metadata.
+pub(crate) fn export_sidecars(data: &MyData) -> anyhow::Result<SidecarSummary> {
+    let root = &data.path;
+    let names = user_names(&*data.pool.get()?)?;
+    let mut albums = vec![];
+    collect_albums(root, Path::new(""), &mut albums)?;
+
+    let mut sidecars: BTreeMap<PathBuf, Sidecar> = BTreeMap::new();
+    {
+        let cache = data.cache.read().unwrap();
+        for album in &albums {
+            let mut sidecar = Sidecar::default();
+            if let Some(entry) = cache.get(album) {
+                if let CachePayload::Album(payload) = &entry.payload {
+                    sidecar.desc = entry.desc.clone();
+                    sidecar.owner = names.get(&payload.owner).cloned();
+                    sidecar.password_hash =
+                        (!payload.password_hash.is_empty()).then(|| payload.password_hash.clone());
+                    sidecar.public = payload.public;
+                }
+            }
+            sidecars.insert(album.clone(), sidecar);
+        }
+        for (path, entry) in cache.iter() {
+            let (CachePayload::File(_), Some(desc)) = (&entry.payload, &entry.desc) else {
+                continue;
+            };
+            let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                continue;
+            };
+            let (Some(sidecar), Some(name)) = (sidecars.get_mut(parent), name.to_str()) else {
+                continue;
+            };
+            if !desc.is_empty() && root.join(path).is_file() {
+                sidecar.files.insert(
+                    name.to_string(),
+                    FileMetadata {
+                        desc: Some(desc.clone()),
+                    },
+                );
+            }
+        }
+    }
+
+    let mut summary = SidecarSummary::default();
+    for (album, sidecar) in sidecars {
+        let dir = root.join(&album);
+        if sidecar.is_empty() {
+            // The metadata was removed since the last export.
+            let sidecar_path = dir.join(SIDECAR_FILE_NAME);
+            if sidecar_path.exists() {
+                std::fs::remove_file(sidecar_path)?;
+            }
+            continue;
+        }
+        // Write to a temporary file and rename, so that an interrupted export does not leave a
+        // broken sidecar.
+        let tmp_path = dir.join(format!("{SIDECAR_FILE_NAME}.tmp"));
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&sidecar)?)?;
+        std::fs::rename(&tmp_path, dir.join(SIDECAR_FILE_NAME))?;
+        summary.albums += 1;
+        summary.files += sidecar.files.len();
+    }
+    println!(
+        "Exported metadata of {} albums and {} files to sidecars",
+        summary.albums, summary.files
+    );
+    Ok(summary)
+}
+
+/// Read the sidecars under the album root into the DB and the cache. The metadata in a sidecar
+/// overwrites the one in the DB, but the metadata not in any sidecar is kept.
+pub(crate) fn import_sidecars(data: &MyData) -> anyhow::Result<SidecarSummary> {
+    let root = &data.path;
+    let mut albums = vec![];
+    collect_albums(root, Path::new(""), &mut albums)?;
+    let mut sidecars = vec![];
+    for album in albums {
+        let sidecar_path = root.join(&album).join(SIDECAR_FILE_NAME);
+        if !sidecar_path.exists() {
+            continue;
+        }
+        let text = std::fs::read_to_string(&sidecar_path)?;
+        let sidecar: Sidecar = serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse {sidecar_path:?}"))?;
+        sidecars.push((album, sidecar));
+    }
+
+    let mut conn = data.pool.get()?;
+    let ids: HashMap<String, usize> = user_names(&conn)?
+        .into_iter()
+        .map(|(id, name)| (name, id))
+        .collect();
+
+    // Write the DB first, and update the cache only after the transaction is committed, so that
+    // the cache never gets ahead of the DB.
+    let mut album_updates = vec![];
+    let mut file_updates = vec![];
+    let tx = conn.transaction()?;
+    for (album, sidecar) in sidecars {
+        let path_str = album.to_str();
+        let owner = match &sidecar.owner {
+            Some(name) => ids.get(name).copied().unwrap_or_else(|| {
+                println!("Owner {name:?} of album {album:?} is not found; the admin owns it");
+                ADMIN_USER_ID
+            }),
+            None => ADMIN_USER_ID,
+        };
+        let password_hash = sidecar.password_hash.unwrap_or_default();
+        tx.execute(
+            "INSERT INTO album (path, desc, password, owner, public) VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT(path) DO UPDATE SET desc = ?2, password = ?3, owner = ?4, public = ?5",
+            params![path_str, sidecar.desc, password_hash, owner, sidecar.public],
+        )?;
+        album_updates.push((
+            album.clone(),
+            sidecar.desc,
+            AlbumPayload {
+                password_hash,
+                owner,
+                public: sidecar.public,
+            },
+        ));
+
+        for (name, metadata) in sidecar.files {
+            // Only a file directly in the album, so that a sidecar cannot touch other albums.
+            let path = album.join(&name);
+            if Path::new(&name).file_name() != Some(OsStr::new(&name))
+                || is_hidden(&path)
+                || !root.join(&path).is_file()
+            {
+                println!("{path:?} in the sidecar of {album:?} is not found; skipped");
+                continue;
+            }
+            // A new row has no thumbnail yet; modified = 0 makes it outdated.
+            tx.execute(
+                "INSERT INTO file (path, modified, desc) VALUES (?1, 0, ?2)
+                    ON CONFLICT(path) DO UPDATE SET desc = ?2",
+                params![path.to_str(), metadata.desc],
+            )?;
+            file_updates.push((path, metadata.desc));
+        }
+    }
+    tx.commit()?;
+
+    let summary = SidecarSummary {
+        albums: album_updates.len(),
+        files: file_updates.len(),
+    };
+    let mut cache = data.cache.write().unwrap();
+    for (path, desc, payload) in album_updates {
+        let entry = cache
+            .entry(path)
+            .or_insert_with(|| CacheEntry::album_with_owner(payload.owner));
+        entry.new = false;
+        entry.desc = desc;
+        entry.payload = CachePayload::Album(payload);
+    }
+    for (path, desc) in file_updates {
+        cache
+            .entry(path)
+            .or_insert_with(|| CacheEntry {
+                new: false,
+                modified: 0.,
+                desc: None,
+                payload: CachePayload::File(FilePayload { data: vec![] }),
+            })
+            .desc = desc;
+    }
+    println!(
+        "Imported metadata of {} albums and {} files from sidecars",
+        summary.albums, summary.files
+    );
+    Ok(summary)
+}
+
+#[actix_web::post("/admin/sidecars/export")]
+pub(crate) async fn export_metadata(
+    data: web::Data<MyData>,
+    req: HttpRequest,
+) -> Result<web::Json<SidecarSummary>> {
+    check_admin(&data, &req, "export or import metadata")?;
+    let summary = web::block(move || export_sidecars(&data))
+        .await?
+        .map_err(map_err)?;
+    Ok(web::Json(summary))
+}
+
+#[actix_web::post("/admin/sidecars/import")]
+pub(crate) async fn import_metadata(
+    data: web::Data<MyData>,
+    req: HttpRequest,
+) -> Result<web::Json<SidecarSummary>> {
+    check_admin(&data, &req, "export or import metadata")?;
+    let summary = web::block(move || import_sidecars(&data))
+        .await?
+        .map_err(map_err)?;
+    Ok(web::Json(summary))
+}
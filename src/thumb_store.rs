@@ -0,0 +1,332 @@
+//! Storage of thumbnail bytes.
+//!
+//! The metadata of the files (modified date and description) is always in the `file` table, but
+//! the thumbnails can be either in its `data` column or in a directory on disk, selected by the
+//! `thumbnail_store` config. `massphoto thumbs migrate` moves the thumbnails between them.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ArgEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ThumbnailStoreKind {
+    /// In the `data` column of the `file` table
+    Sqlite,
+    /// In a directory on disk, named by the hash of the content
+    Disk,
+}
+
+impl std::fmt::Display for ThumbnailStoreKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sqlite => write!(f, "sqlite"),
+            Self::Disk => write!(f, "disk"),
+        }
+    }
+}
+
+/// A storage of thumbnails. Each method takes a connection because a store may keep the mapping
+/// from the paths to the thumbnails in the DB. The `file` row of the path shall exist before
+/// [`ThumbnailStore::save`] is called.
+pub(crate) trait ThumbnailStore: Send + Sync {
+    fn load(&self, conn: &Connection, path: &Path) -> anyhow::Result<Option<Vec<u8>>>;
+
+    fn save(&self, conn: &Connection, path: &Path, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Delete all the thumbnails
+    fn clear(&self, conn: &Connection) -> anyhow::Result<()>;
+
+    /// Delete the thumbnails that no file refers to, and return the number of them. The files
+    /// that the store did not make are left alone.
+    fn gc(&self, _conn: &Connection) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+}
+
+pub(crate) fn new_store(kind: ThumbnailStoreKind, dir: &Path) -> Box<dyn ThumbnailStore> {
+    match kind {
+        ThumbnailStoreKind::Sqlite => Box::new(SqliteStore),
+        ThumbnailStoreKind::Disk => Box::new(DiskStore {
+            dir: dir.to_owned(),
+        }),
+    }
+}
+
+pub(crate) struct SqliteStore;
+
+impl ThumbnailStore for SqliteStore {
+    fn load(&self, conn: &Connection, path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+        let data: Option<Option<Vec<u8>>> = conn
+            .query_row(
+                "SELECT data FROM file WHERE path = ?1",
+                [path.to_str()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(data.flatten().filter(|data| !data.is_empty()))
+    }
+
+    fn save(&self, conn: &Connection, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+        conn.execute(
+            "UPDATE file SET data = ?2, thumb = NULL WHERE path = ?1",
+            params![path.to_str(), data],
+        )?;
+        Ok(())
+    }
+
+    fn clear(&self, conn: &Connection) -> anyhow::Result<()> {
+        conn.execute("UPDATE file SET data = NULL", [])?;
+        Ok(())
+    }
+}
+
+/// Thumbnails in files named by the SHA-256 of their content, so that identical thumbnails are
+/// stored once. The `thumb` column of the `file` table has the hash.
+pub(crate) struct DiskStore {
+    dir: PathBuf,
+}
+
+impl DiskStore {
+    fn file_path(&self, hash: &str) -> PathBuf {
+        // Split into subdirectories to avoid too many files in a directory
+        self.dir.join(&hash[..2]).join(hash)
+    }
+
+    /// The shard directories. Anything else in the directory is skipped, since `thumbnail_dir`
+    /// may point to a directory shared with other files.
+    fn shards(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut shards = vec![];
+        if !self.dir.exists() {
+            return Ok(shards);
+        }
+        for shard in std::fs::read_dir(&self.dir)? {
+            let shard = shard?;
+            if shard.file_type()?.is_dir() && shard.file_name().to_str().is_some_and(is_shard_name)
+            {
+                shards.push(shard.path());
+            }
+        }
+        Ok(shards)
+    }
+
+    /// The thumbnail files in the shards, as `(hash, path)`. The other files, including the
+    /// temporary files of the saves in progress, are skipped.
+    fn stored_files(&self) -> anyhow::Result<Vec<(String, PathBuf)>> {
+        let mut files = vec![];
+        for shard in self.shards()? {
+            for file in std::fs::read_dir(&shard)? {
+                let file = file?;
+                let Some(name) = file.file_name().to_str().map(str::to_owned) else {
+                    continue;
+                };
+                if file.file_type()?.is_file() && is_hash_name(&name) && shard.ends_with(&name[..2])
+                {
+                    files.push((name, file.path()));
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+fn is_shard_name(name: &str) -> bool {
+    name.len() == 2 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn is_hash_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+impl ThumbnailStore for DiskStore {
+    fn load(&self, conn: &Connection, path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+        let hash: Option<Option<String>> = conn
+            .query_row(
+                "SELECT thumb FROM file WHERE path = ?1",
+                [path.to_str()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(hash) = hash.flatten() else {
+            return Ok(None);
+        };
+        match std::fs::read(self.file_path(&hash)) {
+            Ok(data) => Ok(Some(data)),
+            // Deleted from outside; it will be made again.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, conn: &Connection, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+        let hash = sha256::digest(data);
+        let file_path = self.file_path(&hash);
+        if !file_path.exists() {
+            let parent = file_path.parent().expect("The file path has a parent");
+            std::fs::create_dir_all(parent)?;
+            // Write to a temporary file and rename, so that a reader never sees a partial file.
+            // The name is unique so that concurrent saves of the same thumbnail do not write to
+            // the same file.
+            let tmp_path = parent.join(format!("{hash}.{:016x}.tmp", rand::random::<u64>()));
+            let res = std::fs::write(&tmp_path, data)
+                .and_then(|_| std::fs::rename(&tmp_path, &file_path));
+            if let Err(e) = res {
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(e.into());
+            }
+        }
+        conn.execute(
+            "UPDATE file SET thumb = ?2, data = NULL WHERE path = ?1",
+            params![path.to_str(), hash],
+        )?;
+        Ok(())
+    }
+
+    fn clear(&self, conn: &Connection) -> anyhow::Result<()> {
+        conn.execute("UPDATE file SET thumb = NULL", [])?;
+        for (_, path) in self.stored_files()? {
+            std::fs::remove_file(path)?;
+        }
+        for shard in self.shards()? {
+            // Keep the shards that still have other files, such as the saves in progress.
+            let _ = std::fs::remove_dir(shard);
+        }
+        Ok(())
+    }
+
+    fn gc(&self, conn: &Connection) -> anyhow::Result<usize> {
+        let mut stmt = conn.prepare("SELECT DISTINCT thumb FROM file WHERE thumb IS NOT NULL")?;
+        let referenced = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<HashSet<_>>>()?;
+        let mut removed = 0;
+        for (hash, path) in self.stored_files()? {
+            if !referenced.contains(&hash) {
+                std::fs::remove_file(path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Move all the thumbnails from `from` to `to`, and return the number of them. The thumbnails are
+/// deleted from `from` only after the transaction is committed, so that a failure never leaves
+/// them in neither store.
+pub(crate) fn migrate(
+    conn: &mut Connection,
+    from: &dyn ThumbnailStore,
+    to: &dyn ThumbnailStore,
+) -> anyhow::Result<usize> {
+    let tx = conn.transaction()?;
+    let paths = tx
+        .prepare("SELECT path FROM file")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut count = 0;
+    for path in paths {
+        let path = Path::new(&path);
+        if let Some(data) = from.load(&tx, path)? {
+            to.save(&tx, path, &data)?;
+            count += 1;
+        }
+    }
+    tx.commit()?;
+    // Saving to `to` dropped the references to `from`, so its thumbnails are garbage now.
+    from.gc(conn)?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (tempfile::TempDir, Connection) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migration::migrate(&mut conn, Path::new("")).unwrap();
+        for path in ["a.jpg", "b.jpg"] {
+            conn.execute("INSERT INTO file (path, modified) VALUES (?1, 1)", [path])
+                .unwrap();
+        }
+        (dir, conn)
+    }
+
+    #[test]
+    fn clear_keeps_foreign_files() {
+        let (dir, conn) = setup();
+        let store = DiskStore {
+            dir: dir.path().to_owned(),
+        };
+        store.save(&conn, Path::new("a.jpg"), b"thumb").unwrap();
+        let hash = sha256::digest(&b"thumb"[..]);
+        let shard = dir.path().join(&hash[..2]);
+        std::fs::write(dir.path().join("photo.jpg"), b"photo").unwrap();
+        std::fs::create_dir(dir.path().join("album")).unwrap();
+        std::fs::write(shard.join("notes.txt"), b"notes").unwrap();
+
+        store.clear(&conn).unwrap();
+        assert!(!shard.join(&hash).exists());
+        assert!(shard.join("notes.txt").exists());
+        assert!(dir.path().join("photo.jpg").exists());
+        assert!(dir.path().join("album").exists());
+        assert_eq!(store.load(&conn, Path::new("a.jpg")).unwrap(), None);
+    }
+
+    #[test]
+    fn gc_skips_stray_and_temporary_files() {
+        let (dir, conn) = setup();
+        let store = DiskStore {
+            dir: dir.path().to_owned(),
+        };
+        store.save(&conn, Path::new("a.jpg"), b"kept").unwrap();
+        store.save(&conn, Path::new("b.jpg"), b"old").unwrap();
+        store.save(&conn, Path::new("b.jpg"), b"new").unwrap();
+        let old = sha256::digest(&b"old"[..]);
+        let tmp = dir.path().join(&old[..2]).join(format!("{old}.0123.tmp"));
+        std::fs::write(&tmp, b"in progress").unwrap();
+        std::fs::write(dir.path().join("stray"), b"stray").unwrap();
+
+        assert_eq!(store.gc(&conn).unwrap(), 1);
+        assert!(!store.file_path(&old).exists());
+        assert!(tmp.exists());
+        assert!(dir.path().join("stray").exists());
+        assert_eq!(
+            store.load(&conn, Path::new("a.jpg")).unwrap().as_deref(),
+            Some(&b"kept"[..])
+        );
+    }
+
+    #[test]
+    fn migrate_both_ways() {
+        let (dir, mut conn) = setup();
+        let disk = DiskStore {
+            dir: dir.path().to_owned(),
+        };
+        SqliteStore
+            .save(&conn, Path::new("a.jpg"), b"thumb")
+            .unwrap();
+
+        assert_eq!(migrate(&mut conn, &SqliteStore, &disk).unwrap(), 1);
+        assert_eq!(SqliteStore.load(&conn, Path::new("a.jpg")).unwrap(), None);
+        assert_eq!(
+            disk.load(&conn, Path::new("a.jpg")).unwrap().as_deref(),
+            Some(&b"thumb"[..])
+        );
+
+        assert_eq!(migrate(&mut conn, &disk, &SqliteStore).unwrap(), 1);
+        assert_eq!(disk.load(&conn, Path::new("a.jpg")).unwrap(), None);
+        assert!(disk.stored_files().unwrap().is_empty());
+        assert_eq!(
+            SqliteStore
+                .load(&conn, Path::new("a.jpg"))
+                .unwrap()
+                .as_deref(),
+            Some(&b"thumb"[..])
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Built-in HTTPS serving with rustls.
+//!
+//! The certificate is resolved on every handshake through [`CertResolver`], so that it can be
+//! replaced without restarting the server. It is reloaded on SIGHUP, or when the certificate or
+//! the key file is modified.
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{dev::Server, http::header, web, App, HttpRequest, HttpResponse, HttpServer};
+use anyhow::{anyhow, Context};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{any_supported_type, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+
+/// How often the certificate files are checked for modification
+const WATCH_PERIOD: Duration = Duration::from_secs(60);
+
+pub(crate) struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    certified_key: RwLock<Arc<CertifiedKey>>,
+    /// The latest modified time of the certificate and the key files when loaded
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl CertResolver {
+    pub(crate) fn new(cert_path: &Path, key_path: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            certified_key: RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?)),
+            modified: Mutex::new(modified(cert_path, key_path)),
+        })
+    }
+
+    /// Reload the certificate. On failure, the current certificate is kept.
+    pub(crate) fn reload(&self) -> anyhow::Result<()> {
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.certified_key.write().unwrap() = Arc::new(certified_key);
+        *self.modified.lock().unwrap() = modified(&self.cert_path, &self.key_path);
+        println!("Reloaded TLS certificate {:?}", self.cert_path);
+        Ok(())
+    }
+
+    fn reload_if_modified(&self) -> anyhow::Result<()> {
+        let current = modified(&self.cert_path, &self.key_path);
+        if current.is_some() && current != *self.modified.lock().unwrap() {
+            self.reload()?;
+        }
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.certified_key.read().unwrap().clone())
+    }
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+    let modified = |path| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    };
+    modified(cert_path).max(modified(key_path))
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> anyhow::Result<CertifiedKey> {
+    let mut reader = BufReader::new(
+        File::open(cert_path)
+            .with_context(|| format!("Failed to open the certificate {cert_path:?}"))?,
+    );
+    let certs: Vec<_> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {cert_path:?}"));
+    }
+
+    let mut reader = BufReader::new(
+        File::open(key_path)
+            .with_context(|| format!("Failed to open the private key {key_path:?}"))?,
+    );
+    let key = std::iter::from_fn(|| rustls_pemfile::read_one(&mut reader).transpose())
+        .find_map(|item| match item {
+            Ok(rustls_pemfile::Item::PKCS8Key(key))
+            | Ok(rustls_pemfile::Item::RSAKey(key))
+            | Ok(rustls_pemfile::Item::ECKey(key)) => Some(Ok(PrivateKey(key))),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+        .ok_or_else(|| anyhow!("No private key found in {key_path:?}"))??;
+    let signing_key = any_supported_type(&key)
+        .map_err(|_| anyhow!("Unsupported private key type in {key_path:?}"))?;
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+pub(crate) fn server_config(resolver: Arc<CertResolver>) -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver)
+}
+
+/// Reload the certificate when the files are modified or the process receives SIGHUP.
+pub(crate) async fn watch_certificate(resolver: Arc<CertResolver>) {
+    #[cfg(unix)]
+    {
+        use actix_rt::signal::unix::{signal, SignalKind};
+        let resolver = resolver.clone();
+        match signal(SignalKind::hangup()) {
+            Ok(mut hangup) => {
+                actix_rt::spawn(async move {
+                    while hangup.recv().await.is_some() {
+                        if let Err(e) = resolver.reload() {
+                            println!("Error in reloading TLS certificate: {e:#}");
+                        }
+                    }
+                });
+            }
+            Err(e) => println!("Failed to listen to SIGHUP: {e}"),
+        }
+    }
+
+    let mut interval = actix_rt::time::interval(WATCH_PERIOD);
+    loop {
+        interval.tick().await;
+        if let Err(e) = resolver.reload_if_modified() {
+            println!("Error in reloading TLS certificate: {e:#}");
+        }
+    }
+}
+
+/// A plain HTTP server that redirects every request to the HTTPS server on `https_port`.
+pub(crate) fn redirect_server(host: &str, port: u16, https_port: u16) -> std::io::Result<Server> {
+    Ok(HttpServer::new(move || {
+        App::new().default_service(web::to(move |req: HttpRequest| async move {
+            let conn = req.connection_info();
+            let host = conn.host();
+            // Strip the port of the plain HTTP server, if any.
+            let host = host
+                .rsplit_once(':')
+                .filter(|(_, port)| port.bytes().all(|b| b.is_ascii_digit()))
+                .map(|(host, _)| host)
+                .unwrap_or(host);
+            let location = if https_port == 443 {
+                format!("https://{host}{}", req.uri())
+            } else {
+                format!("https://{host}:{https_port}{}", req.uri())
+            };
+            HttpResponse::PermanentRedirect()
+                .insert_header((header::LOCATION, location))
+                .finish()
+        }))
+    })
+    .bind((host, port))?
+    .run())
+}
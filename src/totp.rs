@@ -0,0 +1,690 @@
+//! TOTP (RFC 6238) two-factor authentication.
+//!
+//! A user enrolls by `POST /totp/enroll`, which returns a secret and an `otpauth://` URI to show as
+//! a QR code to an authenticator app, and confirms it by `POST /totp/confirm` with a code from the
+//! app. The confirmation returns recovery codes, each of which can be used once instead of a code
+//! when the app is lost. Only the hashes of the recovery codes are stored.
+//!
+//! Once enabled, the correct password in `POST /users/login` only marks the session as pending,
+//! and `POST /users/login/totp` with a code completes the login.
+//!
+//! Unlike the passwords, the TOTP secrets cannot be hashed, since the server needs them to compute
+//! the codes. They are encrypted with a key kept in [`TOTP_KEY_FILE_NAME`] instead of the DB, so
+//! that a copy of the DB such as a backup does not give them away. The secrets stored in plain
+//! text by older versions are encrypted on startup.
+
+use std::{
+    io::Write,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{error, web, HttpRequest, Result};
+use anyhow::{anyhow, bail, Context};
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::{
+    map_err,
+    rate_limit::{account_key, client_keys, LoginRateLimit},
+    session::{check_admin, get_valid_session, get_valid_session_mut},
+    user::user_exists,
+    MyData,
+};
+
+/// The issuer shown in authenticator apps
+const ISSUER: &str = "Massphoto";
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+/// Number of steps before and after the current one to accept, to allow for clock drift
+const SKEW_STEPS: u64 = 1;
+/// In bytes, the length of the HMAC-SHA1 output as RFC 4226 recommends
+const SECRET_LEN: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+/// How long the password step of a login stays valid without the code
+pub(crate) const PENDING_LOGIN_TTL: Duration = Duration::from_secs(5 * 60);
+/// The file of the key that encrypts the TOTP secrets, in the album root next to the DB. It is
+/// hidden, so it is never served.
+pub(crate) const TOTP_KEY_FILE_NAME: &str = ".totp.key";
+/// Marks an encrypted secret in the `secret` column of the `totp` table. The secrets without it
+/// were stored in plain text base32 by older versions.
+const ENCRYPTED_PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 12;
+
+/// The key to encrypt the TOTP secrets in the DB
+pub(crate) struct TotpKey(Key);
+
+impl TotpKey {
+    /// Read the key from the file, or make a new one if the file does not exist.
+    pub(crate) fn load_or_create(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read(path) {
+            Ok(bytes) if bytes.len() == 32 => return Ok(Self(*Key::from_slice(&bytes))),
+            Ok(_) => bail!("The TOTP key file {path:?} is broken"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read the TOTP key {path:?}"))
+            }
+        }
+        let mut key = Key::default();
+        rand::thread_rng().fill_bytes(&mut key);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)
+            .and_then(|mut file| file.write_all(&key))
+            .with_context(|| format!("Failed to write the TOTP key {path:?}"))?;
+        println!("Created the TOTP key {path:?}");
+        Ok(Self(key))
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        // Not imported, since the HMAC of the codes has a method of the same name.
+        <ChaCha20Poly1305 as chacha20poly1305::KeyInit>::new(&self.0)
+    }
+
+    /// Encrypt the secret for the `secret` column. The user ID is authenticated with it, so that
+    /// the secret of a user cannot be copied to another user.
+    fn encrypt(&self, user_id: usize, secret: &[u8]) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let aad = (user_id as u64).to_le_bytes();
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: secret,
+                    aad: &aad,
+                },
+            )
+            .expect("Encryption does not fail with a short message");
+        let mut bytes = nonce.to_vec();
+        bytes.extend(ciphertext);
+        format!("{ENCRYPTED_PREFIX}{}", BASE32_NOPAD.encode(&bytes))
+    }
+
+    /// Decrypt the `secret` column, which may still be in plain text.
+    fn decrypt(&self, user_id: usize, stored: &str) -> anyhow::Result<Vec<u8>> {
+        let Some(encrypted) = stored.strip_prefix(ENCRYPTED_PREFIX) else {
+            return Ok(BASE32_NOPAD.decode(stored.as_bytes())?);
+        };
+        let bytes = BASE32_NOPAD.decode(encrypted.as_bytes())?;
+        if bytes.len() < NONCE_LEN {
+            bail!("The TOTP secret of user {user_id} is broken");
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let aad = (user_id as u64).to_le_bytes();
+        self.cipher()
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| {
+                anyhow!("Failed to decrypt the TOTP secret of user {user_id}. Is the TOTP key file the one it was made with?")
+            })
+    }
+}
+
+/// Encrypt the secrets stored in plain text by older versions, and return the number of them.
+pub(crate) fn encrypt_plain_secrets(conn: &mut Connection, key: &TotpKey) -> anyhow::Result<usize> {
+    let tx = conn.transaction()?;
+    let plain = tx
+        .prepare("SELECT user_id, secret FROM totp WHERE secret NOT LIKE 'enc:%'")?
+        .query_map([], |row| {
+            Ok((row.get::<_, usize>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (user_id, secret) in &plain {
+        let secret = BASE32_NOPAD.decode(secret.as_bytes())?;
+        tx.execute(
+            "UPDATE totp SET secret = ?1 WHERE user_id = ?2",
+            params![key.encrypt(*user_id, &secret), user_id],
+        )?;
+    }
+    tx.commit()?;
+    Ok(plain.len())
+}
+
+/// A login whose password is verified, waiting for the second factor
+#[derive(Debug)]
+pub(crate) struct PendingLogin {
+    pub user_id: usize,
+    pub started: Instant,
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes a key of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // Dynamic truncation in RFC 4226
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    code % 10u32.pow(DIGITS)
+}
+
+fn current_step() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time always exist since UNIX_EPOCH")
+        .as_secs()
+        / STEP_SECS
+}
+
+/// Returns the step of the code if it matches a step around now. A step not later than
+/// `last_step` is rejected, so that a code cannot be used twice.
+fn verify_code(secret: &[u8], code: &str, last_step: Option<u64>) -> Option<u64> {
+    verify_code_at(secret, code, last_step, current_step())
+}
+
+fn verify_code_at(secret: &[u8], code: &str, last_step: Option<u64>, now: u64) -> Option<u64> {
+    let code = code.trim();
+    // `parse` alone would accept a sign.
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    (now.saturating_sub(SKEW_STEPS)..=now + SKEW_STEPS)
+        .filter(|step| last_step < Some(*step))
+        .find(|step| hotp(secret, *step) == code)
+}
+
+/// Recovery codes are compared ignoring the case and the separators.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    sha256::digest(normalized)
+}
+
+fn generate_recovery_code() -> String {
+    let chars: Vec<char> = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .map(|b| char::from(b).to_ascii_lowercase())
+        .collect();
+    format!(
+        "{}-{}",
+        chars[..5].iter().collect::<String>(),
+        chars[5..].iter().collect::<String>()
+    )
+}
+
+/// Encode a component of the provisioning URI.
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(b).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+struct TotpRow {
+    secret: Vec<u8>,
+    enabled: bool,
+    last_step: Option<u64>,
+}
+
+fn load_totp(conn: &Connection, key: &TotpKey, user_id: usize) -> anyhow::Result<Option<TotpRow>> {
+    let row: Option<(String, bool, Option<u64>)> = conn
+        .query_row(
+            "SELECT secret, enabled, last_step FROM totp WHERE user_id = ?1",
+            [user_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+    let Some((secret, enabled, last_step)) = row else {
+        return Ok(None);
+    };
+    Ok(Some(TotpRow {
+        secret: key.decrypt(user_id, &secret)?,
+        enabled,
+        last_step,
+    }))
+}
+
+pub(crate) fn totp_enabled(conn: &Connection, user_id: usize) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM totp WHERE user_id = ?1 AND enabled)",
+        [user_id],
+        |row| row.get(0),
+    )
+}
+
+/// Remove the second factor of the user, including an unconfirmed enrollment.
+pub(crate) fn remove_totp(conn: &Connection, user_id: usize) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM totp WHERE user_id = ?1", [user_id])?;
+    conn.execute("DELETE FROM totp_recovery WHERE user_id = ?1", [user_id])?;
+    Ok(())
+}
+
+/// Verify a TOTP code or a recovery code of the user. A used recovery code is removed, and the
+/// step of a used TOTP code is recorded.
+fn verify_second_factor(
+    conn: &Connection,
+    key: &TotpKey,
+    user_id: usize,
+    code: &str,
+) -> anyhow::Result<bool> {
+    let Some(row) = load_totp(conn, key, user_id)?.filter(|row| row.enabled) else {
+        return Ok(false);
+    };
+    if let Some(step) = verify_code(&row.secret, code, row.last_step) {
+        conn.execute(
+            "UPDATE totp SET last_step = ?1 WHERE user_id = ?2",
+            params![step, user_id],
+        )?;
+        return Ok(true);
+    }
+    let used = conn.execute(
+        "DELETE FROM totp_recovery WHERE user_id = ?1 AND code_hash = ?2",
+        params![user_id, hash_recovery_code(code)],
+    )?;
+    if 0 < used {
+        println!("User {user_id} used a recovery code");
+    }
+    Ok(0 < used)
+}
+
+/// Verify the second factor with the same brute-force protection as the passwords of logins.
+fn check_second_factor(
+    data: &MyData,
+    req: &HttpRequest,
+    conn: &Connection,
+    user_id: usize,
+    code: &str,
+) -> Result<()> {
+    let name: String = conn
+        .query_row("SELECT name FROM user WHERE id = ?1", [user_id], |row| {
+            row.get(0)
+        })
+        .map_err(map_err)?;
+    let mut limiter = data.rate_limiter.lock().unwrap();
+    let target = account_key(&name);
+    let keys = client_keys(req, target.clone());
+    limiter.check_attempt(&keys)?;
+    if !verify_second_factor(conn, &data.totp_key, user_id, code).map_err(map_err)? {
+        limiter.record_failure(conn, &keys).map_err(map_err)?;
+        return Err(error::ErrorNotAcceptable("Incorrect code"));
+    }
+    limiter.record_success(&target);
+    Ok(())
+}
+
+fn logged_in_user(data: &MyData, req: &HttpRequest) -> Result<usize> {
+    let sessions = data.sessions.read().unwrap();
+    get_valid_session(req, &sessions)?
+        .user_id
+        .ok_or_else(|| error::ErrorBadRequest("Please login first"))
+}
+
+#[derive(Serialize)]
+struct TotpStatusResult {
+    enabled: bool,
+    recovery_codes_left: usize,
+}
+
+#[actix_web::get("/totp")]
+pub(crate) async fn get_totp_status(
+    data: web::Data<MyData>,
+    req: HttpRequest,
+) -> Result<web::Json<TotpStatusResult>> {
+    let user_id = logged_in_user(&data, &req)?;
+    let conn = data.conn()?;
+    let enabled = totp_enabled(&conn, user_id).map_err(map_err)?;
+    let recovery_codes_left = conn
+        .query_row(
+            "SELECT COUNT(*) FROM totp_recovery WHERE user_id = ?1",
+            [user_id],
+            |row| row.get(0),
+        )
+        .map_err(map_err)?;
+    Ok(web::Json(TotpStatusResult {
+        enabled,
+        recovery_codes_left,
+    }))
+}
+
+#[derive(Serialize)]
+struct EnrollResult {
+    /// The secret in base32, for apps that cannot read the QR code
+    secret: String,
+    /// The provisioning URI to show as a QR code
+    uri: String,
+}
+
+/// Starts an enrollment with a new secret. It is not required on login until confirmed.
+#[actix_web::post("/totp/enroll")]
+pub(crate) async fn enroll_totp(
+    data: web::Data<MyData>,
+    req: HttpRequest,
+) -> Result<web::Json<EnrollResult>> {
+    let user_id = logged_in_user(&data, &req)?;
+    let conn = data.conn()?;
+    if totp_enabled(&conn, user_id).map_err(map_err)? {
+        return Err(error::ErrorBadRequest(
+            "Two-factor authentication is already enabled. Disable it first.",
+        ));
+    }
+    let name: String = conn
+        .query_row("SELECT name FROM user WHERE id = ?1", [user_id], |row| {
+            row.get(0)
+        })
+        .map_err(map_err)?;
+    let mut secret = [0u8; SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut secret);
+    conn.execute(
+        "INSERT OR REPLACE INTO totp (user_id, secret, enabled, last_step) VALUES (?1, ?2, FALSE, NULL)",
+        params![user_id, data.totp_key.encrypt(user_id, &secret)],
+    )
+    .map_err(map_err)?;
+    let secret = BASE32_NOPAD.encode(&secret);
+    let uri = format!(
+        "otpauth://totp/{issuer}:{name}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}",
+        issuer = percent_encode(ISSUER),
+        name = percent_encode(&name),
+    );
+    Ok(web::Json(EnrollResult { secret, uri }))
+}
+
+#[derive(Deserialize)]
+struct CodeParams {
+    code: String,
+}
+
+#[derive(Serialize)]
+struct ConfirmResult {
+    /// Shown only once
+    recovery_codes: Vec<String>,
+}
+
+/// Enables the enrolled secret with a code from the app, and returns new recovery codes.
+#[actix_web::post("/totp/confirm")]
+pub(crate) async fn confirm_totp(
+    data: web::Data<MyData>,
+    params: web::Json<CodeParams>,
+    req: HttpRequest,
+) -> Result<web::Json<ConfirmResult>> {
+    let user_id = logged_in_user(&data, &req)?;
+    let mut conn = data.conn()?;
+    let Some(row) = load_totp(&conn, &data.totp_key, user_id)
+        .map_err(map_err)?
+        .filter(|row| !row.enabled)
+    else {
+        return Err(error::ErrorBadRequest("Start an enrollment first"));
+    };
+    let Some(step) = verify_code(&row.secret, &params.code, None) else {
+        return Err(error::ErrorNotAcceptable("Incorrect code"));
+    };
+
+    let recovery_codes: Vec<_> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+    let tx = conn.transaction().map_err(map_err)?;
+    tx.execute(
+        "UPDATE totp SET enabled = TRUE, last_step = ?1 WHERE user_id = ?2",
+        params![step, user_id],
+    )
+    .map_err(map_err)?;
+    tx.execute("DELETE FROM totp_recovery WHERE user_id = ?1", [user_id])
+        .map_err(map_err)?;
+    for code in &recovery_codes {
+        tx.execute(
+            "INSERT INTO totp_recovery (user_id, code_hash) VALUES (?1, ?2)",
+            params![user_id, hash_recovery_code(code)],
+        )
+        .map_err(map_err)?;
+    }
+    tx.commit().map_err(map_err)?;
+    println!("User {user_id} enabled two-factor authentication");
+    Ok(web::Json(ConfirmResult { recovery_codes }))
+}
+
+/// Disables the second factor of the logged in user, which requires a code or a recovery code.
+#[actix_web::post("/totp/disable", wrap = "LoginRateLimit")]
+pub(crate) async fn disable_totp(
+    data: web::Data<MyData>,
+    params: web::Json<CodeParams>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    let user_id = logged_in_user(&data, &req)?;
+    let conn = data.conn()?;
+    if !totp_enabled(&conn, user_id).map_err(map_err)? {
+        return Err(error::ErrorBadRequest(
+            "Two-factor authentication is not enabled",
+        ));
+    }
+    check_second_factor(&data, &req, &conn, user_id, &params.code)?;
+    remove_totp(&conn, user_id).map_err(map_err)?;
+    println!("User {user_id} disabled two-factor authentication");
+    Ok("Ok")
+}
+
+/// Removes the second factor of a user who lost both the app and the recovery codes.
+#[actix_web::delete("/users/{id}/totp")]
+pub(crate) async fn reset_totp(
+    data: web::Data<MyData>,
+    id: web::Path<usize>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    check_admin(&data, &req, "reset two-factor authentication")?;
+    let conn = data.conn()?;
+    if !user_exists(&conn, *id).map_err(map_err)? {
+        return Err(error::ErrorNotFound("User not found"));
+    }
+    remove_totp(&conn, *id).map_err(map_err)?;
+    println!("Reset two-factor authentication of user {id}");
+    Ok("Ok")
+}
+
+/// The second step of a login with two-factor authentication
+#[actix_web::post("/users/login/totp", wrap = "LoginRateLimit")]
+pub(crate) async fn login_totp(
+    data: web::Data<MyData>,
+    params: web::Json<CodeParams>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    let mut sessions = data.sessions.write().unwrap();
+    let session = get_valid_session_mut(&req, &mut sessions)?;
+    let Some(user_id) = session
+        .pending_login
+        .as_ref()
+        .filter(|pending| pending.started.elapsed() < PENDING_LOGIN_TTL)
+        .map(|pending| pending.user_id)
+    else {
+        session.pending_login = None;
+        return Err(error::ErrorBadRequest(
+            "The login has expired. Enter the password again.",
+        ));
+    };
+    let conn = data.conn()?;
+    check_second_factor(&data, &req, &conn, user_id, &params.code)?;
+
+    // Read the flags again, since they may have changed since the password was checked.
+    let (is_admin, disabled): (bool, bool) = conn
+        .query_row(
+            "SELECT is_admin, disabled FROM user WHERE id = ?1",
+            [user_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .map_err(map_err)?;
+    session.pending_login = None;
+    if disabled {
+        return Err(error::ErrorForbidden("The account is disabled"));
+    }
+    session.user_id = Some(user_id);
+    session.is_admin = is_admin;
+    Ok("Ok")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_rfc4226_vectors() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, code) in expected.into_iter().enumerate() {
+            assert_eq!(hotp(RFC_SECRET, counter as u64), code, "counter {counter}");
+        }
+    }
+
+    fn code_at(step: u64) -> String {
+        format!("{:06}", hotp(RFC_SECRET, step))
+    }
+
+    /// A step whose code starts with 0, so that a sign in place of it would parse to the same number
+    const NOW: u64 = 30;
+
+    #[test]
+    fn verify_with_skew() {
+        let now = NOW;
+        assert_eq!(
+            verify_code_at(RFC_SECRET, &code_at(now), None, now),
+            Some(now)
+        );
+        assert_eq!(
+            verify_code_at(RFC_SECRET, &code_at(now - 1), None, now),
+            Some(now - 1)
+        );
+        assert_eq!(
+            verify_code_at(RFC_SECRET, &code_at(now + 1), None, now),
+            Some(now + 1)
+        );
+        assert_eq!(
+            verify_code_at(RFC_SECRET, &code_at(now - 3), None, now),
+            None
+        );
+        assert_eq!(
+            verify_code_at(RFC_SECRET, &code_at(now + 3), None, now),
+            None
+        );
+        assert_eq!(
+            verify_code_at(b"another secret", &code_at(now), None, now),
+            None
+        );
+    }
+
+    #[test]
+    fn reject_replay() {
+        let now = NOW;
+        let code = code_at(now);
+        assert_eq!(verify_code_at(RFC_SECRET, &code, Some(now), now), None);
+        assert_eq!(verify_code_at(RFC_SECRET, &code, Some(now + 1), now), None);
+        assert_eq!(
+            verify_code_at(RFC_SECRET, &code_at(now + 1), Some(now), now),
+            Some(now + 1)
+        );
+        assert_eq!(
+            verify_code_at(RFC_SECRET, &code, Some(now - 1), now),
+            Some(now)
+        );
+    }
+
+    #[test]
+    fn reject_malformed_codes() {
+        let code = code_at(NOW);
+        assert!(code.starts_with('0'));
+        assert_eq!(
+            verify_code_at(RFC_SECRET, &format!(" {code}\n"), None, NOW),
+            Some(NOW)
+        );
+        for bad in [
+            "",
+            &code[..5],
+            &format!("{code}0"),
+            &format!("+{}", &code[1..]),
+            &format!("-{}", &code[1..]),
+            "12a456",
+            "１２３４５６",
+        ] {
+            assert_eq!(verify_code_at(RFC_SECRET, bad, None, NOW), None, "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn recovery_code_normalization() {
+        let hash = hash_recovery_code("abcde-12345");
+        assert_eq!(hash_recovery_code("ABCDE-12345"), hash);
+        assert_eq!(hash_recovery_code("abcde12345"), hash);
+        assert_eq!(hash_recovery_code(" abcde 12345 "), hash);
+        assert_ne!(hash_recovery_code("abcde-12346"), hash);
+
+        let code = generate_recovery_code();
+        assert_eq!(code.len(), 11);
+        assert_eq!(code.as_bytes()[5], b'-');
+    }
+
+    #[test]
+    fn encrypt_secrets() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join(TOTP_KEY_FILE_NAME);
+        let key = TotpKey::load_or_create(&key_path).unwrap();
+        let stored = key.encrypt(1, RFC_SECRET);
+        assert!(stored.starts_with(ENCRYPTED_PREFIX));
+        assert!(!stored.contains(&BASE32_NOPAD.encode(RFC_SECRET)));
+        assert_eq!(key.decrypt(1, &stored).unwrap(), RFC_SECRET);
+        // Another user, or another key
+        assert!(key.decrypt(2, &stored).is_err());
+        let other = TotpKey::load_or_create(&dir.path().join("other.key")).unwrap();
+        assert!(other.decrypt(1, &stored).is_err());
+        // The same key again from the file
+        let reloaded = TotpKey::load_or_create(&key_path).unwrap();
+        assert_eq!(reloaded.decrypt(1, &stored).unwrap(), RFC_SECRET);
+        std::fs::write(&key_path, b"short").unwrap();
+        assert!(TotpKey::load_or_create(&key_path).is_err());
+    }
+
+    #[test]
+    fn encrypt_plain_secrets_of_old_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = TotpKey::load_or_create(&dir.path().join(TOTP_KEY_FILE_NAME)).unwrap();
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migration::migrate(&mut conn, Path::new("")).unwrap();
+        conn.execute(
+            "INSERT INTO totp (user_id, secret, enabled) VALUES (1, ?1, TRUE)",
+            [BASE32_NOPAD.encode(RFC_SECRET)],
+        )
+        .unwrap();
+        // Readable before being encrypted
+        let row = load_totp(&conn, &key, 1).unwrap().unwrap();
+        assert_eq!(row.secret, RFC_SECRET);
+
+        assert_eq!(encrypt_plain_secrets(&mut conn, &key).unwrap(), 1);
+        assert_eq!(encrypt_plain_secrets(&mut conn, &key).unwrap(), 0);
+        let stored: String = conn
+            .query_row("SELECT secret FROM totp WHERE user_id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(stored.starts_with(ENCRYPTED_PREFIX));
+        let row = load_totp(&conn, &key, 1).unwrap().unwrap();
+        assert_eq!(row.secret, RFC_SECRET);
+    }
+}
@@ -0,0 +1,334 @@
+//! Lower bitrate renditions of videos for slow links.
+//!
+//! Videos from phones are often too heavy to stream as is. If ffmpeg is available, `/stream/` makes
+//! an H.264 MP4 rendition of the video in the background on the first request, and serves it once
+//! it is ready. Until then, or if ffmpeg is not available, the original file is served.
+//!
+//! Renditions are cached in a directory named by the hash of the path and the modified date of the
+//! video, so a modified video gets a new rendition. The renditions of modified or deleted videos
+//! are collected on startup.
+//!
+//! A single worker thread runs the jobs from a bounded queue, one at a time, so that many requests
+//! for different videos neither pile up threads nor run many ffmpeg processes. The worker exits
+//! when the queue is empty and is started again by the next job.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Mutex,
+};
+
+use actix_files::NamedFile;
+use actix_web::{error, web, HttpRequest, Result};
+
+use crate::{
+    files::{authorized_path, get_file_modified, has_extension_segments, is_hidden, CheckAuth},
+    session::find_session,
+    MyData,
+};
+
+/// The maximum height of the renditions. Smaller videos keep their size.
+const RENDITION_HEIGHT: u32 = 720;
+const VIDEO_BITRATE: &str = "2M";
+const AUDIO_BITRATE: &str = "128k";
+/// The maximum number of jobs waiting for the worker. Requests beyond it get the original, and
+/// queue the job again on a later request.
+const MAX_QUEUED_JOBS: usize = 64;
+
+enum Job {
+    Transcode {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+    /// Delete the renditions of the videos that no longer exist or have been modified, and the
+    /// temporary files left by an interrupted run
+    CollectGarbage,
+}
+
+#[derive(Default)]
+struct JobQueue {
+    jobs: VecDeque<Job>,
+    /// The renditions queued or being made
+    pending: HashSet<PathBuf>,
+    /// The renditions ffmpeg failed to make, which are not tried again until the video is modified
+    /// or the server restarts
+    failed: HashSet<PathBuf>,
+    worker_running: bool,
+}
+
+pub(crate) struct Transcoder {
+    /// The ffmpeg executable, if available
+    ffmpeg: Option<PathBuf>,
+    dir: PathBuf,
+    queue: Mutex<JobQueue>,
+}
+
+impl Transcoder {
+    /// Detect ffmpeg. An empty `ffmpeg` disables transcoding.
+    pub(crate) fn new(ffmpeg: &str, dir: PathBuf) -> Self {
+        let ffmpeg = (!ffmpeg.is_empty())
+            .then(|| PathBuf::from(ffmpeg))
+            .filter(|ffmpeg| {
+                let found = Command::new(ffmpeg)
+                    .arg("-version")
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .map(|status| status.success())
+                    .unwrap_or(false);
+                if !found {
+                    println!("ffmpeg is not found at {ffmpeg:?}; videos are streamed as they are");
+                }
+                found
+            });
+        Self {
+            ffmpeg,
+            dir,
+            queue: Mutex::default(),
+        }
+    }
+
+    fn rendition_path(&self, rel_path: &Path, modified: f64) -> PathBuf {
+        let key = format!(
+            "{}\n{modified}\n{RENDITION_HEIGHT}",
+            rel_path.to_string_lossy()
+        );
+        self.dir.join(format!("{}.mp4", sha256::digest(key)))
+    }
+}
+
+/// Start the worker if it is not running. Call with the queue locked and a job in it.
+fn ensure_worker(data: &web::Data<MyData>, queue: &mut JobQueue) {
+    if queue.worker_running {
+        return;
+    }
+    queue.worker_running = true;
+    let data = data.clone();
+    std::thread::spawn(move || run_worker(&data));
+}
+
+fn run_worker(data: &MyData) {
+    let transcoder = &data.transcoder;
+    loop {
+        let job = {
+            let mut queue = transcoder.queue.lock().unwrap();
+            let Some(job) = queue.jobs.pop_front() else {
+                queue.worker_running = false;
+                return;
+            };
+            job
+        };
+        match job {
+            Job::Transcode { src, dest } => {
+                let Some(ffmpeg) = &transcoder.ffmpeg else {
+                    continue;
+                };
+                println!("Transcoding {src:?}");
+                let start = std::time::Instant::now();
+                let res = transcode(ffmpeg, &src, &dest);
+                let mut queue = transcoder.queue.lock().unwrap();
+                queue.pending.remove(&dest);
+                match res {
+                    Ok(()) => println!("Transcoded {src:?} in {} s", start.elapsed().as_secs_f64()),
+                    Err(e) => {
+                        println!("Failed to transcode {src:?}: {e}");
+                        queue.failed.insert(dest);
+                    }
+                }
+            }
+            Job::CollectGarbage => match collect_garbage(transcoder, &data.path) {
+                Ok(0) => {}
+                Ok(count) => println!("Deleted {count} stale video renditions"),
+                Err(e) => println!("Error in deleting stale video renditions: {e}"),
+            },
+        }
+    }
+}
+
+/// Delete the files in the rendition directory other than the renditions of the current videos,
+/// and return the number of them. Runs on the worker, so no temporary file is being written.
+fn collect_garbage(transcoder: &Transcoder, root: &Path) -> anyhow::Result<usize> {
+    if !transcoder.dir.exists() {
+        return Ok(0);
+    }
+    let mut videos = vec![];
+    collect_videos(root, Path::new(""), &mut videos)?;
+    let current: HashSet<_> = videos
+        .iter()
+        .filter_map(|rel_path| {
+            let modified = get_file_modified(&root.join(rel_path)).ok()?;
+            Some(transcoder.rendition_path(rel_path, modified))
+        })
+        .collect();
+    let mut removed = 0;
+    for entry in std::fs::read_dir(&transcoder.dir)? {
+        let path = entry?.path();
+        let is_rendition = path.extension().is_some_and(|ext| ext == "mp4");
+        if path.is_file() && is_rendition && !current.contains(&path) {
+            std::fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Collect relative paths of videos under `path`, recursively.
+fn collect_videos(root: &Path, path: &Path, videos: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(root.join(path))? {
+        let rel_path = path.join(entry?.file_name());
+        let abs_path = root.join(&rel_path);
+        if is_hidden(&abs_path) {
+            continue;
+        }
+        if abs_path.is_dir() {
+            collect_videos(root, &rel_path, videos)?;
+        } else if is_video(&rel_path) {
+            videos.push(rel_path);
+        }
+    }
+    Ok(())
+}
+
+/// Queue deleting stale renditions in the background, if transcoding is enabled.
+pub(crate) fn start_rendition_gc(data: &web::Data<MyData>) {
+    if data.transcoder.ffmpeg.is_none() {
+        return;
+    }
+    let mut queue = data.transcoder.queue.lock().unwrap();
+    queue.jobs.push_back(Job::CollectGarbage);
+    ensure_worker(data, &mut queue);
+}
+
+fn is_video(path: &Path) -> bool {
+    path.to_str()
+        .map(|path| has_extension_segments(path, ".mp4") || has_extension_segments(path, ".webm"))
+        .unwrap_or(false)
+}
+
+fn transcode(ffmpeg: &Path, src: &Path, dest: &Path) -> anyhow::Result<()> {
+    let parent = dest.parent().expect("The rendition path has a parent");
+    std::fs::create_dir_all(parent)?;
+    // Write to a temporary file and rename, so that a partial rendition is never served.
+    let tmp_path = dest.with_extension("tmp.mp4");
+    let output = Command::new(ffmpeg)
+        .args(["-y", "-loglevel", "error", "-i"])
+        .arg(src)
+        .args([
+            "-vf",
+            // Keep the aspect ratio; H.264 needs even dimensions.
+            &format!("scale=-2:'min({RENDITION_HEIGHT},ih)'"),
+            "-c:v",
+            "libx264",
+            "-preset",
+            "veryfast",
+            "-b:v",
+            VIDEO_BITRATE,
+            "-maxrate",
+            VIDEO_BITRATE,
+            "-bufsize",
+            "4M",
+            "-c:a",
+            "aac",
+            "-b:a",
+            AUDIO_BITRATE,
+            // Put the index at the beginning, so that the playback can start before the download
+            // completes.
+            "-movflags",
+            "+faststart",
+        ])
+        .arg(&tmp_path)
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&tmp_path);
+        anyhow::bail!(
+            "ffmpeg exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    std::fs::rename(&tmp_path, dest)?;
+    Ok(())
+}
+
+/// Queue making the rendition in the background, unless it is already queued, it has failed
+/// before or the queue is full.
+fn start_transcode(data: &web::Data<MyData>, src: PathBuf, dest: PathBuf) {
+    let mut queue = data.transcoder.queue.lock().unwrap();
+    if queue.pending.contains(&dest)
+        || queue.failed.contains(&dest)
+        || MAX_QUEUED_JOBS <= queue.jobs.len()
+    {
+        return;
+    }
+    queue.pending.insert(dest.clone());
+    queue.jobs.push_back(Job::Transcode { src, dest });
+    ensure_worker(data, &mut queue);
+}
+
+#[actix_web::get("/stream/{path:.*}")]
+pub(crate) async fn stream_video(
+    data: web::Data<MyData>,
+    path: web::Path<PathBuf>,
+    req: HttpRequest,
+) -> Result<NamedFile> {
+    {
+        let sessions = data.sessions.read().unwrap();
+        let session = find_session(&req, &sessions);
+        let cache = data.cache.read().unwrap();
+        let access = data.access.read().unwrap();
+        authorized_path(&path, session, &cache, &access, CheckAuth::Read)?;
+    }
+    if !is_video(&path) {
+        return Err(error::ErrorBadRequest("Not a video"));
+    }
+    let abs_path = data.path.join(&*path);
+
+    if data.transcoder.ffmpeg.is_some() {
+        // A missing file falls through to NamedFile, which responds 404.
+        if let Ok(modified) = get_file_modified(&abs_path) {
+            let rendition = data.transcoder.rendition_path(&path, modified);
+            if rendition.exists() {
+                return Ok(NamedFile::open(rendition)?);
+            }
+            start_transcode(&data, abs_path.clone(), rendition);
+        }
+    }
+
+    println!("Streaming the original {abs_path:?}");
+    Ok(NamedFile::open(abs_path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_stale_renditions() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        std::fs::create_dir(root.join("album")).unwrap();
+        std::fs::write(root.join("album/kept.mp4"), b"video").unwrap();
+        let transcoder = Transcoder::new("", root.join(".renditions"));
+        std::fs::create_dir(&transcoder.dir).unwrap();
+
+        let rel_path = Path::new("album/kept.mp4");
+        let modified = get_file_modified(&root.join(rel_path)).unwrap();
+        let current = transcoder.rendition_path(rel_path, modified);
+        let modified_before = transcoder.rendition_path(rel_path, modified - 1.);
+        let deleted = transcoder.rendition_path(Path::new("album/deleted.mp4"), modified);
+        let partial = current.with_extension("tmp.mp4");
+        let other = transcoder.dir.join("README.txt");
+        for path in [&current, &modified_before, &deleted, &partial, &other] {
+            std::fs::write(path, b"rendition").unwrap();
+        }
+
+        assert_eq!(collect_garbage(&transcoder, root).unwrap(), 3);
+        assert!(current.exists());
+        assert!(other.exists());
+        assert!(!modified_before.exists());
+        assert!(!deleted.exists());
+        assert!(!partial.exists());
+    }
+}
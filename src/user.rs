@@ -1,48 +1,33 @@
-use actix_web::{
-    error,
-    web::{self, Bytes},
-    HttpRequest, Result,
-};
-use rusqlite::{params, Connection};
+use std::{path::PathBuf, time::Instant};
+
+use actix_web::{error, web, HttpRequest, Result};
+use anyhow::anyhow;
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::{params, Connection, OptionalExtension};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    db_utils::table_exists,
+    api_token::remove_token_sessions,
+    cache::CachePayload,
     map_err,
-    session::{get_valid_session, get_valid_session_mut},
+    password::PasswordPolicy,
+    rate_limit::{account_key, client_keys, LoginRateLimit},
+    session::{
+        admin_session, check_admin, get_valid_session, get_valid_session_mut, remove_user_sessions,
+        session_key,
+    },
+    totp::{totp_enabled, PendingLogin},
     MyData,
 };
 
-pub(crate) fn init_table(conn: &Connection) -> anyhow::Result<()> {
-    if !table_exists(&conn, "user") {
-        // See https://www.sqlite.org/autoinc.html for PRIMARY KEY and AUTOINCREMENT implications
-        // We want to avoid wrong user ids even though AUTOINCREMENT adds some overhead.
-        conn.execute(
-            "CREATE TABLE user (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                name TEXT NOT NULL,
-                password TEXT,
-                is_admin BOOL NOT NULL
-            )",
-            [],
-        )
-        .unwrap();
-
-        // Add the admin account without a password.
-        conn.execute(
-            r#"INSERT INTO user (name, is_admin) VALUES ("admin", TRUE)"#,
-            [],
-        )?;
-        println!("table \"user\" created!");
-    }
-    Ok(())
-}
-
 #[derive(Serialize)]
 struct ListElementUser {
     id: usize,
     name: String,
+    display_name: Option<String>,
     password: bool,
+    is_admin: bool,
+    disabled: bool,
 }
 
 #[actix_web::get("/users")]
@@ -50,25 +35,22 @@ pub(crate) async fn list_users(
     data: web::Data<MyData>,
     req: HttpRequest,
 ) -> Result<web::Json<Vec<ListElementUser>>> {
-    let sessions = data.sessions.read().unwrap();
-    let session = get_valid_session(&req, &sessions)?;
-    if !session.is_admin {
-        return Err(error::ErrorForbidden("Non-admin cannot list users"));
-    }
-    let conn = data.conn.lock().unwrap();
+    check_admin(&data, &req, "list users")?;
+    let conn = data.conn()?;
     let mut stmt = conn
         .prepare(
-            "SELECT id, name, password is not null and length(password) != 0, is_admin FROM user",
+            "SELECT id, name, display_name, password is not null and length(password) != 0, is_admin, disabled FROM user",
         )
         .map_err(map_err)?;
     let users: Vec<_> = stmt
         .query_map([], |row| {
-            let id: usize = row.get(0)?;
-            let name: String = row.get(1)?;
             Ok(ListElementUser {
-                id,
-                name,
-                password: row.get(2)?,
+                id: row.get(0)?,
+                name: row.get(1)?,
+                display_name: row.get(2)?,
+                password: row.get(3)?,
+                is_admin: row.get(4)?,
+                disabled: row.get(5)?,
             })
         })
         .map_err(map_err)?
@@ -90,12 +72,9 @@ pub(crate) async fn create_user(
     params: web::Json<CreateUserParams>,
     req: HttpRequest,
 ) -> Result<String> {
-    let sessions = data.sessions.read().unwrap();
-    let session = get_valid_session(&req, &sessions)?;
-    if !session.is_admin {
-        return Err(error::ErrorForbidden("Only the admin can add a user"));
-    }
-    let conn = data.conn.lock().unwrap();
+    check_admin(&data, &req, "add a user")?;
+    data.password_policy.check_request(&params.password)?;
+    let conn = data.conn()?;
     conn.execute(
         "INSERT INTO user (name, password, is_admin) VALUES (?1, ?2, FALSE)",
         params![params.name, sha256::digest(&params.password)],
@@ -104,24 +83,322 @@ pub(crate) async fn create_user(
     Ok(conn.last_insert_rowid().to_string())
 }
 
+#[derive(Debug, Deserialize)]
+struct DeleteUserQuery {
+    /// The user to take over the albums of the deleted user. Defaults to the admin deleting it.
+    new_owner: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct DeleteUserResult {
+    new_owner: usize,
+    /// The albums transferred to the new owner
+    albums: Vec<PathBuf>,
+    /// Number of sessions of the deleted user that were invalidated
+    sessions: usize,
+}
+
 #[actix_web::delete("/users/{id}")]
 pub(crate) async fn delete_user(
     data: web::Data<MyData>,
     id: web::Path<usize>,
+    query: web::Query<DeleteUserQuery>,
     req: HttpRequest,
-) -> Result<String> {
-    let sessions = data.sessions.read().unwrap();
-    let session = get_valid_session(&req, &sessions)?;
-    if !session.is_admin {
-        return Err(error::ErrorForbidden("Only the admin can delete a user"));
-    }
-    if session.user_id == Some(*id) {
+) -> Result<web::Json<DeleteUserResult>> {
+    let id = *id;
+    let mut sessions = data.sessions.write().unwrap();
+    let session = admin_session(&req, &sessions, "delete a user")?;
+    if session.user_id == Some(id) {
         return Err(error::ErrorBadRequest("You cannot delete yourself"));
     }
-    let conn = data.conn.lock().unwrap();
-    conn.execute("DELETE FROM user WHERE id = ?1", params![id.as_ref()])
+    let new_owner = query
+        .new_owner
+        .or(session.user_id)
+        .ok_or_else(|| error::ErrorBadRequest("Please login first"))?;
+    if new_owner == id {
+        return Err(error::ErrorBadRequest(
+            "The new owner must be a user other than the deleted one",
+        ));
+    }
+
+    let mut cache = data.cache.write().unwrap();
+    let mut access = data.access.write().unwrap();
+    let mut conn = data.conn()?;
+    if !user_exists(&conn, id).map_err(map_err)? {
+        return Err(error::ErrorNotFound("User not found"));
+    }
+    // A disabled owner cannot log in to manage the albums.
+    let new_owner_disabled: Option<bool> = conn
+        .query_row(
+            "SELECT disabled FROM user WHERE id = ?1",
+            [new_owner],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(map_err)?;
+    match new_owner_disabled {
+        None => return Err(error::ErrorBadRequest("The new owner is not found")),
+        Some(true) => return Err(error::ErrorBadRequest("The new owner is disabled")),
+        Some(false) => {}
+    }
+    let albums = delete_user_rows(&mut conn, id, new_owner).map_err(map_err)?;
+
+    // Update the memory only after the transaction is committed.
+    access.remove_user(id);
+    for entry in cache.values_mut() {
+        if let CachePayload::Album(payload) = &mut entry.payload {
+            if payload.owner == id {
+                payload.owner = new_owner;
+            }
+        }
+    }
+    let result = DeleteUserResult {
+        new_owner,
+        albums,
+        sessions: remove_user_sessions(&mut sessions, id, None),
+    };
+    println!(
+        "Deleted user {id}; transferred {} albums to user {new_owner} and invalidated {} sessions",
+        result.albums.len(),
+        result.sessions
+    );
+    Ok(web::Json(result))
+}
+
+pub(crate) fn user_exists(conn: &Connection, id: usize) -> rusqlite::Result<bool> {
+    conn.query_row("SELECT id FROM user WHERE id = ?1", [id], |row| {
+        row.get::<_, usize>(0)
+    })
+    .optional()
+    .map(|id| id.is_some())
+}
+
+/// Delete the user and the group memberships, grants, reset tokens, second factors and API tokens
+/// of the user, and transfer the albums owned by the user to `new_owner`, in a transaction. Returns the
+/// transferred albums.
+pub(crate) fn delete_user_rows(
+    conn: &mut Connection,
+    id: usize,
+    new_owner: usize,
+) -> rusqlite::Result<Vec<PathBuf>> {
+    let tx = conn.transaction()?;
+    let albums = {
+        let mut stmt = tx.prepare("SELECT path FROM album WHERE owner = ?1")?;
+        let albums = stmt
+            .query_map([id], |row| Ok(PathBuf::from(row.get::<_, String>(0)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        albums
+    };
+    tx.execute(
+        "UPDATE album SET owner = ?1 WHERE owner = ?2",
+        [new_owner, id],
+    )?;
+    tx.execute("DELETE FROM user WHERE id = ?1", [id])?;
+    tx.execute("DELETE FROM group_member WHERE user_id = ?1", [id])?;
+    tx.execute("DELETE FROM album_grant WHERE user_id = ?1", [id])?;
+    tx.execute("DELETE FROM password_reset WHERE user_id = ?1", [id])?;
+    tx.execute("DELETE FROM totp WHERE user_id = ?1", [id])?;
+    tx.execute("DELETE FROM totp_recovery WHERE user_id = ?1", [id])?;
+    tx.execute("DELETE FROM api_token WHERE user_id = ?1", [id])?;
+    tx.commit()?;
+    Ok(albums)
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateUserParams {
+    name: Option<String>,
+    /// An empty string clears the display name.
+    display_name: Option<String>,
+    is_admin: Option<bool>,
+    disabled: Option<bool>,
+}
+
+/// Updates the profile of a user. Only the given fields are changed.
+#[actix_web::patch("/users/{id}")]
+pub(crate) async fn update_user(
+    data: web::Data<MyData>,
+    id: web::Path<usize>,
+    params: web::Json<UpdateUserParams>,
+    req: HttpRequest,
+) -> Result<&'static str> {
+    // Keep the sessions locked until they are updated, so that the user cannot act with the old
+    // flags in between.
+    let mut sessions = data.sessions.write().unwrap();
+    let session = admin_session(&req, &sessions, "update a user")?;
+    if session.user_id == Some(*id) && params.disabled == Some(true) {
+        return Err(error::ErrorBadRequest("You cannot disable yourself"));
+    }
+    let mut conn = data.conn()?;
+    let (is_admin, disabled) = update_user_row(&mut conn, *id, &params)?;
+
+    // The sessions of the API tokens are made again with the new flags on their next use, and
+    // the tokens of a disabled user would not be accepted anymore.
+    remove_token_sessions(&mut sessions, *id);
+    for session in sessions.values_mut() {
+        if session.user_id != Some(*id) {
+            continue;
+        }
+        if disabled {
+            session.user_id = None;
+            session.is_admin = false;
+        } else {
+            session.is_admin = is_admin;
+        }
+    }
+    println!("Updated user {id}: {:?}", params.0);
+    Ok("Ok")
+}
+
+/// Apply the changes to the user in a transaction and return the new `(is_admin, disabled)`.
+fn update_user_row(
+    conn: &mut Connection,
+    id: usize,
+    params: &UpdateUserParams,
+) -> Result<(bool, bool)> {
+    let tx = conn.transaction().map_err(map_err)?;
+    let (was_admin, was_disabled): (bool, bool) = tx
+        .query_row(
+            "SELECT is_admin, disabled FROM user WHERE id = ?1",
+            [id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map_err(map_err)?
+        .ok_or_else(|| error::ErrorNotFound("User not found"))?;
+    let is_admin = params.is_admin.unwrap_or(was_admin);
+    let disabled = params.disabled.unwrap_or(was_disabled);
+
+    if was_admin && !was_disabled && (!is_admin || disabled) {
+        let other_admins: usize = tx
+            .query_row(
+                "SELECT COUNT(*) FROM user WHERE is_admin AND NOT disabled AND id != ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .map_err(map_err)?;
+        if other_admins == 0 {
+            return Err(error::ErrorBadRequest(
+                "There must be at least one enabled admin",
+            ));
+        }
+    }
+
+    if let Some(name) = &params.name {
+        if name.is_empty() {
+            return Err(error::ErrorBadRequest("The user name must not be empty"));
+        }
+        let taken = tx
+            .query_row(
+                "SELECT id FROM user WHERE name = ?1 AND id != ?2",
+                params![name, id],
+                |row| row.get::<_, usize>(0),
+            )
+            .optional()
+            .map_err(map_err)?
+            .is_some();
+        if taken {
+            return Err(error::ErrorConflict(format!(
+                "User name {name:?} is already taken"
+            )));
+        }
+        tx.execute("UPDATE user SET name = ?1 WHERE id = ?2", params![name, id])
+            .map_err(map_err)?;
+    }
+    if let Some(display_name) = &params.display_name {
+        let display_name = (!display_name.is_empty()).then_some(display_name);
+        tx.execute(
+            "UPDATE user SET display_name = ?1 WHERE id = ?2",
+            params![display_name, id],
+        )
         .map_err(map_err)?;
-    Ok("Ok".to_string())
+    }
+    tx.execute(
+        "UPDATE user SET is_admin = ?1, disabled = ?2 WHERE id = ?3",
+        params![is_admin, disabled, id],
+    )
+    .map_err(map_err)?;
+    tx.commit().map_err(map_err)?;
+    Ok((is_admin, disabled))
+}
+
+/// The length of the admin password generated on the first run
+const INITIAL_PASSWORD_LEN: usize = 16;
+
+/// Make sure that an admin can log in. On the first run, the admin has no password, which used to
+/// let anyone log in as the admin. Set `password` to the admin in that case, which must satisfy
+/// `policy` as any other password, or generate a random one and print it once to the console.
+pub(crate) fn init_admin_password(
+    conn: &Connection,
+    password: Option<&str>,
+    policy: &PasswordPolicy,
+) -> anyhow::Result<()> {
+    let has_password: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM user WHERE is_admin AND NOT disabled
+            AND password IS NOT NULL AND length(password) != 0)",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_password {
+        return Ok(());
+    }
+    let admin: Option<(usize, String)> = conn
+        .query_row(
+            "SELECT id, name FROM user WHERE is_admin AND NOT disabled ORDER BY id",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let Some((id, name)) = admin else {
+        println!("There is no enabled admin. Add one by `massphoto user add --admin`.");
+        return Ok(());
+    };
+    if let Some(password) = password {
+        policy
+            .check(password)
+            .map_err(|e| anyhow!("admin_password: {e}"))?;
+        conn.execute(
+            "UPDATE user SET password = ?1, initial_password = FALSE WHERE id = ?2",
+            params![sha256::digest(password), id],
+        )?;
+        println!("Set the given password to the admin {name:?}");
+        return Ok(());
+    }
+    let password: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(INITIAL_PASSWORD_LEN)
+        .map(char::from)
+        .collect();
+    conn.execute(
+        "UPDATE user SET password = ?1, initial_password = TRUE WHERE id = ?2",
+        params![sha256::digest(&password), id],
+    )?;
+    println!("==========================================================");
+    println!("Generated the password of the admin {name:?}: {password}");
+    println!("It is not shown again. Log in and change it.");
+    println!("==========================================================");
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct SetupStatusResult {
+    /// Whether an admin still has the password generated on the first run
+    initial_password: bool,
+}
+
+/// Tells the frontend whether the first-run setup is still to be finished. Needs no login.
+#[actix_web::get("/setup_status")]
+pub(crate) async fn get_setup_status(
+    data: web::Data<MyData>,
+) -> Result<web::Json<SetupStatusResult>> {
+    let conn = data.conn()?;
+    let initial_password = conn
+        .query_row(
+            "SELECT EXISTS (SELECT 1 FROM user WHERE is_admin AND NOT disabled AND initial_password)",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(map_err)?;
+    Ok(web::Json(SetupStatusResult { initial_password }))
 }
 
 #[derive(Serialize)]
@@ -129,6 +406,7 @@ struct StatusUserResult {
     logged_in: bool,
     is_admin: bool,
     name: Option<String>,
+    display_name: Option<String>,
 }
 
 #[actix_web::get("/user_status")]
@@ -143,20 +421,24 @@ pub(crate) async fn status_user(
             logged_in: false,
             is_admin: false,
             name: None,
+            display_name: None,
         }));
     };
-    let conn = data.conn.lock().unwrap();
-    let (name, is_admin) = conn
+    let conn = data.conn()?;
+    let (name, display_name, is_admin) = conn
         .query_row_and_then(
-            "SELECT name, is_admin FROM user WHERE id = ?1",
+            "SELECT name, display_name, is_admin FROM user WHERE id = ?1",
             [user_id],
-            |q| -> rusqlite::Result<(String, bool)> { Ok((q.get(0)?, q.get(1)?)) },
+            |q| -> rusqlite::Result<(String, Option<String>, bool)> {
+                Ok((q.get(0)?, q.get(1)?, q.get(2)?))
+            },
         )
         .map_err(map_err)?;
     Ok(web::Json(StatusUserResult {
         logged_in: true,
         is_admin,
         name: Some(name),
+        display_name,
     }))
 }
 
@@ -166,38 +448,77 @@ struct LoginUserParams {
     password: String,
 }
 
-#[actix_web::post("/users/login")]
+#[derive(Serialize)]
+struct LoginResult {
+    /// Whether the login has to be completed by `/users/login/totp` with a code
+    totp_required: bool,
+}
+
+#[actix_web::post("/users/login", wrap = "LoginRateLimit")]
 pub(crate) async fn login_user(
     data: web::Data<MyData>,
     req: HttpRequest,
     params: web::Json<LoginUserParams>,
-) -> Result<&'static str> {
+) -> Result<web::Json<LoginResult>> {
     let mut sessions = data.sessions.write().unwrap();
     let session = get_valid_session_mut(&req, &mut sessions)?;
     println!("Attempt logging in: {name:?}", name = params.name);
-    let conn = data.conn.lock().unwrap();
-    let (id, db_passwd, is_admin) = conn
+    let conn = data.conn()?;
+    let mut limiter = data.rate_limiter.lock().unwrap();
+    let target = account_key(&params.name);
+    let keys = client_keys(&req, target.clone());
+    limiter.check_attempt(&keys)?;
+    let user = conn
         .query_row_and_then(
-            "SELECT id, password, is_admin FROM user WHERE name = ?1",
+            "SELECT id, password, is_admin, disabled FROM user WHERE name = ?1",
             [&params.name],
-            |q| -> rusqlite::Result<(usize, Option<String>, bool)> {
-                Ok((q.get(0)?, q.get(1)?, q.get(2)?))
+            |q| -> rusqlite::Result<(usize, Option<String>, bool, bool)> {
+                Ok((q.get(0)?, q.get(1)?, q.get(2)?, q.get(3)?))
             },
         )
-        .map_err(|err| match err {
-            rusqlite::Error::QueryReturnedNoRows => error::ErrorBadRequest("User not found"),
-            e => map_err(e),
+        .map(Some)
+        .or_else(|err| match err {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(map_err(e)),
         })?;
-    if db_passwd
-        .map(|db_passwd| db_passwd != sha256::digest(&params.password))
-        .unwrap_or(false)
-    {
-        // TODO: is it safe to respond that the user name exists?
-        return Err(error::ErrorNotAcceptable("Incorrect password"));
+    // Always compute the hash so that the response time does not tell whether the user exists.
+    let hash = sha256::digest(&params.password);
+    // An account without a password cannot log in.
+    let Some((id, _, is_admin, disabled)) =
+        user.filter(|(_, db_passwd, _, _)| db_passwd.as_deref() == Some(hash.as_str()))
+    else {
+        limiter.record_failure(&conn, &keys).map_err(map_err)?;
+        // Do not tell whether the user name exists.
+        return Err(error::ErrorNotAcceptable("Incorrect user name or password"));
+    };
+    let totp_required = totp_enabled(&conn, id).map_err(map_err)?;
+    if !totp_required {
+        // With the second factor, the failures are forgotten only after login_totp verifies it,
+        // since it counts its failures against the same account. Otherwise the one who has the
+        // password could reset the count before each guess of a code.
+        limiter.record_success(&target);
     }
+    if disabled {
+        return Err(error::ErrorForbidden("The account is disabled"));
+    }
+    if totp_required {
+        // Log in after the second factor is verified by login_totp.
+        session.user_id = None;
+        session.is_admin = false;
+        session.pending_login = Some(PendingLogin {
+            user_id: id,
+            started: Instant::now(),
+        });
+        return Ok(web::Json(LoginResult {
+            totp_required: true,
+        }));
+    }
+    session.pending_login = None;
     session.user_id = Some(id);
     session.is_admin = is_admin;
-    Ok("Ok")
+    Ok(web::Json(LoginResult {
+        totp_required: false,
+    }))
 }
 
 #[actix_web::post("/user_logout")]
@@ -210,25 +531,209 @@ pub(crate) async fn logout_user(data: web::Data<MyData>, req: HttpRequest) -> Re
     }
     session.user_id = None;
     session.is_admin = false;
+    session.pending_login = None;
     Ok("Ok")
 }
 
-#[actix_web::post("/set_password")]
+#[derive(Deserialize)]
+struct SetPasswordParams {
+    current_password: String,
+    new_password: String,
+}
+
+/// Changes the password of the logged in user. The current password is required, so that a
+/// hijacked session cannot lock the user out. The other sessions of the user are logged out.
+#[actix_web::post("/set_password", wrap = "LoginRateLimit")]
 pub(crate) async fn set_user_password(
     data: web::Data<MyData>,
     req: HttpRequest,
-    passwd: Bytes,
+    params: web::Json<SetPasswordParams>,
 ) -> Result<&'static str> {
-    let sessions = data.sessions.read().unwrap();
+    let mut sessions = data.sessions.write().unwrap();
     let session = get_valid_session(&req, &sessions)?;
     let user_id = session
         .user_id
         .ok_or_else(|| error::ErrorBadRequest("Please login first"))?;
-    let conn = data.conn.lock().map_err(map_err)?;
+    data.password_policy.check_request(&params.new_password)?;
+    let conn = data.conn()?;
+    let (name, db_passwd) = conn
+        .query_row(
+            "SELECT name, password FROM user WHERE id = ?1",
+            [user_id],
+            |row| -> rusqlite::Result<(String, Option<String>)> { Ok((row.get(0)?, row.get(1)?)) },
+        )
+        .map_err(map_err)?;
+    let mut limiter = data.rate_limiter.lock().unwrap();
+    let target = account_key(&name);
+    let keys = client_keys(&req, target.clone());
+    limiter.check_attempt(&keys)?;
+    if db_passwd.as_deref() != Some(sha256::digest(&params.current_password).as_str()) {
+        limiter.record_failure(&conn, &keys).map_err(map_err)?;
+        return Err(error::ErrorNotAcceptable("Incorrect current password"));
+    }
+    limiter.record_success(&target);
     conn.execute(
-        "UPDATE user SET password = ?1 WHERE id = ?2",
-        params![sha256::digest(passwd.as_ref()), user_id],
+        "UPDATE user SET password = ?1, initial_password = FALSE WHERE id = ?2",
+        params![sha256::digest(&params.new_password), user_id],
     )
     .map_err(map_err)?;
+
+    let current = session_key(&req);
+    let count = remove_user_sessions(&mut sessions, user_id, current.as_deref());
+    println!("Changed the password of user {user_id} and logged out {count} other sessions");
     Ok("Ok")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use actix_web::{cookie::Cookie, http::StatusCode, test, App};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        db_utils::init_db,
+        session::{Session, SESSION_COOKIE},
+        totp::login_totp,
+    };
+
+    #[actix_web::test]
+    async fn admin_password_follows_policy() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migration::migrate(&mut conn, std::path::Path::new("")).unwrap();
+        let policy = PasswordPolicy {
+            min_length: 8,
+            min_classes: 2,
+        };
+        let admin_password = |conn: &Connection| -> Option<String> {
+            conn.query_row("SELECT password FROM user WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+
+        for weak in ["short1", "lowercaseonly"] {
+            assert!(init_admin_password(&conn, Some(weak), &policy).is_err());
+            assert_eq!(admin_password(&conn), None);
+        }
+        init_admin_password(&conn, Some("Strong password"), &policy).unwrap();
+        assert_eq!(
+            admin_password(&conn),
+            Some(sha256::digest("Strong password"))
+        );
+    }
+
+    #[actix_web::test]
+    async fn password_does_not_reset_code_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = init_db(&Config {
+            path: dir.path().to_owned(),
+            ..Config::default()
+        })
+        .unwrap();
+        {
+            let conn = data.conn().unwrap();
+            conn.execute(
+                "INSERT INTO user (name, password, is_admin) VALUES ('alice', ?1, FALSE)",
+                [sha256::digest("password")],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO totp (user_id, secret, enabled)
+                SELECT id, 'JBSWY3DPEHPK3PXP', TRUE FROM user WHERE name = 'alice'",
+                [],
+            )
+            .unwrap();
+        }
+        data.sessions
+            .write()
+            .unwrap()
+            .insert("session".to_string(), Session::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .service(login_user)
+                .service(login_totp),
+        )
+        .await;
+        // Every request comes from another address, as an attacker rotating IPv6 addresses can.
+        let mut client = 0;
+        let mut post = |uri: &str, body: serde_json::Value| {
+            client += 1;
+            test::TestRequest::post()
+                .uri(uri)
+                .peer_addr(SocketAddr::from(([10, 0, 0, client], 1234)))
+                .cookie(Cookie::new(SESSION_COOKIE, "session"))
+                .set_json(body)
+                .to_request()
+        };
+        let password = json!({"name": "alice", "password": "password"});
+
+        for _ in 0..3 {
+            let res = test::call_service(&app, post("/users/login", password.clone())).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res =
+                test::call_service(&app, post("/users/login/totp", json!({"code": "x"}))).await;
+            assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+        }
+        let res = test::call_service(&app, post("/users/login", password)).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_web::test]
+    async fn delete_user_rejects_disabled_new_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = init_db(&Config {
+            path: dir.path().to_owned(),
+            ..Config::default()
+        })
+        .unwrap();
+        {
+            let conn = data.conn().unwrap();
+            for (name, disabled) in [("alice", false), ("bob", true), ("carol", false)] {
+                conn.execute(
+                    "INSERT INTO user (name, password, is_admin, disabled) VALUES (?1, '', FALSE, ?2)",
+                    params![name, disabled],
+                )
+                .unwrap();
+            }
+            conn.execute(
+                "INSERT INTO album (path, desc, password, owner) VALUES ('a', '', '', 2)",
+                [],
+            )
+            .unwrap();
+        }
+        let mut session = Session::new();
+        session.is_admin = true;
+        session.user_id = Some(1);
+        data.sessions
+            .write()
+            .unwrap()
+            .insert("session".to_string(), session);
+        let app = test::init_service(App::new().app_data(data.clone()).service(delete_user)).await;
+        let delete = |uri: &str| {
+            test::TestRequest::delete()
+                .uri(uri)
+                .cookie(Cookie::new(SESSION_COOKIE, "session"))
+                .to_request()
+        };
+
+        for uri in ["/users/2?new_owner=3", "/users/2?new_owner=99"] {
+            let res = test::call_service(&app, delete(uri)).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{uri}");
+        }
+        assert!(user_exists(&data.conn().unwrap(), 2).unwrap());
+        let res = test::call_service(&app, delete("/users/2?new_owner=4")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let owner: usize = data
+            .conn()
+            .unwrap()
+            .query_row("SELECT owner FROM album WHERE path = 'a'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(owner, 4);
+    }
+}